@@ -0,0 +1,327 @@
+use ethers::types::{H160, H256, U256};
+use ethers::utils::format_units;
+use eyre::*;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::*;
+
+use crate::api::EthersClient;
+use crate::events::{ChainEvent, EventBus};
+use crate::api::assets::AssetId;
+use crate::model::{EnumAlertKind, EnumExchangeFlow, EnumLabelCategory};
+use crate::portfolio::NativeCoin;
+use crate::price_history::PriceHistory;
+use crate::tables::{Tables, TokenTransferRow, TransactionRow, WhaleAlertRow};
+
+const NATIVE_DECIMALS: u8 = 18;
+
+#[derive(Debug, Clone, Copy)]
+pub struct WhaleAlertThresholds {
+    pub min_usd: f64,
+    /// Movements into or out of an exchange are reported from this value
+    pub exchange_min_usd: f64,
+}
+
+impl Default for WhaleAlertThresholds {
+    fn default() -> Self {
+        Self {
+            min_usd: 1_000_000.0,
+            exchange_min_usd: 250_000.0,
+        }
+    }
+}
+
+struct Movement {
+    kind: EnumAlertKind,
+    tx_hash: H256,
+    block_number: u32,
+    timestamp_s: u32,
+    token_address: Option<H160>,
+    symbol: String,
+    decimals: u8,
+    from: H160,
+    to: H160,
+    amount: U256,
+}
+
+/// Values ingested transactions, internal transfers and token transfers in
+/// USD and records those above the thresholds in the `WhaleAlert` table.
+///
+/// Movements are valued at their block's time, so backfilled blocks are
+/// held to the thresholds at the prices of their day.
+///
+/// Runs behind the event bus so slow price lookups and traces never hold up
+/// ingestion. If it falls too far behind, the skipped events are logged and
+/// not evaluated.
+pub struct WhaleAlerter {
+    api: Arc<EthersClient>,
+    history: Arc<PriceHistory>,
+    tables: Arc<Tables>,
+    events: EventBus,
+    native: NativeCoin,
+    thresholds: WhaleAlertThresholds,
+    /// One `trace_transaction` per transaction, only worth it against a
+    /// node with tracing enabled
+    trace_internal_transfers: bool,
+}
+
+impl WhaleAlerter {
+    pub fn new(
+        api: Arc<EthersClient>,
+        history: Arc<PriceHistory>,
+        tables: Arc<Tables>,
+        events: EventBus,
+        native: NativeCoin,
+        thresholds: WhaleAlertThresholds,
+        trace_internal_transfers: bool,
+    ) -> Self {
+        Self {
+            api,
+            history,
+            tables,
+            events,
+            native,
+            thresholds,
+            trace_internal_transfers,
+        }
+    }
+
+    /// Takes the receiver rather than subscribing itself so nothing ingested
+    /// before the task is first polled gets missed
+    pub async fn run(self: Arc<Self>, mut receiver: broadcast::Receiver<Arc<ChainEvent>>) {
+        loop {
+            let event = match receiver.recv().await {
+                Result::Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "Whale alerter lagged behind, {} events were not evaluated",
+                        missed
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let result = match &*event {
+                ChainEvent::Transaction(row) => self.check_transaction(row).await,
+                ChainEvent::TokenTransfer(row) => self.check_token_transfer(row).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to evaluate whale alert: {:?}", e);
+            }
+        }
+    }
+
+    async fn check_transaction(&self, row: &TransactionRow) -> Result<()> {
+        // a reverted transaction moved no ETH, neither did its internal calls
+        if row.status != 1 {
+            return Ok(());
+        }
+        let AssetId::Cmc(native_id) = self.native.asset else {
+            return Ok(());
+        };
+        let Some(price) = self.usd_price(native_id, row.timestamp_s).await? else {
+            return Ok(());
+        };
+        let tx_hash = H256::from(row.hash);
+        let eth_movement = |kind, from, to, amount| Movement {
+            kind,
+            tx_hash,
+            block_number: row.block_number,
+            timestamp_s: row.timestamp_s,
+            token_address: None,
+            symbol: self.native.symbol.clone(),
+            decimals: NATIVE_DECIMALS,
+            from,
+            to,
+            amount,
+        };
+        if let Some(to) = row.to_address.clone() {
+            let movement = eth_movement(
+                EnumAlertKind::Transaction,
+                row.from_address.clone().into(),
+                to.into(),
+                row.value.clone().into(),
+            );
+            self.evaluate(movement, price).await?;
+        }
+        if self.trace_internal_transfers {
+            if let Some((value, from, to)) = self.api.get_internal_largest_transfer(tx_hash).await?
+            {
+                let movement = eth_movement(EnumAlertKind::InternalTransfer, from, to, value);
+                self.evaluate(movement, price).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_token_transfer(&self, row: &TokenTransferRow) -> Result<()> {
+        if row.token_id.is_some() {
+            // NFTs have no per-unit price
+            return Ok(());
+        }
+        let token_address = H160::from(row.token_address.clone());
+        let Some(token) = self.tables.tokens.select_token(token_address) else {
+            return Ok(());
+        };
+        // symbols are ambiguous, only tokens matched to their CMC listing
+        // by contract address are priced
+        let Some(cmc_id) = token.cmc_id else {
+            return Ok(());
+        };
+        let Some(price) = self.usd_price(cmc_id, row.timestamp_s).await? else {
+            return Ok(());
+        };
+        let movement = Movement {
+            kind: EnumAlertKind::TokenTransfer,
+            tx_hash: H256::from(row.tx_hash),
+            block_number: row.block_number,
+            timestamp_s: row.timestamp_s,
+            token_address: Some(token_address),
+            symbol: token.symbol,
+            decimals: token.decimals,
+            from: row.from_address.clone().into(),
+            to: row.to_address.clone().into(),
+            amount: row.value.clone().into(),
+        };
+        self.evaluate(movement, price).await
+    }
+
+    /// Price at the block's time, `None` if CMC has no quotes around it
+    async fn usd_price(&self, cmc_id: u64, timestamp_s: u32) -> Result<Option<f64>> {
+        let price = self.history.price_at(cmc_id, timestamp_s as i64).await?;
+        Ok(price.filter(|price| *price > 0.0))
+    }
+
+    fn is_exchange(&self, address: H160) -> bool {
+        self.tables
+            .address_labels
+            .labels_of(address.into())
+            .iter()
+            .any(|row| row.category == EnumLabelCategory::Exchange.as_str())
+    }
+
+    fn exchange_flow(&self, from: H160, to: H160) -> EnumExchangeFlow {
+        match (self.is_exchange(from), self.is_exchange(to)) {
+            (true, true) => EnumExchangeFlow::Internal,
+            (false, true) => EnumExchangeFlow::Inflow,
+            (true, false) => EnumExchangeFlow::Outflow,
+            (false, false) => EnumExchangeFlow::None,
+        }
+    }
+
+    async fn evaluate(&self, movement: Movement, price: f64) -> Result<()> {
+        if movement.amount.is_zero() {
+            return Ok(());
+        }
+        let units: f64 = format_units(movement.amount, movement.decimals as u32)?.parse()?;
+        let usd_value = units * price;
+        let exchange_flow = self.exchange_flow(movement.from, movement.to);
+        let threshold = match exchange_flow {
+            EnumExchangeFlow::None => self.thresholds.min_usd,
+            _ => self.thresholds.exchange_min_usd,
+        };
+        if usd_value < threshold {
+            return Ok(());
+        }
+
+        let row = WhaleAlertRow {
+            id: self.tables.whale_alerts.get_next_pk().into(),
+            tx_hash: movement.tx_hash.0,
+            block_number: movement.block_number,
+            timestamp_s: movement.timestamp_s,
+            kind: movement.kind as u8,
+            token_address: movement.token_address.map(Into::into),
+            symbol: movement.symbol,
+            from_address: movement.from.into(),
+            to_address: movement.to.into(),
+            amount: movement.amount.into(),
+            usd_value_cents: (usd_value * 100.0) as u64,
+            exchange_flow: exchange_flow as u8,
+        };
+        self.tables.whale_alerts.insert(row.clone())?;
+        debug!(
+            "Whale alert: {} {} (${:.0}) in {:?}",
+            units, row.symbol, usd_value, movement.tx_hash
+        );
+        self.events.publish(ChainEvent::WhaleAlert(row));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::cmc::CoinMarketCap;
+    use crate::price_history::QuoteInterval;
+    use ethers::types::Chain;
+
+    const TIMESTAMP_S: u32 = 1_700_000_000;
+
+    /// Alerter pricing ETH at $2000 from a stored quote, so nothing is
+    /// fetched
+    async fn alerter() -> (WhaleAlerter, Arc<Tables>) {
+        let tables = Arc::new(Tables::default());
+        let native = NativeCoin::for_chain(Chain::Mainnet).unwrap();
+        let AssetId::Cmc(native_id) = native.asset else {
+            unreachable!()
+        };
+        tables
+            .price_history
+            .record(native_id, TIMESTAMP_S, 2000.0, true)
+            .await
+            .unwrap();
+        let cmc = Arc::new(CoinMarketCap::new("test").unwrap());
+        let history = Arc::new(PriceHistory::new(
+            cmc,
+            tables.clone(),
+            QuoteInterval::Hourly,
+        ));
+        let alerter = WhaleAlerter::new(
+            Arc::new(EthersClient::new("http://127.0.0.1:1", None)),
+            history,
+            tables.clone(),
+            EventBus::default(),
+            native,
+            WhaleAlertThresholds::default(),
+            false,
+        );
+        (alerter, tables)
+    }
+
+    /// 1000 ETH, $2M at the stored price
+    fn transaction(status: u8) -> TransactionRow {
+        TransactionRow {
+            id: 0,
+            hash: [1; 32],
+            status,
+            block_number: 1,
+            timestamp_s: TIMESTAMP_S,
+            from_address: H160::repeat_byte(2).into(),
+            to_address: Some(H160::repeat_byte(3).into()),
+            value: (U256::exp10(18) * U256::from(1000)).into(),
+            fee: U256::zero().into(),
+            gas: None,
+            nonce: 0,
+            gas_limit: 21_000,
+            gas_used: 21_000,
+            input: vec![],
+            value_usd_cents: None,
+            fee_usd_cents: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn alerts_whale_transaction() {
+        let (alerter, tables) = alerter().await;
+        alerter.check_transaction(&transaction(1)).await.unwrap();
+        assert_eq!(tables.whale_alerts.select_all().execute().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ignores_reverted_whale_transaction() {
+        let (alerter, tables) = alerter().await;
+        alerter.check_transaction(&transaction(0)).await.unwrap();
+        assert!(tables.whale_alerts.select_all().execute().unwrap().is_empty());
+    }
+}
@@ -1,15 +1,53 @@
 use ethers::prelude::*;
+use ethers::providers::RpcError;
 use eyre::Result;
 use eyre::{Context, ContextCompat};
 use serde::Serialize;
 use std::ops::Deref;
+use tracing::debug;
 
-mod cmc;
-mod assets;
-mod models;
+pub mod cmc;
+pub mod cmc_cache;
+pub mod cmc_keys;
+pub mod aggregate;
+pub mod assets;
+pub mod coingecko;
+pub mod models;
 
 
 pub type BlockWithTx = Block<Transaction>;
+
+/// keccak256("Transfer(address,address,uint256)")
+pub const TRANSFER_EVENT_TOPIC: H256 = H256([
+    0xdd, 0xf2, 0x52, 0xad, 0x1b, 0xe2, 0xc8, 0x9b, 0x69, 0xc2, 0xb0, 0x68, 0xfc, 0x37, 0x8d, 0xaa,
+    0x95, 0x2b, 0xa7, 0xf1, 0x63, 0xc4, 0xa1, 0x16, 0x28, 0xf5, 0x5a, 0x4d, 0xf5, 0x23, 0xb3, 0xef,
+]);
+/// keccak256("Upgraded(address)"), emitted by EIP-1967 proxies and beacons
+pub const UPGRADED_EVENT_TOPIC: H256 = H256([
+    0xbc, 0x7c, 0xd7, 0x5a, 0x20, 0xee, 0x27, 0xfd, 0x9a, 0xde, 0xba, 0xb3, 0x20, 0x41, 0xf7, 0x55,
+    0x21, 0x4d, 0xbc, 0x6b, 0xff, 0xa9, 0x0c, 0xc0, 0x22, 0x5b, 0x39, 0xda, 0x2e, 0x5c, 0x2d, 0x3b,
+]);
+/// keccak256("BeaconUpgraded(address)")
+pub const BEACON_UPGRADED_EVENT_TOPIC: H256 = H256([
+    0x1c, 0xf3, 0xb0, 0x3a, 0x6c, 0xf1, 0x9f, 0xa2, 0xba, 0xba, 0x4d, 0xf1, 0x48, 0xe9, 0xdc, 0xab,
+    0xed, 0xea, 0x7f, 0x8a, 0x5c, 0x07, 0x84, 0x0e, 0x20, 0x7e, 0x5c, 0x08, 0x9b, 0xe9, 0x5d, 0x3e,
+]);
+/// keccak256("Sync(uint112,uint112)"), emitted by Uniswap V2 pairs with their
+/// reserves after every change
+pub const UNISWAP_V2_SYNC_EVENT_TOPIC: H256 = H256([
+    0x1c, 0x41, 0x1e, 0x9a, 0x96, 0xe0, 0x71, 0x24, 0x1c, 0x2f, 0x21, 0xf7, 0x72, 0x6b, 0x17, 0xae,
+    0x89, 0xe3, 0xca, 0xb4, 0xc7, 0x8b, 0xe5, 0x0e, 0x06, 0x2b, 0x03, 0xa9, 0xff, 0xfb, 0xba, 0xd1,
+]);
+/// keccak256("Swap(address,uint256,uint256,uint256,uint256,address)")
+pub const UNISWAP_V2_SWAP_EVENT_TOPIC: H256 = H256([
+    0xd7, 0x8a, 0xd9, 0x5f, 0xa4, 0x6c, 0x99, 0x4b, 0x65, 0x51, 0xd0, 0xda, 0x85, 0xfc, 0x27, 0x5f,
+    0xe6, 0x13, 0xce, 0x37, 0x65, 0x7f, 0xb8, 0xd5, 0xe3, 0xd1, 0x30, 0x84, 0x01, 0x59, 0xd8, 0x22,
+]);
+/// keccak256("Swap(address,address,int256,int256,uint160,uint128,int24)")
+pub const UNISWAP_V3_SWAP_EVENT_TOPIC: H256 = H256([
+    0xc4, 0x20, 0x79, 0xf9, 0x4a, 0x63, 0x50, 0xd7, 0xe6, 0x23, 0x5f, 0x29, 0x17, 0x49, 0x24, 0xf9,
+    0x28, 0xcc, 0x2a, 0xc8, 0x18, 0xeb, 0x64, 0xfe, 0xd8, 0x00, 0x4e, 0x11, 0x5f, 0xbc, 0xca, 0x67,
+]);
 #[derive(Debug, serde::Deserialize, Serialize, Clone)]
 pub enum BlockType {
     Confirmed(u64),
@@ -111,10 +149,30 @@ impl EthersClient {
         }
         Err(error.unwrap())
     }
+    /// Receipts of every transaction of a block, one receipt at a time from
+    /// nodes without `eth_getBlockReceipts`
+    pub async fn get_block_receipts(&self, block: &BlockWithTx) -> Result<Vec<TransactionReceipt>> {
+        let number = block.number.context("block without a number")?;
+        match self.client.get_block_receipts(number).await {
+            Ok(receipts) if receipts.len() == block.transactions.len() => return Ok(receipts),
+            Ok(_) => debug!("Incomplete receipts of block {}, fetching them one by one", number),
+            Err(e) => debug!("eth_getBlockReceipts failed for {}: {:?}", number, e),
+        }
+        let mut receipts = Vec::with_capacity(block.transactions.len());
+        for tx in &block.transactions {
+            receipts.push(self.get_transaction_receipt(tx.hash).await?);
+        }
+        Ok(receipts)
+    }
     pub async fn get_block_by_number(&self, block_number: BlockId) -> Result<Option<BlockWithTx>> {
         let block = self.client.get_block_with_txs(block_number).await?;
         Ok(block)
     }
+    pub async fn get_chain(&self) -> Result<Chain> {
+        let chain_id = self.client.get_chainid().await?;
+        Chain::try_from(chain_id.as_u64())
+            .with_context(|| format!("Unknown chain id {}", chain_id))
+    }
     pub async fn get_latest_block(&self) -> Result<BlockWithTx> {
         let block = self
             .client
@@ -123,6 +181,57 @@ impl EthersClient {
             .with_context(|| format!("Failed to get latest block"))?;
         Ok(block)
     }
+    /// Logs emitted in a block whose first topic is one of `topics`
+    pub async fn get_block_logs(&self, block_number: u64, topics: Vec<H256>) -> Result<Vec<Log>> {
+        let filter = Filter::new().select(block_number).topic0(topics);
+        let logs = self.client.get_logs(&filter).await?;
+        Ok(logs)
+    }
+    pub async fn get_storage_at(
+        &self,
+        address: Address,
+        slot: H256,
+        block_number: BlockId,
+    ) -> Result<H256> {
+        let value = self
+            .client
+            .get_storage_at(address, slot, Some(block_number))
+            .await?;
+        Ok(value)
+    }
+    /// `eth_call` against the given block
+    pub async fn call_contract(
+        &self,
+        to: Address,
+        data: Bytes,
+        block_number: BlockId,
+    ) -> Result<Bytes> {
+        let tx = TransactionRequest::new().to(to).data(data);
+        let output = self.client.call(&tx.into(), Some(block_number)).await?;
+        Ok(output)
+    }
+    /// Like `call_contract`, but `None` if the call reverted. Errors from the
+    /// node or the transport are still errors
+    pub async fn try_call_contract(
+        &self,
+        to: Address,
+        data: Bytes,
+        block_number: BlockId,
+    ) -> Result<Option<Bytes>> {
+        let tx = TransactionRequest::new().to(to).data(data);
+        match self.client.call(&tx.into(), Some(block_number)).await {
+            Ok(output) => Ok(Some(output)),
+            // contracts compiled before REVERT existed fail with an invalid opcode
+            Err(e)
+                if RpcError::as_error_response(&e).is_some_and(|response| {
+                    response.is_revert() || response.message.contains("invalid opcode")
+                }) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
     pub async fn get_contract_bytecode(
         &self,
         address: Address,
@@ -148,14 +257,28 @@ impl EthersClient {
         Ok(content)
     }
 
+    /// Value, sender and recipient of the largest call below the top-level
+    /// one, which is the transaction itself
     pub async fn get_internal_largest_transfer(
         &self,
         tx_hash: TxHash,
-    ) -> Result<Option<(U256, Address)>> {
+    ) -> Result<Option<(U256, Address, Address)>> {
         let res = self.client.trace_transaction(tx_hash).await?;
+        // a call that reverted moved nothing, nor did any call under it
+        let reverted: Vec<&Vec<usize>> = res
+            .iter()
+            .filter(|trace| trace.error.is_some())
+            .map(|trace| &trace.trace_address)
+            .collect();
 
         let largest_call = res
             .iter()
+            .filter(|trace| !trace.trace_address.is_empty())
+            .filter(|trace| {
+                !reverted
+                    .iter()
+                    .any(|address| trace.trace_address.starts_with(address))
+            })
             .filter(|trace| match trace.action {
                 Action::Call(_) => return true,
                 _ => return false,
@@ -168,7 +291,7 @@ impl EthersClient {
             .max_by(|a, b| a.value.cmp(&b.value));
 
         if let Some(call) = largest_call {
-            return Ok(Some((call.value, call.to)));
+            return Ok(Some((call.value, call.from, call.to)));
         } else {
             return Ok(None);
         }
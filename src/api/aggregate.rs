@@ -0,0 +1,483 @@
+use super::assets::{AssetId, AssetInfoClient, AssetPriceByPeriod};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::*;
+
+/// How the accepted prices of an asset are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregationMethod {
+    Median,
+    /// Weighted by each source's `weight`, scaled down for prices backed by
+    /// less than `full_weight_liquidity_usd` of liquidity
+    Weighted,
+}
+
+#[derive(Clone)]
+pub struct PriceSource {
+    pub name: String,
+    pub client: Arc<dyn AssetInfoClient>,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AggregationConfig {
+    pub method: AggregationMethod,
+    /// Prices further than this fraction from the median are rejected
+    pub tolerance: f64,
+    /// A source slower than this is treated as failed
+    pub timeout: Duration,
+    /// A failed source's last price is used until it is this old
+    pub max_staleness: Duration,
+    /// Assets fewer sources agree on the price of, within the tolerance,
+    /// are left unpriced, so a single source can't set a price on its own
+    pub min_sources: usize,
+    /// Liquidity a price reported with one needs to count with its source's
+    /// full weight
+    pub full_weight_liquidity_usd: f64,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self {
+            method: AggregationMethod::Median,
+            tolerance: 0.05,
+            timeout: Duration::from_secs(10),
+            max_staleness: Duration::from_secs(15 * 60),
+            min_sources: 2,
+            full_weight_liquidity_usd: 1_000_000.0,
+        }
+    }
+}
+
+/// One source's price of an asset
+#[derive(Debug, Clone)]
+pub struct SourcePrice {
+    pub source: String,
+    pub price_usd: f64,
+    /// Behind the price, for sources that report it
+    pub liquidity_usd: Option<f64>,
+    /// When the source returned it, earlier than the query for a price kept
+    /// from before the source failed
+    pub observed_at: DateTime<Utc>,
+    /// Whether it was within the tolerance and went into the price
+    pub accepted: bool,
+}
+
+impl SourcePrice {
+    pub fn age(&self) -> chrono::Duration {
+        Utc::now() - self.observed_at
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AggregatedPrice {
+    pub asset: AssetId,
+    pub price_usd: f64,
+    pub sources: Vec<SourcePrice>,
+}
+
+/// A source's last price of an asset with its liquidity, and when it was
+/// observed
+type LastKnownPrices = HashMap<(AssetId, usize), (f64, Option<f64>, DateTime<Utc>)>;
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Combines several price sources queried concurrently.
+///
+/// Each asset's prices are compared to their median and those outside the
+/// tolerance rejected before the rest are combined. An asset fewer than
+/// `min_sources` sources agree on is left out. A source that fails or
+/// times out is left out, with its last prices standing in while they are
+/// fresh enough, so one provider being down never fails the batch.
+pub struct AggregatePriceClient {
+    sources: Vec<PriceSource>,
+    config: AggregationConfig,
+    /// Last price of each asset by source index, for when it fails
+    last_known: Mutex<LastKnownPrices>,
+}
+
+impl AggregatePriceClient {
+    pub fn new(sources: Vec<PriceSource>, config: AggregationConfig) -> Self {
+        Self {
+            sources,
+            config,
+            last_known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Every source's latest prices and liquidity, by source index. Failed
+    /// sources have no entry
+    async fn query_latest(
+        &self,
+        assets: &[AssetId],
+    ) -> HashMap<usize, HashMap<AssetId, (f64, Option<f64>)>> {
+        let mut tasks = JoinSet::new();
+        for (index, source) in self.sources.iter().enumerate() {
+            let client = source.client.clone();
+            let assets = assets.to_vec();
+            let timeout = self.config.timeout;
+            tasks.spawn(async move {
+                let prices = client.get_usd_price_latest_with_liquidity(&assets);
+                (index, tokio::time::timeout(timeout, prices).await)
+            });
+        }
+        let mut results = HashMap::new();
+        while let Some(joined) = tasks.join_next().await {
+            let (index, result) = match joined {
+                Result::Ok(joined) => joined,
+                Err(e) => {
+                    warn!("Price source task failed: {:?}", e);
+                    continue;
+                }
+            };
+            match result {
+                Result::Ok(Result::Ok(prices)) => {
+                    results.insert(index, prices);
+                }
+                Result::Ok(Err(e)) => {
+                    warn!("Price source {} failed: {:?}", self.sources[index].name, e)
+                }
+                Err(_) => warn!("Price source {} timed out", self.sources[index].name),
+            }
+        }
+        results
+    }
+
+    /// The source's weight, scaled down for a price with less than the full
+    /// weight liquidity behind it
+    fn price_weight(&self, index: usize, price: &SourcePrice) -> f64 {
+        let weight = self.sources[index].weight.max(0.0);
+        match price.liquidity_usd {
+            Some(liquidity_usd) => {
+                let share = liquidity_usd / self.config.full_weight_liquidity_usd;
+                weight * share.clamp(0.0, 1.0)
+            }
+            None => weight,
+        }
+    }
+
+    fn aggregate(
+        &self,
+        asset: &AssetId,
+        mut prices: Vec<(usize, SourcePrice)>,
+    ) -> Option<AggregatedPrice> {
+        let min_sources = self.config.min_sources.max(1);
+        if prices.len() < min_sources {
+            return None;
+        }
+        let center = median(
+            &mut prices
+                .iter()
+                .map(|(_, price)| price.price_usd)
+                .collect::<Vec<_>>(),
+        );
+        for (_, price) in &mut prices {
+            price.accepted = (price.price_usd - center).abs() <= center * self.config.tolerance;
+        }
+        let accepted: Vec<&(usize, SourcePrice)> =
+            prices.iter().filter(|(_, price)| price.accepted).collect();
+        if accepted.len() < min_sources {
+            // nothing to tell the outliers from
+            let sources: Vec<&SourcePrice> = prices.iter().map(|(_, price)| price).collect();
+            warn!("Price sources disagree on {}: {:?}", asset, sources);
+            return None;
+        }
+        let price_usd = match self.config.method {
+            AggregationMethod::Median => median(
+                &mut accepted
+                    .iter()
+                    .map(|(_, price)| price.price_usd)
+                    .collect::<Vec<_>>(),
+            ),
+            AggregationMethod::Weighted => {
+                let total: f64 = accepted
+                    .iter()
+                    .map(|(index, price)| self.price_weight(*index, price))
+                    .sum();
+                if total > 0.0 {
+                    accepted
+                        .iter()
+                        .map(|(index, price)| price.price_usd * self.price_weight(*index, price))
+                        .sum::<f64>()
+                        / total
+                } else {
+                    median(
+                        &mut accepted
+                            .iter()
+                            .map(|(_, price)| price.price_usd)
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            }
+        };
+        Some(AggregatedPrice {
+            asset: asset.clone(),
+            price_usd,
+            sources: prices.into_iter().map(|(_, price)| price).collect(),
+        })
+    }
+
+    /// Latest prices with the sources that went into each
+    pub async fn get_usd_prices_with_provenance(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AggregatedPrice>> {
+        let now = Utc::now();
+        let results = self.query_latest(assets).await;
+        let max_staleness = chrono::Duration::from_std(self.config.max_staleness)?;
+        let mut last_known = self.last_known.lock().unwrap();
+        let mut aggregated = HashMap::new();
+        for asset in assets {
+            let mut prices = vec![];
+            for (index, source) in self.sources.iter().enumerate() {
+                let observed = match results.get(&index) {
+                    Some(prices) => {
+                        let fresh = prices.get(asset).copied().filter(|(price, _)| *price > 0.0);
+                        if let Some((price, liquidity_usd)) = fresh {
+                            last_known.insert((asset.clone(), index), (price, liquidity_usd, now));
+                        }
+                        fresh.map(|(price, liquidity_usd)| (price, liquidity_usd, now))
+                    }
+                    // the source failed, not just missing the asset
+                    None => last_known
+                        .get(&(asset.clone(), index))
+                        .copied()
+                        .filter(|(.., observed_at)| now - *observed_at <= max_staleness),
+                };
+                let Some((price_usd, liquidity_usd, observed_at)) = observed else {
+                    continue;
+                };
+                prices.push((
+                    index,
+                    SourcePrice {
+                        source: source.name.clone(),
+                        price_usd,
+                        liquidity_usd,
+                        observed_at,
+                        accepted: false,
+                    },
+                ));
+            }
+            if let Some(price) = self.aggregate(asset, prices) {
+                aggregated.insert(asset.clone(), price);
+            }
+        }
+        Ok(aggregated)
+    }
+}
+
+#[async_trait]
+impl AssetInfoClient for AggregatePriceClient {
+    async fn get_usd_price_latest(&self, assets: &[AssetId]) -> Result<HashMap<AssetId, f64>> {
+        Ok(self
+            .get_usd_prices_with_provenance(assets)
+            .await?
+            .into_iter()
+            .map(|(asset, price)| (asset, price.price_usd))
+            .collect())
+    }
+
+    /// Each price of the period aggregated on its own over the sources that
+    /// have it
+    async fn get_usd_price_period(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AssetPriceByPeriod>> {
+        let latest = self.get_usd_prices_with_provenance(assets).await?;
+        let mut tasks = JoinSet::new();
+        for (index, source) in self.sources.iter().enumerate() {
+            let client = source.client.clone();
+            let assets = assets.to_vec();
+            let timeout = self.config.timeout;
+            tasks.spawn(async move {
+                let result =
+                    tokio::time::timeout(timeout, client.get_usd_price_period(&assets)).await;
+                (index, result)
+            });
+        }
+        let mut periods: Vec<(usize, HashMap<AssetId, AssetPriceByPeriod>)> = vec![];
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Result::Ok((index, Result::Ok(Result::Ok(period)))) => {
+                    periods.push((index, period))
+                }
+                Result::Ok((index, Result::Ok(Err(e)))) => {
+                    warn!("Price source {} failed: {:?}", self.sources[index].name, e)
+                }
+                Result::Ok((index, Err(_))) => {
+                    warn!("Price source {} timed out", self.sources[index].name)
+                }
+                Err(e) => warn!("Price source task failed: {:?}", e),
+            }
+        }
+
+        let now = Utc::now();
+        let mut result = HashMap::new();
+        for (asset, price) in latest {
+            let then = |field: fn(&AssetPriceByPeriod) -> Option<f64>| {
+                let prices = periods
+                    .iter()
+                    .filter_map(|(index, period)| {
+                        let price = field(period.get(&asset)?).filter(|price| *price > 0.0)?;
+                        Some((
+                            *index,
+                            SourcePrice {
+                                source: self.sources[*index].name.clone(),
+                                price_usd: price,
+                                liquidity_usd: None,
+                                observed_at: now,
+                                accepted: false,
+                            },
+                        ))
+                    })
+                    .collect();
+                self.aggregate(&asset, prices).map(|price| price.price_usd)
+            };
+            result.insert(
+                asset.clone(),
+                AssetPriceByPeriod {
+                    asset: asset.clone(),
+                    price_latest: price.price_usd,
+                    price_1d: then(|period| period.price_1d),
+                    price_7d: then(|period| period.price_7d),
+                    price_30d: then(|period| period.price_30d),
+                },
+            );
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoPrices;
+
+    #[async_trait]
+    impl AssetInfoClient for NoPrices {
+        async fn get_usd_price_latest(&self, _: &[AssetId]) -> Result<HashMap<AssetId, f64>> {
+            Ok(HashMap::new())
+        }
+
+        async fn get_usd_price_period(
+            &self,
+            _: &[AssetId],
+        ) -> Result<HashMap<AssetId, AssetPriceByPeriod>> {
+            Ok(HashMap::new())
+        }
+    }
+
+    fn client(weights: &[f64], method: AggregationMethod) -> AggregatePriceClient {
+        let sources = weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| PriceSource {
+                name: format!("source{}", index),
+                client: Arc::new(NoPrices),
+                weight: *weight,
+            })
+            .collect();
+        let config = AggregationConfig {
+            method,
+            ..AggregationConfig::default()
+        };
+        AggregatePriceClient::new(sources, config)
+    }
+
+    /// Prices by source index, with the liquidity behind them
+    fn prices(quotes: &[(usize, f64, Option<f64>)]) -> Vec<(usize, SourcePrice)> {
+        quotes
+            .iter()
+            .map(|(index, price_usd, liquidity_usd)| {
+                let price = SourcePrice {
+                    source: format!("source{}", index),
+                    price_usd: *price_usd,
+                    liquidity_usd: *liquidity_usd,
+                    observed_at: Utc::now(),
+                    accepted: false,
+                };
+                (*index, price)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn takes_the_middle_or_mean_of_the_middle_two() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
+
+    #[test]
+    fn rejects_prices_outside_the_tolerance() {
+        let client = client(&[1.0, 1.0, 1.0], AggregationMethod::Median);
+        let asset = AssetId::Cmc(1);
+        let price = client
+            .aggregate(
+                &asset,
+                prices(&[(0, 100.0, None), (1, 101.0, None), (2, 150.0, None)]),
+            )
+            .unwrap();
+        assert_eq!(price.price_usd, 100.5);
+        let accepted: Vec<bool> = price.sources.iter().map(|price| price.accepted).collect();
+        assert_eq!(accepted, vec![true, true, false]);
+    }
+
+    #[test]
+    fn leaves_a_single_source_unpriced() {
+        let client = client(&[1.0, 1.0], AggregationMethod::Median);
+        let asset = AssetId::Cmc(1);
+        assert!(client
+            .aggregate(&asset, prices(&[(1, 100.0, None)]))
+            .is_none());
+    }
+
+    #[test]
+    fn weights_thin_liquidity_down() {
+        let client = client(&[1.0, 1.0], AggregationMethod::Weighted);
+        let asset = AssetId::Cmc(1);
+        // a quarter of the full weight liquidity counts a quarter
+        let price = client
+            .aggregate(
+                &asset,
+                prices(&[(0, 100.0, None), (1, 102.0, Some(250_000.0))]),
+            )
+            .unwrap();
+        assert!((price.price_usd - 100.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn leaves_disagreeing_sources_unpriced() {
+        let client = client(&[1.0, 2.0], AggregationMethod::Weighted);
+        let asset = AssetId::Cmc(1);
+        assert!(client
+            .aggregate(&asset, prices(&[(0, 100.0, None), (1, 300.0, None)]))
+            .is_none());
+    }
+
+    #[test]
+    fn counts_only_accepted_prices_towards_min_sources() {
+        let client = client(&[1.0, 1.0, 1.0], AggregationMethod::Median);
+        let asset = AssetId::Cmc(1);
+        // only the median itself is within the tolerance
+        assert!(client
+            .aggregate(
+                &asset,
+                prices(&[(0, 100.0, None), (1, 200.0, None), (2, 300.0, None)]),
+            )
+            .is_none());
+    }
+}
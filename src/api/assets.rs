@@ -1,22 +1,62 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use async_trait::async_trait;
+use ethers::types::{Chain, H160};
 use eyre::*;
 
+/// How an asset is named to a price source. Symbols are ambiguous, many
+/// listings share popular ones, so prefer ids wherever one is known
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AssetId {
+    /// CoinMarketCap id
+    Cmc(u64),
+    /// CoinGecko id, e.g. `ethereum`
+    CoinGecko(String),
+    /// Token contract on a chain
+    Contract(Chain, H160),
+    Symbol(String),
+}
+
+impl fmt::Display for AssetId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetId::Cmc(id) => write!(f, "cmc:{}", id),
+            AssetId::CoinGecko(id) => write!(f, "coingecko:{}", id),
+            AssetId::Contract(chain, address) => write!(f, "{}:{:?}", chain, address),
+            AssetId::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AssetPriceByPeriod {
-    pub symbol: String,
+    pub asset: AssetId,
     pub price_latest: f64,
     pub price_1d: Option<f64>,
     pub price_7d: Option<f64>,
     pub price_30d: Option<f64>,
 }
 
+/// Assets the source has no price for are left out of the results
 #[async_trait]
 pub trait AssetInfoClient: Sync + Send {
-    async fn get_usd_price_latest(&self, symbols: &[String]) -> Result<HashMap<String, f64>>;
+    async fn get_usd_price_latest(&self, assets: &[AssetId]) -> Result<HashMap<AssetId, f64>>;
+    /// Latest prices with the USD liquidity behind each, for sources that
+    /// know it
+    async fn get_usd_price_latest_with_liquidity(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, (f64, Option<f64>)>> {
+        Ok(self
+            .get_usd_price_latest(assets)
+            .await?
+            .into_iter()
+            .map(|(asset, price)| (asset, (price, None)))
+            .collect())
+    }
     async fn get_usd_price_period(
         &self,
-        symbols: &[String],
-    ) -> Result<HashMap<String, AssetPriceByPeriod>>;
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AssetPriceByPeriod>>;
 }
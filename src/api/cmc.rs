@@ -1,7 +1,9 @@
-use super::assets::{AssetInfoClient, AssetPriceByPeriod};
+use super::assets::{AssetId, AssetInfoClient, AssetPriceByPeriod};
+use super::cmc_cache::{CachedPrice, CmcPriceStore};
+use super::cmc_keys::{estimate_credits, CallOutcome, CmcCreditBudget, CmcKeyPool, CmcKeyUsage};
 use super::models::*;
 use async_trait::async_trait;
-use chrono::{Duration, NaiveDate, Utc};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use dashmap::DashMap;
 use ethers::types::Chain;
 use eyre::*;
@@ -12,19 +14,22 @@ use serde::de::DeserializeOwned;
 use serde_json::{from_value, Value};
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::Mutex;
 use tracing::*;
 
-const API_KEY: &str = "ec6c4b09-03e6-4bd6-84f9-95406fc2ce81";
 const BASE_URL: &str = "https://pro-api.coinmarketcap.com";
 const LATEST_QUOTES_URL: &str = "/v2/cryptocurrency/quotes/latest";
 const HISTORICAL_QUOTE_URL: &str = "/v2/cryptocurrency/quotes/historical";
 const METADATA_URL: &str = "/v1/cryptocurrency/info";
 const MAP_URL: &str = "/v1/cryptocurrency/map";
 const LISTING_URL: &str = "/v1/cryptocurrency/listings/latest";
+/// CMC refreshes latest quotes every minute, prices cached for today are
+/// fetched again once this old
+pub const DEFAULT_LATEST_PRICE_TTL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
 
 pub fn try_deserialize<T: DeserializeOwned>(data: Value) -> Result<T> {
     let data = serde_json::to_string(&data)?;
@@ -32,33 +37,160 @@ pub fn try_deserialize<T: DeserializeOwned>(data: Value) -> Result<T> {
     let value = serde_path_to_error::deserialize(jd)?;
     Ok(value)
 }
+
+/// The historical endpoint only wraps the result in an object keyed by id
+/// when several ids are requested
+fn historical_entry(payload: &Value, id: u64) -> &Value {
+    match payload.get(id.to_string()) {
+        Some(entry) => entry,
+        None => payload,
+    }
+}
+
+fn ids_param(ids: &[u64]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+}
+
+/// Prices by day with when they were fetched, in unix seconds. Latest
+/// prices are under `None`, apart from the historical endpoint's days
+type PriceCache<K> = Mutex<LruCache<(Option<NaiveDate>, K), (f64, i64)>>;
+
 #[derive(Debug)]
 pub struct CoinMarketCap {
     client: Client,
+    keys: CmcKeyPool,
     base_url: String,
-    price_cache: Mutex<LruCache<(NaiveDate, String), f64>>,
+    price_cache: PriceCache<String>,
+    id_price_cache: PriceCache<u64>,
     persistent_price_cache: DashMap<String, f64>,
+    latest_ttl: std::time::Duration,
+    store: Option<CmcPriceStore>,
     //no_reattempt_symbols: DashSet<String>,
 }
 impl CoinMarketCap {
-    pub fn new_debug_key() -> Result<Self> {
-        Self::new(API_KEY)
-    }
     pub fn new(api_key: &str) -> Result<Self> {
+        Self::with_keys(&[api_key.to_string()], CmcCreditBudget::default())
+    }
+    /// Keys are used in turn, see `CmcKeyPool` for how limits and the
+    /// budget are enforced
+    pub fn with_keys(api_keys: &[String], budget: CmcCreditBudget) -> Result<Self> {
         let mut headers = HeaderMap::new();
-        headers.insert("X-CMC_PRO_API_KEY", HeaderValue::from_str(api_key)?);
         headers.insert("Accept", HeaderValue::from_static("application/json"));
         headers.insert("Accept-Encoding", HeaderValue::from_static("deflate, gzip"));
 
         Ok(Self {
             base_url: BASE_URL.to_string(),
             client: Client::builder().default_headers(headers).build()?,
+            keys: CmcKeyPool::new(api_keys, budget)?,
             price_cache: Mutex::new(LruCache::new(NonZeroUsize::new(30000).unwrap())),
+            id_price_cache: Mutex::new(LruCache::new(NonZeroUsize::new(30000).unwrap())),
             persistent_price_cache: DashMap::new(),
+            latest_ttl: DEFAULT_LATEST_PRICE_TTL,
+            store: None,
             //no_reattempt_symbols: DashSet::new(),
         })
     }
 
+    /// Loads the caches from the price log at `path` and appends every
+    /// price fetched from then on, see `CmcPriceStore`
+    pub fn with_price_store(
+        mut self,
+        path: &Path,
+        latest_ttl: std::time::Duration,
+    ) -> Result<Self> {
+        let (store, entries) = CmcPriceStore::open(path, latest_ttl)?;
+        info!(
+            "Loaded {} cached CMC prices from {}",
+            entries.len(),
+            path.display()
+        );
+        let price_cache = self.price_cache.get_mut();
+        let id_price_cache = self.id_price_cache.get_mut();
+        for entry in entries {
+            match entry {
+                CachedPrice::Symbol {
+                    date,
+                    symbol,
+                    price,
+                    at,
+                } => {
+                    price_cache.put((date, symbol), (price, at));
+                }
+                CachedPrice::Id {
+                    date,
+                    id,
+                    price,
+                    at,
+                } => {
+                    id_price_cache.put((date, id), (price, at));
+                }
+                CachedPrice::Listing { symbol, price, .. } => {
+                    self.persistent_price_cache.insert(symbol, price);
+                }
+            }
+        }
+        self.latest_ttl = store.latest_ttl();
+        self.store = Some(store);
+        Ok(self)
+    }
+
+    /// A day's price fetched after the day was over is kept for good, the
+    /// rest for the TTL
+    fn is_fresh(&self, date: Option<NaiveDate>, at: i64) -> bool {
+        CachedPrice::is_final(date, at)
+            || Utc::now().timestamp() - at <= self.latest_ttl.as_secs() as i64
+    }
+
+    async fn cached_symbol_price(&self, date: Option<NaiveDate>, symbol: &str) -> Option<f64> {
+        let mut cache = self.price_cache.lock().await;
+        let (price, at) = *cache.get(&(date, symbol.to_string()))?;
+        self.is_fresh(date, at).then_some(price)
+    }
+
+    /// Zero prices stand in for failed lookups and are kept out of the log
+    async fn cache_symbol_price(&self, date: Option<NaiveDate>, symbol: String, price: f64) {
+        let at = Utc::now().timestamp();
+        if let Some(store) = self.store.as_ref().filter(|_| price > 0.0) {
+            store.record(&CachedPrice::Symbol {
+                date,
+                symbol: symbol.clone(),
+                price,
+                at,
+            });
+        }
+        self.price_cache
+            .lock()
+            .await
+            .put((date, symbol), (price, at));
+    }
+
+    async fn cache_id_price(&self, date: Option<NaiveDate>, id: u64, price: f64) {
+        let at = Utc::now().timestamp();
+        if let Some(store) = self.store.as_ref().filter(|_| price > 0.0) {
+            store.record(&CachedPrice::Id {
+                date,
+                id,
+                price,
+                at,
+            });
+        }
+        self.id_price_cache
+            .lock()
+            .await
+            .put((date, id), (price, at));
+    }
+
+    fn cache_listing_price(&self, symbol: String, price: f64) {
+        if let Some(store) = &self.store {
+            store.record(&CachedPrice::Listing {
+                symbol: symbol.clone(),
+                price,
+                at: Utc::now().timestamp(),
+            });
+        }
+        self.persistent_price_cache.insert(symbol, price);
+    }
+
     pub async fn get_token_infos_by_symbol_v2(
         &self,
         symbols: &[String],
@@ -71,6 +203,28 @@ impl CoinMarketCap {
         Ok(tokens)
     }
 
+    /// Coins that fail to parse are left out rather than failing the batch
+    pub async fn get_token_infos_by_id(&self, ids: &[u64]) -> Result<HashMap<u64, CoinInfo>> {
+        let mut url = self.metadata_url();
+        self.append_url_params(&mut url, "id", &ids_param(ids));
+        self.append_url_params(&mut url, "aux", &["status".to_string()]);
+        self.append_url_params(&mut url, "skip_invalid", &["true".to_string()]);
+        let tokens: HashMap<String, Value> = self.send_and_parse_response(&url).await?;
+
+        let mut infos = HashMap::with_capacity(tokens.len());
+        for (id, token) in tokens {
+            match try_deserialize::<CoinInfo>(token) {
+                Result::Ok(info) => {
+                    infos.insert(info.id as u64, info);
+                }
+                Err(e) => warn!("Failed to parse CMC info of {}: {:?}", id, e),
+            }
+        }
+        Ok(infos)
+    }
+
+    /// A symbol listed by several coins is priced as whichever CMC returns
+    /// first, use the `_by_id` variants when the id is known
     pub async fn get_usd_prices_by_symbol_with_options(
         &self,
         symbols: &[String],
@@ -78,17 +232,12 @@ impl CoinMarketCap {
     ) -> Result<HashMap<String, f64>> {
         let begin = Instant::now();
 
-        let date = Utc::now().date_naive();
+        let date = None;
         let mut token_prices: HashMap<String, f64> = HashMap::with_capacity(symbols.len());
 
         for symbol in symbols {
-            if let Some(price) = self
-                .price_cache
-                .lock()
-                .await
-                .get(&(date, symbol.to_string()))
-            {
-                token_prices.insert(symbol.clone(), *price);
+            if let Some(price) = self.cached_symbol_price(date, symbol).await {
+                token_prices.insert(symbol.clone(), price);
             }
         }
         let new_symbols = symbols
@@ -109,10 +258,10 @@ impl CoinMarketCap {
                 }
                 if let Some(price) = token["quote"]["USD"]["price"].as_f64() {
                     token_prices.insert(symbol.clone(), price);
-                    self.price_cache.lock().await.put((date, symbol), price);
+                    self.cache_symbol_price(date, symbol, price).await;
                 } else if tolerate_errors {
                     token_prices.insert(symbol.clone(), 0.0);
-                    self.price_cache.lock().await.put((date, symbol), 0.0);
+                    self.cache_symbol_price(date, symbol, 0.0).await;
                 } else {
                     bail!("price not found for {}", symbol)
                 }
@@ -252,17 +401,12 @@ impl CoinMarketCap {
         tolerate_errors: bool,
     ) -> Result<HashMap<String, f64>> {
         let begin = Instant::now();
-        let date = Utc::now().date_naive() - Duration::days(days as i64);
+        let date = Some(Utc::now().date_naive() - Duration::days(days as i64));
         let mut token_prices: HashMap<String, f64> = HashMap::with_capacity(symbols.len());
 
         for symbol in symbols {
-            if let Some(price) = self
-                .price_cache
-                .lock()
-                .await
-                .get(&(date, symbol.to_string()))
-            {
-                token_prices.insert(symbol.clone(), *price);
+            if let Some(price) = self.cached_symbol_price(date, symbol).await {
+                token_prices.insert(symbol.clone(), price);
             }
         }
         let new_symbols = symbols
@@ -288,10 +432,10 @@ impl CoinMarketCap {
                 let price = quote["price"].as_f64();
                 if let Some(price) = price {
                     token_prices.insert(symbol.clone(), price);
-                    self.price_cache.lock().await.put((date, symbol), price);
+                    self.cache_symbol_price(date, symbol, price).await;
                 } else if tolerate_errors {
                     token_prices.insert(symbol.clone(), 0.0);
-                    self.price_cache.lock().await.put((date, symbol), 0.0);
+                    self.cache_symbol_price(date, symbol, 0.0).await;
                 } else {
                     bail!("price not found for {}", symbol)
                 }
@@ -319,6 +463,184 @@ impl CoinMarketCap {
         Ok(price)
     }
 
+    /// Cached ids for `date`, `None` for the latest prices, and the ids
+    /// still to fetch
+    async fn cached_id_prices(
+        &self,
+        ids: &[u64],
+        date: Option<NaiveDate>,
+    ) -> (HashMap<u64, f64>, Vec<u64>) {
+        let mut cache = self.id_price_cache.lock().await;
+        let mut prices = HashMap::with_capacity(ids.len());
+        let mut missing = vec![];
+        for id in ids {
+            match cache.get(&(date, *id)) {
+                Some((price, at)) if self.is_fresh(date, *at) => {
+                    prices.insert(*id, *price);
+                }
+                _ => missing.push(*id),
+            }
+        }
+        (prices, missing)
+    }
+
+    /// Prices found in `payload` by `price_of`, with 0 for the rest if
+    /// `tolerate_errors`. Every price is cached under `date`
+    async fn collect_id_prices(
+        &self,
+        ids: Vec<u64>,
+        date: Option<NaiveDate>,
+        tolerate_errors: bool,
+        price_of: impl Fn(u64) -> Option<f64>,
+        prices: &mut HashMap<u64, f64>,
+    ) -> Result<()> {
+        for id in ids {
+            let price = match price_of(id) {
+                Some(price) => price,
+                None if tolerate_errors => 0.0,
+                None => bail!("price not found for CMC id {}", id),
+            };
+            prices.insert(id, price);
+            self.cache_id_price(date, id, price).await;
+        }
+        Ok(())
+    }
+
+    /// Latest USD prices by CMC id, bypassing the cache and refreshing it.
+    /// Ids without a quote are left out
+    pub async fn get_usd_prices_by_id(&self, ids: &[u64]) -> Result<HashMap<u64, f64>> {
+        let date = None;
+        let mut url = self.price_url();
+        self.append_url_params(&mut url, "id", &ids_param(ids));
+        self.append_url_params(&mut url, "skip_invalid", &["true".to_string()]);
+        let payload: Value = self.send_and_parse_response(&url).await?;
+        let mut prices = HashMap::with_capacity(ids.len());
+        for id in ids {
+            if let Some(price) = payload[id.to_string()]["quote"]["USD"]["price"].as_f64() {
+                prices.insert(*id, price);
+                self.cache_id_price(date, *id, price).await;
+            }
+        }
+        Ok(prices)
+    }
+
+    /// Same as `get_usd_prices_by_symbol_with_options`, but by CMC id so a
+    /// symbol shared by several listings can't pick the wrong one
+    pub async fn get_usd_prices_by_id_with_options(
+        &self,
+        ids: &[u64],
+        tolerate_errors: bool,
+    ) -> Result<HashMap<u64, f64>> {
+        let begin = Instant::now();
+        let date = None;
+        let (mut prices, new_ids) = self.cached_id_prices(ids, date).await;
+        if !new_ids.is_empty() {
+            let mut url = self.price_url();
+            self.append_url_params(&mut url, "id", &ids_param(&new_ids));
+            self.append_url_params(&mut url, "skip_invalid", &["true".to_string()]);
+            let payload: Value = self.send_and_parse_response(&url).await?;
+            if !tolerate_errors {
+                for id in &new_ids {
+                    if payload[id.to_string()]["is_active"].as_u64() != Some(1) {
+                        bail!("CMC id {} is not active", id)
+                    }
+                }
+            }
+            let price_of = |id: u64| payload[id.to_string()]["quote"]["USD"]["price"].as_f64();
+            self.collect_id_prices(new_ids, date, tolerate_errors, price_of, &mut prices)
+                .await?;
+        }
+        trace!(
+            "get_usd_prices_by_id duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(prices)
+    }
+
+    pub async fn get_usd_price_days_ago_by_id(
+        &self,
+        ids: &[u64],
+        days: u32,
+        tolerate_errors: bool,
+    ) -> Result<HashMap<u64, f64>> {
+        let begin = Instant::now();
+        let date = Some(Utc::now().date_naive() - Duration::days(days as i64));
+        let (mut prices, new_ids) = self.cached_id_prices(ids, date).await;
+        if !new_ids.is_empty() {
+            let mut url = self.quotes_historical_url();
+            let ago = Utc::now() - Duration::days(days as i64);
+            self.append_url_params(&mut url, "id", &ids_param(&new_ids));
+            self.append_url_params(&mut url, "time_start", &[ago.to_rfc3339()]);
+            self.append_url_params(&mut url, "interval", &["daily".to_string()]);
+            self.append_url_params(&mut url, "count", &["1".to_string()]);
+            self.append_url_params(&mut url, "skip_invalid", &["true".to_string()]);
+            let payload: Value = self.send_and_parse_response(&url).await?;
+            let price_of = |id: u64| {
+                historical_entry(&payload, id)["quotes"][0]["quote"]["USD"]["price"].as_f64()
+            };
+            self.collect_id_prices(new_ids, date, tolerate_errors, price_of, &mut prices)
+                .await?;
+        }
+        trace!(
+            "get_usd_price_days_ago_by_id duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(prices)
+    }
+
+    /// USD quotes of one listing from `from` to `to` inclusive, oldest first.
+    /// `interval` is one of the historical endpoint's intervals, e.g. `1h`
+    pub async fn get_usd_quotes_by_id(
+        &self,
+        id: u64,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: &str,
+    ) -> Result<Vec<(DateTime<Utc>, f64)>> {
+        let begin = Instant::now();
+        let mut url = self.quotes_historical_url();
+        self.append_url_params(&mut url, "id", &[id.to_string()]);
+        self.append_url_params(&mut url, "time_start", &[from.to_rfc3339()]);
+        self.append_url_params(&mut url, "time_end", &[to.to_rfc3339()]);
+        self.append_url_params(&mut url, "interval", &[interval.to_string()]);
+        let payload: Value = self.send_and_parse_response(&url).await?;
+        let mut quotes = vec![];
+        for quote in historical_entry(&payload, id)["quotes"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+        {
+            let usd = &quote["quote"]["USD"];
+            let (Some(timestamp), Some(price)) = (usd["timestamp"].as_str(), usd["price"].as_f64())
+            else {
+                continue;
+            };
+            quotes.push((DateTime::parse_from_rfc3339(timestamp)?.to_utc(), price));
+        }
+        quotes.sort_by_key(|(timestamp, _)| *timestamp);
+        trace!(
+            "get_usd_quotes_by_id duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(quotes)
+    }
+
+    /// Price of one listing in another, both by CMC id
+    pub async fn get_quote_price_by_id(&self, base_id: u64, quote_id: u64) -> Result<f64> {
+        let begin = Instant::now();
+        let mut url = self.price_url();
+        self.append_url_params(&mut url, "id", &[base_id.to_string()]);
+        self.append_url_params(&mut url, "convert_id", &[quote_id.to_string()]);
+        let payload: Value = self.send_and_parse_response(&url).await?;
+        let quote = &payload[base_id.to_string()]["quote"][quote_id.to_string()];
+        let price = quote["price"].as_f64().context("price not found")?;
+        trace!(
+            "get_quote_price_by_id duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(price)
+    }
+
     pub async fn get_top_25_coins(&self) -> Result<Vec<MapCoinInfo>> {
         let mut url = self.map_url();
         self.append_url_params(&mut url, "limit", &vec!["25".to_string()]);
@@ -327,6 +649,27 @@ impl CoinMarketCap {
         let data: Vec<MapCoinInfo> = self.send_and_parse_response(&url).await?;
         Ok(data)
     }
+    /// Every active coin and token CMC tracks, with its platform contract
+    pub async fn get_coin_map(&self) -> Result<Vec<MapCoinInfo>> {
+        let mut coins = vec![];
+        let mut offset = 1;
+        let limit = 5000;
+        loop {
+            let mut url = self.map_url();
+            self.append_url_params(&mut url, "start", &[offset.to_string()]);
+            self.append_url_params(&mut url, "limit", &[limit.to_string()]);
+            self.append_url_params(&mut url, "listing_status", &["active".to_string()]);
+
+            let page: Vec<MapCoinInfo> = self.send_and_parse_response(&url).await?;
+            let len = page.len();
+            coins.extend(page);
+            if len < limit {
+                break;
+            }
+            offset += limit;
+        }
+        Ok(coins)
+    }
     fn price_url(&self) -> Url {
         Url::parse(&format!("{}{}", self.base_url, LATEST_QUOTES_URL)).unwrap()
     }
@@ -346,15 +689,37 @@ impl CoinMarketCap {
     }
     pub async fn send_and_parse_response<T: DeserializeOwned>(&self, url: &Url) -> Result<T> {
         trace!("Request: {}", url);
-        let response = self.client.get(url.clone()).send().await?;
-        self.parse_response(response).await
+        let credits = estimate_credits(url);
+        loop {
+            let key = self.keys.acquire(credits).await?;
+            let response = self
+                .client
+                .get(url.clone())
+                .header("X-CMC_PRO_API_KEY", key.header.clone())
+                .send()
+                .await?;
+            let text = response.text().await?;
+            trace!("Response: {}", text);
+            let json = Value::from_str(&text)?;
+            let status = &json["status"];
+            let error_code = status["error_code"].as_u64().unwrap_or(0);
+            let charged = status["credit_count"]
+                .as_u64()
+                .unwrap_or(if error_code == 0 { credits } else { 0 });
+            if self.keys.report(&key, error_code, charged) == CallOutcome::Retry {
+                continue;
+            }
+            return Self::parse_payload(json);
+        }
     }
 
     pub async fn parse_response<T: DeserializeOwned>(&self, response: Response) -> Result<T> {
         let text = response.text().await?;
         trace!("Response: {}", text);
+        Self::parse_payload(Value::from_str(&text)?)
+    }
 
-        let json = Value::from_str(&text)?;
+    fn parse_payload<T: DeserializeOwned>(json: Value) -> Result<T> {
         if let Some(err) = json["status"].get("error_message") {
             if !err.is_null() {
                 bail!("error_message: {}", err);
@@ -363,6 +728,17 @@ impl CoinMarketCap {
         // Ok(try_deserialize(json["data"].clone())?)
         Ok(from_value(json["data"].clone())?)
     }
+
+    /// Keeps the keys' credit counters at `path` across restarts, see
+    /// `CmcKeyPool::with_usage_file`
+    pub fn with_key_usage_file(mut self, path: &Path) -> Result<Self> {
+        self.keys = self.keys.with_usage_file(path)?;
+        Ok(self)
+    }
+    /// Credits spent by each key today and this month
+    pub fn credit_usage(&self) -> Vec<CmcKeyUsage> {
+        self.keys.usage()
+    }
     pub fn coin_symbol_to_chain(&self, coin_symbol: &str) -> Result<Chain> {
         match coin_symbol {
             "ETH" => Ok(Chain::Mainnet),
@@ -390,8 +766,7 @@ impl CoinMarketCap {
                     warn!("price not found for {}", r.symbol);
                     continue;
                 };
-                self.persistent_price_cache
-                    .insert(r.symbol.clone(), price.price);
+                self.cache_listing_price(r.symbol.clone(), price.price);
             }
             if len < limit {
                 break;
@@ -407,7 +782,7 @@ impl CoinMarketCap {
 
         if let Result::Ok(eth_usd) = eth_usd {
             info!("Successfully fetched ETH price: {eth_usd} USD");
-            self.persistent_price_cache.insert(eth, eth_usd);
+            self.cache_listing_price(eth, eth_usd);
         } else {
             warn!("Failed to get USD price for ETH")
         }
@@ -416,33 +791,100 @@ impl CoinMarketCap {
     }
 }
 
+fn split_assets(assets: &[AssetId]) -> (Vec<u64>, Vec<String>) {
+    let mut ids = vec![];
+    let mut symbols = vec![];
+    for asset in assets {
+        match asset {
+            AssetId::Cmc(id) => ids.push(*id),
+            AssetId::Symbol(symbol) => symbols.push(symbol.clone()),
+            // named for other sources, left out
+            AssetId::CoinGecko(_) | AssetId::Contract(..) => {}
+        }
+    }
+    (ids, symbols)
+}
+
+/// Keys prices fetched by id and by symbol by the asset they were asked for
+fn by_asset<T>(by_id: HashMap<u64, T>, by_symbol: HashMap<String, T>) -> HashMap<AssetId, T> {
+    by_id
+        .into_iter()
+        .map(|(id, value)| (AssetId::Cmc(id), value))
+        .chain(
+            by_symbol
+                .into_iter()
+                .map(|(symbol, value)| (AssetId::Symbol(symbol), value)),
+        )
+        .collect()
+}
+
+impl CoinMarketCap {
+    async fn get_usd_price_days_ago_by_asset(
+        &self,
+        ids: &[u64],
+        symbols: &[String],
+        days: u32,
+    ) -> Result<HashMap<AssetId, f64>> {
+        let mut by_id = HashMap::new();
+        let mut by_symbol = HashMap::new();
+        if !ids.is_empty() {
+            by_id = self.get_usd_price_days_ago_by_id(ids, days, true).await?;
+        }
+        if !symbols.is_empty() {
+            by_symbol = self.get_usd_price_days_ago(symbols, days, true).await?;
+        }
+        Ok(by_asset(by_id, by_symbol))
+    }
+}
+
 #[async_trait]
 impl AssetInfoClient for CoinMarketCap {
-    async fn get_usd_price_latest(&self, symbols: &[String]) -> Result<HashMap<String, f64>> {
-        self.get_usd_prices_by_symbol(symbols).await
+    async fn get_usd_price_latest(&self, assets: &[AssetId]) -> Result<HashMap<AssetId, f64>> {
+        let (ids, symbols) = split_assets(assets);
+        let mut by_id = HashMap::new();
+        let mut by_symbol = HashMap::new();
+        if !ids.is_empty() {
+            // an id names exactly one listing, 0 means it has no quote
+            by_id = self.get_usd_prices_by_id_with_options(&ids, true).await?;
+            by_id.retain(|_, price| *price > 0.0);
+        }
+        if !symbols.is_empty() {
+            // an unknown or inactive symbol leaves out just that symbol
+            by_symbol = self
+                .get_usd_prices_by_symbol_with_options(&symbols, true)
+                .await?;
+            by_symbol.retain(|_, price| *price > 0.0);
+        }
+        Ok(by_asset(by_id, by_symbol))
     }
 
     async fn get_usd_price_period(
         &self,
-        symbols: &[String],
-    ) -> Result<HashMap<String, AssetPriceByPeriod>> {
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AssetPriceByPeriod>> {
+        let (ids, symbols) = split_assets(assets);
         let mut result = HashMap::new();
-        let prices = self.get_usd_prices_by_symbol(symbols).await?;
-        let prices_1d = self.get_usd_price_days_ago(symbols, 1, true).await?;
-        let prices_7d = self.get_usd_price_days_ago(symbols, 7, true).await?;
-        let prices_30d = self.get_usd_price_days_ago(symbols, 30, true).await?;
-        for symbol in symbols {
-            let price_latest = prices
-                .get(symbol)
-                .cloned()
-                .context("could not get price of token that was just fetched")?;
-            let price_1d = prices_1d.get(symbol).cloned();
-            let price_7d = prices_7d.get(symbol).cloned();
-            let price_30d = prices_30d.get(symbol).cloned();
+        let prices = self.get_usd_price_latest(assets).await?;
+        let prices_1d = self
+            .get_usd_price_days_ago_by_asset(&ids, &symbols, 1)
+            .await?;
+        let prices_7d = self
+            .get_usd_price_days_ago_by_asset(&ids, &symbols, 7)
+            .await?;
+        let prices_30d = self
+            .get_usd_price_days_ago_by_asset(&ids, &symbols, 30)
+            .await?;
+        for asset in assets {
+            let Some(price_latest) = prices.get(asset).cloned() else {
+                continue;
+            };
+            let price_1d = prices_1d.get(asset).cloned().filter(|price| *price > 0.0);
+            let price_7d = prices_7d.get(asset).cloned().filter(|price| *price > 0.0);
+            let price_30d = prices_30d.get(asset).cloned().filter(|price| *price > 0.0);
             result.insert(
-                symbol.clone(),
+                asset.clone(),
                 AssetPriceByPeriod {
-                    symbol: symbol.clone(),
+                    asset: asset.clone(),
                     price_latest,
                     price_1d,
                     price_7d,
@@ -485,4 +927,4 @@ pub async fn prefetch_prices(cmc_client: Arc<CoinMarketCap>, token_names: Vec<St
     {
         error!("load_token_prices error: {:?}", e);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,220 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use eyre::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::*;
+
+/// Appends the log takes at least before it is compacted again, so a small
+/// log isn't rewritten on every few writes
+const MIN_APPENDS_BEFORE_COMPACTION: usize = 1000;
+
+/// One line of the price log, `at` is when the price was fetched in unix
+/// seconds. `date` is the day asked of the historical endpoint, `None` for
+/// a latest price
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CachedPrice {
+    Symbol {
+        date: Option<NaiveDate>,
+        symbol: String,
+        price: f64,
+        at: i64,
+    },
+    Id {
+        date: Option<NaiveDate>,
+        id: u64,
+        price: f64,
+        at: i64,
+    },
+    /// From the latest listing, not tied to a day
+    Listing { symbol: String, price: f64, at: i64 },
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum EntryKey {
+    Symbol(Option<NaiveDate>, String),
+    Id(Option<NaiveDate>, u64),
+    Listing(String),
+}
+
+impl CachedPrice {
+    fn key(&self) -> EntryKey {
+        match self {
+            CachedPrice::Symbol { date, symbol, .. } => EntryKey::Symbol(*date, symbol.clone()),
+            CachedPrice::Id { date, id, .. } => EntryKey::Id(*date, *id),
+            CachedPrice::Listing { symbol, .. } => EntryKey::Listing(symbol.clone()),
+        }
+    }
+
+    pub fn at(&self) -> i64 {
+        match self {
+            CachedPrice::Symbol { at, .. }
+            | CachedPrice::Id { at, .. }
+            | CachedPrice::Listing { at, .. } => *at,
+        }
+    }
+
+    /// Whether a price of `date` fetched at `at` never changes, which holds
+    /// for a historical day that was over by then. Latest prices and days
+    /// still open are only good for the TTL
+    pub fn is_final(date: Option<NaiveDate>, at: i64) -> bool {
+        let fetched_on = DateTime::from_timestamp(at, 0).map(|at| at.date_naive());
+        matches!((date, fetched_on), (Some(date), Some(fetched_on)) if date < fetched_on)
+    }
+
+    pub fn is_historical(&self) -> bool {
+        match self {
+            CachedPrice::Symbol { date, at, .. } | CachedPrice::Id { date, at, .. } => {
+                Self::is_final(*date, *at)
+            }
+            CachedPrice::Listing { .. } => false,
+        }
+    }
+}
+
+/// Append log of the CMC price caches, so a restart doesn't spend credits
+/// fetching the same prices again.
+///
+/// Loading keeps the first entry of a past day, as history is immutable,
+/// and the last entry of a latest price if it's within the TTL. The log is
+/// rewritten with just those entries on open, and again whenever the lines
+/// appended since outnumber them, so latest prices refreshed every few
+/// minutes don't grow it without bound.
+#[derive(Debug)]
+pub struct CmcPriceStore {
+    path: PathBuf,
+    file: Mutex<LogFile>,
+    latest_ttl: Duration,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    /// Lines appended since the log was last compacted
+    appended: usize,
+    /// Entries the log was last compacted to
+    compacted: usize,
+}
+
+impl CmcPriceStore {
+    /// Opens the log at `path`, creating it if missing, along with the
+    /// entries still valid in load order
+    pub fn open(path: &Path, latest_ttl: Duration) -> Result<(Self, Vec<CachedPrice>)> {
+        let (file, entries) = compact_log(path, latest_ttl)?;
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                file: Mutex::new(file),
+                latest_ttl,
+            },
+            entries,
+        ))
+    }
+
+    pub fn latest_ttl(&self) -> Duration {
+        self.latest_ttl
+    }
+
+    fn write(&self, entry: &CachedPrice) -> Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file.file, "{}", line)?;
+        file.appended += 1;
+        if file.appended > file.compacted.max(MIN_APPENDS_BEFORE_COMPACTION) {
+            *file = compact_log(&self.path, self.latest_ttl)?.0;
+        }
+        Ok(())
+    }
+
+    /// Appends an entry. A failed write is only logged, the in-memory cache
+    /// works without the log
+    pub fn record(&self, entry: &CachedPrice) {
+        if let Err(e) = self.write(entry) {
+            warn!(
+                "Failed to append to price log {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Rewrites the log at `path` with its entries still valid and opens it for
+/// appending, creating it if missing
+fn compact_log(path: &Path, latest_ttl: Duration) -> Result<(LogFile, Vec<CachedPrice>)> {
+    let entries = match File::open(path) {
+        Result::Ok(file) => read_entries(file, latest_ttl),
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    compact(path, &entries)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let file = LogFile {
+        file,
+        appended: 0,
+        compacted: entries.len(),
+    };
+    Ok((file, entries))
+}
+
+fn read_entries(file: File, latest_ttl: Duration) -> Vec<CachedPrice> {
+    let oldest = Utc::now().timestamp() - latest_ttl.as_secs() as i64;
+    let mut entries: Vec<CachedPrice> = vec![];
+    let mut index: HashMap<EntryKey, usize> = HashMap::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let Result::Ok(line) = line else {
+            skipped += 1;
+            continue;
+        };
+        // a write cut short by a crash leaves a partial last line
+        let Result::Ok(entry) = serde_json::from_str::<CachedPrice>(&line) else {
+            skipped += 1;
+            continue;
+        };
+        let historical = entry.is_historical();
+        if !historical && entry.at() < oldest {
+            continue;
+        }
+        match index.get(&entry.key()) {
+            Some(i) if entries[*i].is_historical() => {}
+            Some(i) => entries[*i] = entry,
+            None => {
+                index.insert(entry.key(), entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!("Skipped {} unreadable lines of the price log", skipped);
+    }
+    entries
+}
+
+/// Replaces the log with `entries`, through a temporary file so a crash
+/// leaves either log whole
+fn compact(path: &Path, entries: &[CachedPrice]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(
+        File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?,
+    );
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
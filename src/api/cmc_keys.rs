@@ -0,0 +1,454 @@
+use chrono::{Datelike, NaiveDate, Utc};
+use ethers::utils::{hex, keccak256};
+use eyre::*;
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// CMC counts the minute limit over a rolling window
+const RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+/// Interval of the historical endpoint when none is given
+const DEFAULT_HISTORICAL_INTERVAL_S: i64 = 5 * 60;
+/// Quotes returned by the historical endpoint when neither a count nor a
+/// start is given
+const DEFAULT_HISTORICAL_COUNT: u64 = 10;
+
+/// Credits a key may spend, per UTC day and calendar month. `None` leaves
+/// it to the plan's own limits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CmcCreditBudget {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+#[derive(Debug)]
+struct KeyState {
+    /// Set when CMC reported a limit, until it resets
+    blocked_until: Option<Instant>,
+    /// Rejected by CMC as invalid or disabled, never used again
+    revoked: bool,
+    day: NaiveDate,
+    used_today: u64,
+    month: (i32, u32),
+    used_month: u64,
+}
+
+impl KeyState {
+    fn roll_over(&mut self, today: NaiveDate) {
+        if self.day != today {
+            self.day = today;
+            self.used_today = 0;
+        }
+        if self.month != (today.year(), today.month()) {
+            self.month = (today.year(), today.month());
+            self.used_month = 0;
+        }
+    }
+}
+
+/// Counters of a key as saved to the usage file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedUsage {
+    day: NaiveDate,
+    used_today: u64,
+    month: (i32, u32),
+    used_month: u64,
+}
+
+/// The usage file and the generation of the counters last written to it
+#[derive(Debug)]
+struct UsageFile {
+    path: PathBuf,
+    written: u64,
+}
+
+#[derive(Debug)]
+struct CmcKey {
+    header: HeaderValue,
+    state: Mutex<KeyState>,
+}
+
+/// Credit usage of one key, identified by its last characters
+#[derive(Debug, Clone)]
+pub struct CmcKeyUsage {
+    pub key_suffix: String,
+    pub used_today: u64,
+    pub used_month: u64,
+    pub available: bool,
+}
+
+/// A key picked for one call, handed back with the outcome
+#[derive(Debug, Clone)]
+pub struct CmcKeyLease {
+    index: usize,
+    pub header: HeaderValue,
+}
+
+/// What to do after CMC answered a call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallOutcome {
+    Done,
+    /// The key hit a limit or was rejected, try the call with another
+    Retry,
+}
+
+/// API keys used in turn, each with its own credit accounting.
+///
+/// A key that hits CMC's minute rate limit is rested for a minute, one over
+/// its daily or monthly limit until the limit resets. Calls are deferred
+/// while every key is resting and refused once every key is out of budget.
+#[derive(Debug)]
+pub struct CmcKeyPool {
+    keys: Vec<CmcKey>,
+    next: AtomicUsize,
+    budget: CmcCreditBudget,
+    /// Where the counters are saved after every call, so a restart doesn't
+    /// reset the budget
+    usage_file: Option<Arc<Mutex<UsageFile>>>,
+    /// Bumped on every save, a snapshot older than the one on disk is
+    /// dropped
+    usage_generation: AtomicU64,
+}
+
+impl CmcKeyPool {
+    pub fn new(keys: &[String], budget: CmcCreditBudget) -> Result<Self> {
+        ensure!(!keys.is_empty(), "no CMC API key configured");
+        let today = Utc::now().date_naive();
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let mut header = HeaderValue::from_str(key.trim())?;
+                header.set_sensitive(true);
+                Ok(CmcKey {
+                    header,
+                    state: Mutex::new(KeyState {
+                        blocked_until: None,
+                        revoked: false,
+                        day: today,
+                        used_today: 0,
+                        month: (today.year(), today.month()),
+                        used_month: 0,
+                    }),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            keys,
+            next: AtomicUsize::new(0),
+            budget,
+            usage_file: None,
+            usage_generation: AtomicU64::new(0),
+        })
+    }
+
+    /// Loads the counters saved at `path`, if any, and saves them there
+    /// from then on. Keys are saved by a hash, never in the clear
+    pub fn with_usage_file(mut self, path: &Path) -> Result<Self> {
+        let saved: HashMap<String, SavedUsage> = match std::fs::read_to_string(path) {
+            Result::Ok(data) => serde_json::from_str(&data)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let today = Utc::now().date_naive();
+        for key in &mut self.keys {
+            let Some(usage) = saved.get(&key_hash(&key.header)) else {
+                continue;
+            };
+            let state = key.state.get_mut().unwrap();
+            state.day = usage.day;
+            state.used_today = usage.used_today;
+            state.month = usage.month;
+            state.used_month = usage.used_month;
+            state.roll_over(today);
+        }
+        self.usage_file = Some(Arc::new(Mutex::new(UsageFile {
+            path: path.to_path_buf(),
+            written: 0,
+        })));
+        Ok(self)
+    }
+
+    /// Snapshots the counters and writes them on the blocking pool, so a
+    /// call never waits on the disk
+    fn save_usage(&self) {
+        let Some(file) = &self.usage_file else {
+            return;
+        };
+        // taken before the snapshot, so it holds every call counted so far
+        let generation = self.usage_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let saved: HashMap<String, SavedUsage> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let state = key.state.lock().unwrap();
+                let usage = SavedUsage {
+                    day: state.day,
+                    used_today: state.used_today,
+                    month: state.month,
+                    used_month: state.used_month,
+                };
+                (key_hash(&key.header), usage)
+            })
+            .collect();
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            if file.written > generation {
+                return;
+            }
+            let tmp = file.path.with_extension("tmp");
+            let result = serde_json::to_vec(&saved)
+                .map_err(eyre::Error::from)
+                .and_then(|data| {
+                    std::fs::write(&tmp, data)?;
+                    std::fs::rename(&tmp, &file.path)?;
+                    Ok(())
+                });
+            match result {
+                Result::Ok(()) => file.written = generation,
+                Err(e) => warn!(
+                    "Failed to save CMC key usage to {}: {:?}",
+                    file.path.display(),
+                    e
+                ),
+            }
+        });
+    }
+
+    fn within_budget(&self, state: &KeyState, credits: u64) -> bool {
+        self.budget
+            .daily
+            .is_none_or(|daily| state.used_today + credits <= daily)
+            && self
+                .budget
+                .monthly
+                .is_none_or(|monthly| state.used_month + credits <= monthly)
+    }
+
+    /// Picks the next key with `credits` left in its budget, waiting for a
+    /// rate limited key to rest if that's all there is
+    pub async fn acquire(&self, credits: u64) -> Result<CmcKeyLease> {
+        loop {
+            let today = Utc::now().date_naive();
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let mut earliest: Option<Instant> = None;
+            for offset in 0..self.keys.len() {
+                let index = (start + offset) % self.keys.len();
+                let key = &self.keys[index];
+                let mut state = key.state.lock().unwrap();
+                state.roll_over(today);
+                if state.revoked || !self.within_budget(&state, credits) {
+                    continue;
+                }
+                match state.blocked_until {
+                    Some(until) if until > Instant::now() => {
+                        earliest = Some(earliest.map_or(until, |earliest| earliest.min(until)));
+                    }
+                    _ => {
+                        state.blocked_until = None;
+                        return Ok(CmcKeyLease {
+                            index,
+                            header: key.header.clone(),
+                        });
+                    }
+                }
+            }
+            let Some(until) = earliest else {
+                bail!(
+                    "CMC call of {} credits refused, every key is out of budget or revoked",
+                    credits
+                );
+            };
+            if until > Instant::now() + RATE_LIMIT_COOLDOWN {
+                bail!("CMC call refused, every key is over its plan's limits");
+            }
+            debug!("Every CMC key is rate limited, deferring call");
+            tokio::time::sleep_until(until.into()).await;
+        }
+    }
+
+    /// Records the credits spent by the call and rests or revokes the key
+    /// on the limit errors CMC reports
+    pub fn report(&self, lease: &CmcKeyLease, error_code: u64, credits: u64) -> CallOutcome {
+        let outcome = self.account(lease, error_code, credits);
+        self.save_usage();
+        outcome
+    }
+
+    fn account(&self, lease: &CmcKeyLease, error_code: u64, credits: u64) -> CallOutcome {
+        let key = &self.keys[lease.index];
+        let mut state = key.state.lock().unwrap();
+        state.roll_over(Utc::now().date_naive());
+        state.used_today += credits;
+        state.used_month += credits;
+        let suffix = key_suffix(&key.header);
+        match error_code {
+            // invalid, missing, unpaid, expired or disabled key
+            1001..=1005 | 1007 => {
+                warn!(
+                    "CMC key ...{} was rejected ({}), not using it again",
+                    suffix, error_code
+                );
+                state.revoked = true;
+                CallOutcome::Retry
+            }
+            // minute and IP rate limits
+            1008 | 1011 => {
+                state.blocked_until = Some(Instant::now() + RATE_LIMIT_COOLDOWN);
+                CallOutcome::Retry
+            }
+            1009 => {
+                warn!("CMC key ...{} is over its daily limit", suffix);
+                state.blocked_until = Some(Instant::now() + until_next_day());
+                CallOutcome::Retry
+            }
+            1010 => {
+                warn!("CMC key ...{} is over its monthly limit", suffix);
+                state.blocked_until = Some(Instant::now() + until_next_month());
+                CallOutcome::Retry
+            }
+            _ => CallOutcome::Done,
+        }
+    }
+
+    pub fn usage(&self) -> Vec<CmcKeyUsage> {
+        let today = Utc::now().date_naive();
+        self.keys
+            .iter()
+            .map(|key| {
+                let mut state = key.state.lock().unwrap();
+                state.roll_over(today);
+                CmcKeyUsage {
+                    key_suffix: key_suffix(&key.header),
+                    used_today: state.used_today,
+                    used_month: state.used_month,
+                    available: !state.revoked
+                        && state
+                            .blocked_until
+                            .is_none_or(|until| until <= Instant::now())
+                        && self.within_budget(&state, 1),
+                }
+            })
+            .collect()
+    }
+}
+
+fn key_hash(header: &HeaderValue) -> String {
+    hex::encode(&keccak256(header.as_bytes())[..8])
+}
+
+fn key_suffix(header: &HeaderValue) -> String {
+    let key = header.to_str().unwrap_or_default();
+    key[key.len().saturating_sub(4)..].to_string()
+}
+
+fn until_next_day() -> Duration {
+    let now = Utc::now();
+    let tomorrow = now.date_naive().succ_opt().unwrap_or(now.date_naive());
+    let midnight = tomorrow.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+fn until_next_month() -> Duration {
+    let now = Utc::now();
+    let (year, month) = match now.month() {
+        12 => (now.year() + 1, 1),
+        month => (now.year(), month + 1),
+    };
+    let Some(first) = NaiveDate::from_ymd_opt(year, month, 1) else {
+        return until_next_day();
+    };
+    let midnight = first.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+fn param<'a>(url: &'a Url, key: &str) -> Option<std::borrow::Cow<'a, str>> {
+    url.query_pairs()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+fn list_len(url: &Url, key: &str) -> u64 {
+    param(url, key).map_or(0, |value| value.split(',').count() as u64)
+}
+
+fn interval_seconds(interval: &str) -> i64 {
+    let named = match interval {
+        "hourly" => Some(60 * 60),
+        "daily" => Some(24 * 60 * 60),
+        "weekly" => Some(7 * 24 * 60 * 60),
+        "monthly" => Some(30 * 24 * 60 * 60),
+        "yearly" => Some(365 * 24 * 60 * 60),
+        _ => None,
+    };
+    if let Some(seconds) = named {
+        return seconds;
+    }
+    let (count, unit) = interval.split_at(interval.len().saturating_sub(1));
+    let unit_s = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return DEFAULT_HISTORICAL_INTERVAL_S,
+    };
+    count
+        .parse::<i64>()
+        .map_or(DEFAULT_HISTORICAL_INTERVAL_S, |count| {
+            (count * unit_s).max(60)
+        })
+}
+
+fn historical_points(url: &Url) -> u64 {
+    if let Some(count) = param(url, "count").and_then(|count| count.parse().ok()) {
+        return count;
+    }
+    let timestamp = |key: &str| {
+        param(url, key)
+            .and_then(|value| chrono::DateTime::parse_from_rfc3339(&value).ok())
+            .map(|at| at.timestamp())
+    };
+    let Some(start) = timestamp("time_start") else {
+        return DEFAULT_HISTORICAL_COUNT;
+    };
+    let end = timestamp("time_end").unwrap_or_else(|| Utc::now().timestamp());
+    let interval = param(url, "interval").map_or(DEFAULT_HISTORICAL_INTERVAL_S, |interval| {
+        interval_seconds(&interval)
+    });
+    ((end - start).max(0) / interval + 1) as u64
+}
+
+/// Credits CMC will charge for the call, following its pricing: a credit
+/// per 100 assets or 100 historical quotes, rounded up, times the number of
+/// convert options. The map endpoint costs one credit per call and the
+/// listing one per 200 assets
+pub fn estimate_credits(url: &Url) -> u64 {
+    let per = |count: u64, size: u64| count.div_ceil(size).max(1);
+    let converts = list_len(url, "convert")
+        .max(list_len(url, "convert_id"))
+        .max(1);
+    let assets = list_len(url, "id")
+        .max(list_len(url, "symbol"))
+        .max(list_len(url, "slug"))
+        .max(1);
+    let path = url.path();
+    if path.ends_with("/quotes/historical") {
+        per(assets * historical_points(url), 100) * converts
+    } else if path.ends_with("/quotes/latest") || path.ends_with("/info") {
+        per(assets, 100) * converts
+    } else if path.ends_with("/listings/latest") {
+        let limit = param(url, "limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(100);
+        per(limit, 200) * converts
+    } else {
+        1
+    }
+}
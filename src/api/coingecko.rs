@@ -0,0 +1,448 @@
+use super::assets::{AssetId, AssetInfoClient, AssetPriceByPeriod};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use ethers::types::{Chain, H160};
+use eyre::*;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Instant;
+use tracing::*;
+
+pub const PUBLIC_BASE_URL: &str = "https://api.coingecko.com/api/v3";
+pub const PRO_BASE_URL: &str = "https://pro-api.coingecko.com/api/v3";
+const SIMPLE_PRICE_URL: &str = "/simple/price";
+const TOKEN_PRICE_URL: &str = "/simple/token_price";
+const COIN_MARKETS_URL: &str = "/coins/markets";
+/// Contract addresses accepted per token price request on the public API
+const CONTRACT_BATCH_SIZE: usize = 30;
+/// Ids per simple price request, far below the URL length limit
+const ID_BATCH_SIZE: usize = 250;
+/// Contract charts can't be batched, the prices days ago they are read for
+/// hardly move within this
+const CHART_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Contract charts fetched per period request, the rest wait for later ones
+/// rather than run into the rate limit
+const MAX_CHART_FETCHES_PER_CALL: usize = 10;
+
+/// The asset platform CoinGecko lists the chain's tokens under
+pub fn platform_id(chain: Chain) -> Option<&'static str> {
+    match chain {
+        Chain::Mainnet => Some("ethereum"),
+        Chain::BinanceSmartChain => Some("binance-smart-chain"),
+        Chain::Polygon => Some("polygon-pos"),
+        Chain::Arbitrum => Some("arbitrum-one"),
+        Chain::Optimism => Some("optimistic-ethereum"),
+        Chain::Base => Some("base"),
+        Chain::Avalanche => Some("avalanche"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MarketChart {
+    /// `[unix ms, price]` pairs
+    prices: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CoinMarket {
+    id: String,
+    current_price: Option<f64>,
+    price_change_percentage_24h_in_currency: Option<f64>,
+    price_change_percentage_7d_in_currency: Option<f64>,
+    price_change_percentage_30d_in_currency: Option<f64>,
+}
+
+type Chart = Vec<(DateTime<Utc>, f64)>;
+
+/// What CoinGecko knows an asset as
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum GeckoAsset {
+    Coin(String),
+    Contract(Chain, H160),
+}
+
+/// Prices from the CoinGecko API, simple prices for the latest and market
+/// chart ranges for history.
+///
+/// CoinGecko has its own ids, so CMC ids are only priced once aliased to a
+/// CoinGecko id or a contract with `alias_cmc_id`. The chains' native coins
+/// are aliased from the start, tokens once `TokenPriceSync` matches them to
+/// a listing. Symbols are left out, they are ambiguous and CoinGecko
+/// doesn't rank its listings.
+#[derive(Debug)]
+pub struct CoinGecko {
+    client: Client,
+    base_url: String,
+    cmc_aliases: RwLock<HashMap<u64, AssetId>>,
+    /// Last 30 days of each contract, and when they were fetched
+    charts: RwLock<HashMap<(Chain, H160), (Instant, Chart)>>,
+}
+
+impl CoinGecko {
+    /// `base_url` is `PUBLIC_BASE_URL`, `PRO_BASE_URL` or a stub. Keys for
+    /// the pro API are sent as such, others as demo keys
+    pub fn new(base_url: &str, api_key: Option<&str>) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Accept", HeaderValue::from_static("application/json"));
+        if let Some(api_key) = api_key {
+            let header = if base_url.starts_with(PRO_BASE_URL) {
+                "x-cg-pro-api-key"
+            } else {
+                "x-cg-demo-api-key"
+            };
+            let mut value = HeaderValue::from_str(api_key)?;
+            value.set_sensitive(true);
+            headers.insert(header, value);
+        }
+        let aliases = [(1027, "ethereum"), (1839, "binancecoin"), (1, "bitcoin")]
+            .into_iter()
+            .map(|(cmc_id, id)| (cmc_id, AssetId::CoinGecko(id.to_string())))
+            .collect();
+        Ok(Self {
+            client: Client::builder().default_headers(headers).build()?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cmc_aliases: RwLock::new(aliases),
+            charts: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Prices the CMC listing as `asset`, a CoinGecko id or a contract. An
+    /// existing alias is kept
+    pub fn alias_cmc_id(&self, cmc_id: u64, asset: AssetId) {
+        self.cmc_aliases
+            .write()
+            .unwrap()
+            .entry(cmc_id)
+            .or_insert(asset);
+    }
+
+    fn resolve(&self, asset: &AssetId) -> Option<GeckoAsset> {
+        match asset {
+            AssetId::CoinGecko(id) => Some(GeckoAsset::Coin(id.clone())),
+            AssetId::Contract(chain, address) => Some(GeckoAsset::Contract(*chain, *address)),
+            AssetId::Cmc(id) => {
+                let alias = self.cmc_aliases.read().unwrap().get(id).cloned()?;
+                match alias {
+                    AssetId::CoinGecko(_) | AssetId::Contract(..) => self.resolve(&alias),
+                    AssetId::Cmc(_) | AssetId::Symbol(_) => None,
+                }
+            }
+            AssetId::Symbol(_) => None,
+        }
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        Ok(Url::parse(&format!("{}{}", self.base_url, path))?)
+    }
+
+    async fn send_and_parse_response<T: DeserializeOwned>(&self, url: &Url) -> Result<T> {
+        trace!("Request: {}", url);
+        let response = self.client.get(url.clone()).send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        trace!("Response: {}", text);
+        if !status.is_success() {
+            let json: Value = serde_json::from_str(&text).unwrap_or_default();
+            let message = json["status"]["error_message"]
+                .as_str()
+                .or(json["error"].as_str())
+                .unwrap_or(&text);
+            bail!("CoinGecko returned {}: {}", status, message);
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Latest USD prices by CoinGecko id
+    pub async fn get_usd_prices_by_id(&self, ids: &[String]) -> Result<HashMap<String, f64>> {
+        let begin = Instant::now();
+        let mut prices = HashMap::new();
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let mut url = self.url(SIMPLE_PRICE_URL)?;
+            url.query_pairs_mut()
+                .append_pair("ids", &chunk.join(","))
+                .append_pair("vs_currencies", "usd");
+            let payload: HashMap<String, HashMap<String, f64>> =
+                self.send_and_parse_response(&url).await?;
+            prices.extend(
+                payload
+                    .into_iter()
+                    .filter_map(|(id, quote)| Some((id, *quote.get("usd")?))),
+            );
+        }
+        trace!(
+            "get_usd_prices_by_id duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(prices)
+    }
+
+    /// Latest USD prices of token contracts on the chain
+    pub async fn get_usd_prices_by_contract(
+        &self,
+        chain: Chain,
+        addresses: &[H160],
+    ) -> Result<HashMap<H160, f64>> {
+        let begin = Instant::now();
+        let platform = platform_id(chain).with_context(|| format!("no platform for {}", chain))?;
+        let mut prices = HashMap::new();
+        for chunk in addresses.chunks(CONTRACT_BATCH_SIZE) {
+            let mut url = self.url(&format!("{}/{}", TOKEN_PRICE_URL, platform))?;
+            let addresses: Vec<String> = chunk
+                .iter()
+                .map(|address| format!("{:?}", address))
+                .collect();
+            url.query_pairs_mut()
+                .append_pair("contract_addresses", &addresses.join(","))
+                .append_pair("vs_currencies", "usd");
+            let payload: HashMap<String, HashMap<String, f64>> =
+                self.send_and_parse_response(&url).await?;
+            for (address, quote) in payload {
+                let (Result::Ok(address), Some(price)) = (address.parse(), quote.get("usd")) else {
+                    continue;
+                };
+                prices.insert(address, *price);
+            }
+        }
+        trace!(
+            "get_usd_prices_by_contract duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(prices)
+    }
+
+    /// Latest prices and their changes over the last day, week and month by
+    /// CoinGecko id
+    async fn get_coin_markets(&self, ids: &[String]) -> Result<Vec<CoinMarket>> {
+        let begin = Instant::now();
+        let mut markets = vec![];
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let mut url = self.url(COIN_MARKETS_URL)?;
+            url.query_pairs_mut()
+                .append_pair("vs_currency", "usd")
+                .append_pair("ids", &chunk.join(","))
+                .append_pair("per_page", &ID_BATCH_SIZE.to_string())
+                .append_pair("price_change_percentage", "24h,7d,30d");
+            let page: Vec<CoinMarket> = self.send_and_parse_response(&url).await?;
+            markets.extend(page);
+        }
+        trace!("get_coin_markets duration: {:?}", Instant::now() - begin);
+        Ok(markets)
+    }
+
+    /// The contract's last 30 days, reused while fresh. Once the call
+    /// fetched its share of charts a stale one is used, if any
+    async fn contract_chart(&self, chain: Chain, address: H160, fetches: &mut usize) -> Chart {
+        let cached = self.charts.read().unwrap().get(&(chain, address)).cloned();
+        match cached {
+            Some((at, chart)) if at.elapsed() < CHART_TTL => return chart,
+            Some((_, chart)) if *fetches >= MAX_CHART_FETCHES_PER_CALL => return chart,
+            None if *fetches >= MAX_CHART_FETCHES_PER_CALL => return vec![],
+            _ => {}
+        }
+        *fetches += 1;
+        let now = Utc::now();
+        let asset = AssetId::Contract(chain, address);
+        match self
+            .get_usd_market_chart_range(&asset, now - Duration::days(30) - Duration::hours(2), now)
+            .await
+        {
+            Result::Ok(chart) => {
+                self.charts
+                    .write()
+                    .unwrap()
+                    .insert((chain, address), (Instant::now(), chart.clone()));
+                chart
+            }
+            Err(e) => {
+                warn!("Failed to get CoinGecko chart of {}: {:?}", asset, e);
+                vec![]
+            }
+        }
+    }
+
+    /// USD prices from `from` to `to`, oldest first. CoinGecko picks the
+    /// resolution from the range: 5 minutes up to a day, hourly up to 90
+    /// days, daily beyond
+    pub async fn get_usd_market_chart_range(
+        &self,
+        asset: &AssetId,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, f64)>> {
+        let begin = Instant::now();
+        let path = match self.resolve(asset) {
+            Some(GeckoAsset::Coin(id)) => format!("/coins/{}/market_chart/range", id),
+            Some(GeckoAsset::Contract(chain, address)) => {
+                let platform =
+                    platform_id(chain).with_context(|| format!("no platform for {}", chain))?;
+                format!(
+                    "/coins/{}/contract/{:?}/market_chart/range",
+                    platform, address
+                )
+            }
+            None => bail!("{} has no CoinGecko listing", asset),
+        };
+        let mut url = self.url(&path)?;
+        url.query_pairs_mut()
+            .append_pair("vs_currency", "usd")
+            .append_pair("from", &from.timestamp().to_string())
+            .append_pair("to", &to.timestamp().to_string());
+        let chart: MarketChart = self.send_and_parse_response(&url).await?;
+        let mut prices: Vec<(DateTime<Utc>, f64)> = chart
+            .prices
+            .into_iter()
+            .filter_map(|(ms, price)| Some((DateTime::from_timestamp_millis(ms as i64)?, price)))
+            .collect();
+        prices.sort_by_key(|(at, _)| *at);
+        trace!(
+            "get_usd_market_chart_range duration: {:?}",
+            Instant::now() - begin
+        );
+        Ok(prices)
+    }
+}
+
+/// The price closest to `at` in a chart sorted oldest first, if one is
+/// within `tolerance`
+fn price_near(
+    chart: &[(DateTime<Utc>, f64)],
+    at: DateTime<Utc>,
+    tolerance: Duration,
+) -> Option<f64> {
+    let index = chart.partition_point(|(t, _)| *t < at);
+    [index.checked_sub(1), Some(index)]
+        .into_iter()
+        .flatten()
+        .filter_map(|i| chart.get(i))
+        .map(|(t, price)| ((*t - at).abs(), *price))
+        .filter(|(distance, _)| *distance <= tolerance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, price)| price)
+}
+
+#[async_trait]
+impl AssetInfoClient for CoinGecko {
+    async fn get_usd_price_latest(&self, assets: &[AssetId]) -> Result<HashMap<AssetId, f64>> {
+        let mut coins: HashMap<String, Vec<&AssetId>> = HashMap::new();
+        let mut contracts: HashMap<Chain, HashMap<H160, Vec<&AssetId>>> = HashMap::new();
+        for asset in assets {
+            match self.resolve(asset) {
+                Some(GeckoAsset::Coin(id)) => coins.entry(id).or_default().push(asset),
+                Some(GeckoAsset::Contract(chain, address)) => contracts
+                    .entry(chain)
+                    .or_default()
+                    .entry(address)
+                    .or_default()
+                    .push(asset),
+                None => {}
+            }
+        }
+
+        let mut result = HashMap::new();
+        if !coins.is_empty() {
+            let ids: Vec<String> = coins.keys().cloned().collect();
+            for (id, price) in self.get_usd_prices_by_id(&ids).await? {
+                for asset in coins.get(&id).into_iter().flatten() {
+                    result.insert((*asset).clone(), price);
+                }
+            }
+        }
+        for (chain, by_address) in contracts {
+            if platform_id(chain).is_none() {
+                continue;
+            }
+            let addresses: Vec<H160> = by_address.keys().copied().collect();
+            for (address, price) in self.get_usd_prices_by_contract(chain, &addresses).await? {
+                for asset in by_address.get(&address).into_iter().flatten() {
+                    result.insert((*asset).clone(), price);
+                }
+            }
+        }
+        result.retain(|_, price| *price > 0.0);
+        Ok(result)
+    }
+
+    /// Coins from the markets endpoint in batches, contracts from an hourly
+    /// chart each, see `contract_chart`
+    async fn get_usd_price_period(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AssetPriceByPeriod>> {
+        let mut coins: HashMap<String, Vec<&AssetId>> = HashMap::new();
+        let mut contracts = vec![];
+        for asset in assets {
+            match self.resolve(asset) {
+                Some(GeckoAsset::Coin(id)) => coins.entry(id).or_default().push(asset),
+                Some(GeckoAsset::Contract(chain, address)) => {
+                    contracts.push((asset.clone(), chain, address))
+                }
+                None => {}
+            }
+        }
+
+        let mut result = HashMap::new();
+        if !coins.is_empty() {
+            let ids: Vec<String> = coins.keys().cloned().collect();
+            for market in self.get_coin_markets(&ids).await? {
+                let Some(price_latest) = market.current_price.filter(|price| *price > 0.0) else {
+                    continue;
+                };
+                let before_change = |change_pct: Option<f64>| {
+                    change_pct
+                        .map(|change_pct| price_latest / (1.0 + change_pct / 100.0))
+                        .filter(|price| price.is_finite() && *price > 0.0)
+                };
+                for asset in coins.get(&market.id).into_iter().flatten() {
+                    result.insert(
+                        (*asset).clone(),
+                        AssetPriceByPeriod {
+                            asset: (*asset).clone(),
+                            price_latest,
+                            price_1d: before_change(market.price_change_percentage_24h_in_currency),
+                            price_7d: before_change(market.price_change_percentage_7d_in_currency),
+                            price_30d: before_change(
+                                market.price_change_percentage_30d_in_currency,
+                            ),
+                        },
+                    );
+                }
+            }
+        }
+
+        if contracts.is_empty() {
+            return Ok(result);
+        }
+        let contract_assets: Vec<AssetId> =
+            contracts.iter().map(|(asset, ..)| asset.clone()).collect();
+        let prices = self.get_usd_price_latest(&contract_assets).await?;
+        let now = Utc::now();
+        let mut fetches = 0;
+        for (asset, chain, address) in contracts {
+            let Some(price_latest) = prices.get(&asset).copied() else {
+                continue;
+            };
+            let chart = self.contract_chart(chain, address, &mut fetches).await;
+            let days_ago = |days: i64| {
+                price_near(&chart, now - Duration::days(days), Duration::hours(2))
+                    .filter(|price| *price > 0.0)
+            };
+            result.insert(
+                asset.clone(),
+                AssetPriceByPeriod {
+                    asset,
+                    price_latest,
+                    price_1d: days_ago(1),
+                    price_7d: days_ago(7),
+                    price_30d: days_ago(30),
+                },
+            );
+        }
+        Ok(result)
+    }
+}
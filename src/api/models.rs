@@ -19,6 +19,26 @@ pub struct CoinMarketCapTokenInfo {
     pub addresses: Vec<TokenAddress>,
 }
 
+/// Chain of a CMC platform by its name, `None` for chains we don't index
+pub fn platform_chain(name: &str) -> Option<Chain> {
+    match name {
+        "Ethereum" => Some(Chain::Mainnet),
+        "BNB Smart Chain (BEP20)" => Some(Chain::BinanceSmartChain),
+        "Polygon" => Some(Chain::Polygon),
+        "Arbitrum" => Some(Chain::Arbitrum),
+        "Optimism" => Some(Chain::Optimism),
+        "Base" => Some(Chain::Base),
+        _ => None,
+    }
+}
+
+fn token_address(platform_name: &str, address: &str) -> Option<TokenAddress> {
+    Some(TokenAddress {
+        address: address.trim().parse().ok()?,
+        chain: platform_chain(platform_name)?,
+    })
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MapCoinPlatform {
     pub id: u64,
@@ -40,6 +60,23 @@ pub struct MapCoinInfo {
     pub platform: Option<MapCoinPlatform>,
 }
 
+impl MapCoinInfo {
+    /// Only the primary platform's contract, the info endpoint lists all of them
+    pub fn token_info(&self) -> CoinMarketCapTokenInfo {
+        CoinMarketCapTokenInfo {
+            cmc_id: self.id as u64,
+            name: self.name.clone(),
+            symbol: self.symbol.clone(),
+            slug: self.slug.clone(),
+            addresses: self
+                .platform
+                .iter()
+                .filter_map(|platform| token_address(&platform.name, &platform.token_address))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NativeCoin {
     pub id: String,
@@ -84,6 +121,24 @@ pub struct CoinInfo {
     pub status: String,
 }
 
+impl CoinInfo {
+    pub fn token_info(&self) -> CoinMarketCapTokenInfo {
+        CoinMarketCapTokenInfo {
+            cmc_id: self.id as u64,
+            name: self.name.clone(),
+            symbol: self.symbol.clone(),
+            slug: self.slug.clone(),
+            addresses: self
+                .contract_address
+                .iter()
+                .filter_map(|contract| {
+                    token_address(&contract.platform.name, &contract.contract_address)
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QuoteToken {
     pub price: f64,
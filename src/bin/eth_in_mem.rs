@@ -1,13 +1,34 @@
 use clap::Parser;
-use ethers::{prelude::BlockId, providers::Middleware};
+use spice_backend::alerts::{WhaleAlertThresholds, WhaleAlerter};
+use spice_backend::api::cmc::{CoinMarketCap, DEFAULT_LATEST_PRICE_TTL};
+use spice_backend::api::cmc_keys::CmcCreditBudget;
 use spice_backend::api::*;
+use spice_backend::dex::{DexFactories, DexPriceOracle, DexQuoteTokens, DEFAULT_MIN_LIQUIDITY_USD};
+use spice_backend::events::EventBus;
+use spice_backend::ingest::Ingester;
+use spice_backend::server::method::{
+    add_table_endpoints, MethodGetContractMetadata, MethodGetGasOracle, MethodGetToken,
+    MethodGetTransactionLogs, MethodVerifyTokenBalance, MethodGetPortfolio, MethodGetPriceAt,
+    MethodGetDexPrice, MethodGetAssetPrices,
+};
+use spice_backend::server::{parse_api_key, serve, ApiServer};
 use spice_backend::tables::*;
-use std::sync::atomic::{AtomicU32, Ordering};
+use spice_backend::labels::import_labels_file;
+use spice_backend::search::SearchIndex;
+use spice_backend::token_prices::TokenPriceSync;
+use spice_backend::portfolio::NativeCoin;
+use spice_backend::api::aggregate::{AggregatePriceClient, AggregationConfig, PriceSource};
+use spice_backend::api::assets::{AssetId, AssetInfoClient};
+use spice_backend::api::coingecko::CoinGecko;
+use spice_backend::price_history::{PriceHistory, QuoteInterval};
+use spice_backend::valuation::UsdValuer;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use sysinfo::System;
 use tokio::sync::Mutex;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 use spice_backend::check_memory_usage;
 
@@ -24,6 +45,12 @@ static GLOBAL: Jemalloc = Jemalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+const MEMPOOL_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const SEARCH_INDEX_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const TOKEN_PRICE_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Blocks ingested before quotes for them were published are valued later
+const USD_BACKFILL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -35,6 +62,78 @@ struct Args {
 
     #[arg(short, long)]
     url: String,
+
+    /// Serve the API on this address while ingesting
+    #[arg(long)]
+    api_listen: Option<SocketAddr>,
+
+    /// `<key>=<role>`, may be repeated. Callers without a key are `public`
+    #[arg(long = "api-key", value_parser = parse_api_key)]
+    api_keys: Vec<(String, spice_backend::model::EnumRole)>,
+
+    /// CSV or JSON address label lists to import at startup, may be repeated
+    #[arg(long = "labels")]
+    label_files: Vec<PathBuf>,
+
+    /// Function signature files (selector and text signature per line, or a
+    /// JSON object) for decoding calldata, may be repeated
+    #[arg(long = "signatures")]
+    signature_files: Vec<PathBuf>,
+
+    /// Enables CMC coin metadata in search, token prices and whale alerts.
+    /// Several keys, repeated or comma separated, are used in turn
+    #[arg(long = "cmc-api-key", env = "CMC_API_KEYS", value_delimiter = ',')]
+    cmc_api_keys: Vec<String>,
+
+    /// Credits each CMC key may spend per UTC day
+    #[arg(long, env = "CMC_DAILY_CREDITS")]
+    cmc_daily_credits: Option<u64>,
+
+    /// Credits each CMC key may spend per calendar month
+    #[arg(long, env = "CMC_MONTHLY_CREDITS")]
+    cmc_monthly_credits: Option<u64>,
+
+    /// Log of fetched CMC prices, loaded at startup so a restart doesn't
+    /// spend credits on them again. The keys' credit usage is kept next to
+    /// it, in `<name>.usage.json`, and the price history in
+    /// `<name>.history.jsonl`
+    #[arg(long, env = "CMC_PRICE_CACHE")]
+    cmc_price_cache: Option<PathBuf>,
+
+    /// Seconds a cached latest CMC price is used for, past days' prices are
+    /// kept for good
+    #[arg(long, default_value_t = DEFAULT_LATEST_PRICE_TTL.as_secs())]
+    cmc_latest_price_ttl_s: u64,
+
+    /// CoinGecko API to price portfolios with alongside CMC, e.g.
+    /// https://api.coingecko.com/api/v3
+    #[arg(long, env = "COINGECKO_URL")]
+    coingecko_url: Option<String>,
+
+    /// Demo key, or pro key with the pro API's URL
+    #[arg(long, env = "COINGECKO_API_KEY")]
+    coingecko_api_key: Option<String>,
+
+    /// Record Uniswap V2 and V3 pool states and price tokens from them
+    #[arg(long)]
+    dex_prices: bool,
+
+    /// DEX pools with less liquidity are not priced from
+    #[arg(long, default_value_t = DEFAULT_MIN_LIQUIDITY_USD)]
+    dex_min_liquidity_usd: f64,
+
+    /// Whale alerts are recorded from this USD value
+    #[arg(long, default_value_t = WhaleAlertThresholds::default().min_usd)]
+    whale_alert_usd: f64,
+
+    /// Lower whale alert threshold for movements into or out of exchanges
+    #[arg(long, default_value_t = WhaleAlertThresholds::default().exchange_min_usd)]
+    exchange_alert_usd: f64,
+
+    /// Trace every transaction for internal ETH transfers, needs a node with
+    /// `trace_transaction`
+    #[arg(long)]
+    trace_internal_transfers: bool,
 }
 
 
@@ -47,17 +146,215 @@ async fn main() -> eyre::Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     let args = Args::parse();
-    println!("args: {args:?}");
 
     let start_block = args.start_block;
     let end_block = args.end_block;
     info!("Processing blocks from {} to {}", start_block, end_block);
     let url = args.url;
-    let api = EthersClient::new(&url, Some("https://eth.llamarpc.com"));
+    let api = Arc::new(EthersClient::new(&url, Some("https://eth.llamarpc.com")));
 
-    //let block_table = BlockWorkTable::default();
-    let tx_table = TransactionWorkTable::default();
-    let current_id = AtomicU32::new(0);
+    let tables = Arc::new(Tables::default());
+    for path in &args.label_files {
+        let summary = import_labels_file(&tables.address_labels, path)?;
+        info!(
+            "Imported {} labels from {} ({} duplicates)",
+            summary.added,
+            path.display(),
+            summary.duplicates
+        );
+    }
+    for path in &args.signature_files {
+        let summary = tables.signatures.import_file(path)?;
+        info!(
+            "Imported {} signatures from {} ({} duplicates, {} rejected)",
+            summary.added,
+            path.display(),
+            summary.duplicates,
+            summary.rejected
+        );
+    }
+    let events = EventBus::default();
+    let chain = api.get_chain().await?;
+    let mut ingester = Ingester::new(api.clone(), tables.clone(), events.clone());
+    let mut dex_oracle = None;
+    if args.dex_prices {
+        match (DexFactories::for_chain(chain), DexQuoteTokens::for_chain(chain)) {
+            (Some(factories), Some(quotes)) => {
+                ingester = ingester.with_dex_pools(factories);
+                let native_cmc_id = match NativeCoin::for_chain(chain).map(|coin| coin.asset) {
+                    Some(AssetId::Cmc(id)) => Some(id),
+                    _ => None,
+                };
+                dex_oracle = Some(Arc::new(DexPriceOracle::new(
+                    tables.clone(),
+                    chain,
+                    quotes,
+                    native_cmc_id,
+                    args.dex_min_liquidity_usd,
+                )));
+            }
+            _ => warn!("No DEX pools known on {}, not pricing from them", chain),
+        }
+    }
+    let ingester = Arc::new(ingester);
+    let cmc = if args.cmc_api_keys.is_empty() {
+        None
+    } else {
+        let budget = CmcCreditBudget {
+            daily: args.cmc_daily_credits,
+            monthly: args.cmc_monthly_credits,
+        };
+        let mut cmc = CoinMarketCap::with_keys(&args.cmc_api_keys, budget)?;
+        if let Some(path) = &args.cmc_price_cache {
+            cmc = cmc
+                .with_price_store(path, Duration::from_secs(args.cmc_latest_price_ttl_s))?
+                .with_key_usage_file(&path.with_extension("usage.json"))?;
+        }
+        Some(Arc::new(cmc))
+    };
+    let coingecko = match &args.coingecko_url {
+        Some(url) => Some(Arc::new(CoinGecko::new(
+            url,
+            args.coingecko_api_key.as_deref(),
+        )?)),
+        None => None,
+    };
+    let price_history = match &cmc {
+        Some(cmc) => {
+            let mut history = PriceHistory::new(cmc.clone(), tables.clone(), QuoteInterval::Hourly);
+            if let Some(path) = &args.cmc_price_cache {
+                history = history
+                    .with_quote_log(&path.with_extension("history.jsonl"))
+                    .await?;
+            }
+            Some(Arc::new(history))
+        }
+        None => None,
+    };
+    if let Some(cmc) = &cmc {
+        let mut price_sync = TokenPriceSync::new(cmc.clone(), tables.clone(), chain);
+        if let Some(coingecko) = &coingecko {
+            price_sync = price_sync.with_coingecko(coingecko.clone());
+        }
+        tokio::spawn(Arc::new(price_sync).run(TOKEN_PRICE_REFRESH_INTERVAL));
+    }
+    if let Some(history) = &price_history {
+        if let Some(native) = NativeCoin::for_chain(chain) {
+            let alerter = Arc::new(WhaleAlerter::new(
+                api.clone(),
+                history.clone(),
+                tables.clone(),
+                events.clone(),
+                native.clone(),
+                WhaleAlertThresholds {
+                    min_usd: args.whale_alert_usd,
+                    exchange_min_usd: args.exchange_alert_usd,
+                },
+                args.trace_internal_transfers,
+            ));
+            tokio::spawn(alerter.run(events.subscribe()));
+            if let AssetId::Cmc(native_id) = native.asset {
+                let valuer = Arc::new(UsdValuer::new(tables.clone(), history.clone(), native_id));
+                tokio::spawn(valuer.run(events.subscribe(), USD_BACKFILL_INTERVAL));
+            }
+        }
+    }
+    if let Some(addr) = args.api_listen {
+        let search_index = Arc::new(SearchIndex::new(chain));
+        if let Some(cmc) = &cmc {
+            match cmc.get_coin_map().await {
+                Ok(coins) => search_index.set_cmc_coins(coins),
+                Err(e) => error!("Failed to load CMC coin map: {:?}", e),
+            }
+        }
+        tokio::spawn(
+            search_index
+                .clone()
+                .refresh_periodically(tables.clone(), SEARCH_INDEX_REFRESH_INTERVAL),
+        );
+
+        let mut server = ApiServer::new(args.api_keys.into_iter().collect());
+        add_table_endpoints(&mut server, tables.clone(), search_index);
+        let gas_oracle = ingester.gas_oracle();
+        tokio::spawn(gas_oracle.clone().refresh_on_blocks(events.clone()));
+        server
+            .add_endpoint(MethodGetGasOracle { oracle: gas_oracle })
+            .add_endpoint(MethodGetTransactionLogs {
+                tables: tables.clone(),
+                api: api.clone(),
+            })
+            .add_endpoint(MethodGetContractMetadata {
+                tables: tables.clone(),
+                api: api.clone(),
+            })
+            .add_endpoint(MethodGetToken {
+                tables: tables.clone(),
+                api: api.clone(),
+            })
+            .add_endpoint(MethodVerifyTokenBalance {
+                tables: tables.clone(),
+                api: api.clone(),
+            });
+        let mut sources = vec![];
+        if let Some(cmc) = &cmc {
+            sources.push(PriceSource {
+                name: "cmc".to_string(),
+                client: cmc.clone() as Arc<dyn AssetInfoClient>,
+                weight: 1.0,
+            });
+        }
+        if let Some(coingecko) = &coingecko {
+            sources.push(PriceSource {
+                name: "coingecko".to_string(),
+                client: coingecko.clone(),
+                weight: 1.0,
+            });
+        }
+        if let Some(oracle) = &dex_oracle {
+            // pools are thinner than the aggregators' volume weighted prices
+            sources.push(PriceSource {
+                name: "dex".to_string(),
+                client: oracle.clone(),
+                weight: 0.5,
+            });
+        }
+        if !sources.is_empty() {
+            // a lone configured source is trusted, several corroborate each other
+            let config = AggregationConfig {
+                min_sources: sources.len().min(2),
+                ..AggregationConfig::default()
+            };
+            let prices = Arc::new(AggregatePriceClient::new(sources, config));
+            server
+                .add_endpoint(MethodGetPortfolio {
+                    tables: tables.clone(),
+                    api: api.clone(),
+                    prices: prices.clone(),
+                    native: NativeCoin::for_chain(chain),
+                })
+                .add_endpoint(MethodGetAssetPrices { prices, chain });
+        }
+        if let Some(history) = &price_history {
+            server.add_endpoint(MethodGetPriceAt {
+                history: history.clone(),
+            });
+        }
+        if let Some(oracle) = &dex_oracle {
+            server.add_endpoint(MethodGetDexPrice {
+                oracle: oracle.clone(),
+            });
+        }
+        server
+            .enable_subscriptions(events, tables.clone())
+            .enable_etherscan(tables.clone(), api);
+        let router = server.into_router();
+        tokio::spawn(async move {
+            if let Err(e) = serve(router, addr).await {
+                error!("API server error: {:?}", e);
+            }
+        });
+        tokio::spawn(ingester.clone().watch_mempool(MEMPOOL_POLL_INTERVAL));
+    }
 
     let mut num_transactions: u128 = 0;
 
@@ -68,76 +365,29 @@ async fn main() -> eyre::Result<()> {
     let sys = Arc::new(Mutex::new(System::new_all()));
 
     for block_number in start_block..=end_block {
-        match api
-            .get_block_with_txs(BlockId::from(block_number as u64))
-            .await
-        {
-            Ok(Some(block)) => {
-                //let block_id = current_id.fetch_add(1, Ordering::SeqCst);
-
-                //info!("Processing block: {:?}", block.number);
-                let mut tx_ids: Vec<u32> = vec![];
-
-                for tx in &block.transactions {
-                    num_transactions += 1;
-                    let tx_id = current_id.fetch_add(1, Ordering::SeqCst);
-                    tx_ids.push(tx_id);
-
-                    //info!("Transaction details: {:?}", tx);
-
-                    tx_table.insert(TransactionRow {
-                        id: tx_id,
-                        hash: tx.hash.into(),
-                        status: 1u8,
-                        block_number,
-                        timestamp_s: block.timestamp.as_u32(),
-                        from_address: tx.from.into(),
-                        to_address: tx.to.map(|address| address.into()),
-                        //internal_transactions: "".to_string(), //TODO: this needs to be fetched
-                        value: tx.value.into(),
-                        fee: tx.gas.saturating_mul(tx.gas_price.unwrap_or_default()).into(),
-                        gas: tx.gas_price.map(|gas_price| gas_price.into()),
-                    })?;
-                    //info!("Transaction inserted with ID: {}", tx_id);
-
-                    //info!("Transaction table after insertion:\n{:#?}", tx_table);
-                    //                 info!("transactions_since_last_timing_event: {}",
-                    // transactions_since_last_timing_event);
-                    let now = SystemTime::now();
-                    if SystemTime::now().duration_since(last_time)?.as_secs() >= 1 {
-                        last_time = now;
-                        info!("Processing {} tps", num_transactions - txs_at_last_timer);
-                        txs_at_last_timer = num_transactions;
-
-                        let sysclone = sys.clone();
-
-                        tokio::spawn(async move {
-                            check_memory_usage(sysclone).await;
-                        });
-                    }
-
-                    if num_transactions % 50_000 == 0 {
-                        info!("Processed {num_transactions} transactions");
-                    }
-                }
+        match ingester.ingest_block(block_number).await {
+            Ok(block_transactions) => {
+                let txs_before = num_transactions;
+                num_transactions += block_transactions as u128;
 
-                // block_table.insert(BlockRow {
-                //     id: block_id,
-                //     number: block.number.unwrap_or_default().as_u32(),
-                //     status: 1u8,
-                //     timestamp_s: block.timestamp.as_u32(),
-                //     transactions: tx_ids,
-                //     eth_price_usd_cents: 0, //TODO: use cmc lookup here
-                // })?;
+                let now = SystemTime::now();
+                if SystemTime::now().duration_since(last_time)?.as_secs() >= 1 {
+                    last_time = now;
+                    info!("Processing {} tps", num_transactions - txs_at_last_timer);
+                    txs_at_last_timer = num_transactions;
 
-                // if block_number % 10_000 == 0 {
-                //      debug!("Block inserted with NUMBER: {}", block_number);
-                //  }
+                    let sysclone = sys.clone();
 
-                //info!("Block table after insertion:\n{:#?}", block_table);
+                    tokio::spawn(async move {
+                        check_memory_usage(sysclone).await;
+                    });
+                }
+
+                if num_transactions / 50_000 > txs_before / 50_000 {
+                    info!("Processed {num_transactions} transactions");
+                }
             }
-            Ok(None) => error!("Block {} not found", block_number),
-            Err(e) => error!("Error fetching block {}: {}", block_number, e),
+            Err(e) => error!("Error processing block {}: {:?}", block_number, e),
         }
     }
     info!("Processed all blocks");
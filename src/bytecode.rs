@@ -0,0 +1,366 @@
+use ethers::types::{BlockId, BlockNumber, H160};
+use ethers::utils::{hex, keccak256};
+use eyre::*;
+use std::collections::BTreeSet;
+
+use crate::api::EthersClient;
+use crate::proxy::detect_proxy;
+use crate::tables::{ContractRow, Tables};
+
+const OP_EQ: u8 = 0x14;
+const OP_XOR: u8 = 0x18;
+const OP_PUSH1: u8 = 0x60;
+const OP_PUSH4: u8 = 0x63;
+const OP_PUSH32: u8 = 0x7f;
+const OP_DUP1: u8 = 0x80;
+const OP_DUP16: u8 = 0x8f;
+/// Metadata is a few dozen bytes, deeper nesting means it isn't metadata
+const MAX_CBOR_DEPTH: usize = 4;
+
+/// What the compiler appended to the runtime bytecode
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BytecodeMetadata {
+    /// `solc` or `vyper`
+    pub compiler: Option<String>,
+    pub compiler_version: Option<String>,
+    /// `ipfs://<cid>`, `bzzr0://<hex>` or `bzzr1://<hex>`
+    pub source_hash: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BytecodeAnalysis {
+    pub code_hash: [u8; 32],
+    pub metadata: Option<BytecodeMetadata>,
+    /// Ascending and deduplicated
+    pub selectors: Vec<u32>,
+}
+
+pub fn analyze_bytecode(code: &[u8]) -> BytecodeAnalysis {
+    let (metadata, metadata_len) = match parse_metadata(code) {
+        Some((metadata, len)) => (Some(metadata), len),
+        None => (None, 0),
+    };
+    BytecodeAnalysis {
+        code_hash: keccak256(code),
+        metadata,
+        selectors: extract_selectors(&code[..code.len() - metadata_len]),
+    }
+}
+
+/// Reads the CBOR blob solc and vyper append to the runtime code, followed
+/// by its length as a big-endian `u16`. Returns the metadata and the number
+/// of trailing bytes it takes up
+pub fn parse_metadata(code: &[u8]) -> Option<(BytecodeMetadata, usize)> {
+    let len_offset = code.len().checked_sub(2)?;
+    let len = u16::from_be_bytes([code[len_offset], code[len_offset + 1]]) as usize;
+    let start = len_offset.checked_sub(len)?;
+    let mut reader = CborReader {
+        data: &code[start..len_offset],
+        pos: 0,
+    };
+    let item = reader.read(0)?;
+    if reader.pos != len {
+        return None;
+    }
+    let entries = match item {
+        Cbor::Map(entries) => entries,
+        // vyper 0.3.10 and later wrap the map in an array with section sizes
+        Cbor::Array(items) => items.into_iter().find_map(|item| match item {
+            Cbor::Map(entries) => Some(entries),
+            _ => None,
+        })?,
+        _ => return None,
+    };
+
+    let mut metadata = BytecodeMetadata::default();
+    for (key, value) in entries {
+        let Cbor::Text(key) = key else {
+            continue;
+        };
+        match (key.as_str(), value) {
+            ("solc", Cbor::Bytes(version)) if version.len() == 3 => {
+                metadata.compiler = Some("solc".to_string());
+                metadata.compiler_version =
+                    Some(format!("{}.{}.{}", version[0], version[1], version[2]));
+            }
+            // prereleases carry the full version string
+            ("solc", Cbor::Text(version)) => {
+                metadata.compiler = Some("solc".to_string());
+                metadata.compiler_version = Some(version);
+            }
+            ("vyper", Cbor::Array(parts)) => {
+                let parts: Option<Vec<String>> = parts
+                    .into_iter()
+                    .map(|part| match part {
+                        Cbor::Uint(part) => Some(part.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                metadata.compiler = Some("vyper".to_string());
+                metadata.compiler_version = parts.map(|parts| parts.join("."));
+            }
+            ("ipfs", Cbor::Bytes(hash)) => {
+                metadata.source_hash = Some(format!("ipfs://{}", bs58::encode(hash).into_string()));
+            }
+            (swarm @ ("bzzr0" | "bzzr1"), Cbor::Bytes(hash)) => {
+                metadata.source_hash = Some(format!("{}://{}", swarm, hex::encode(hash)));
+            }
+            _ => {}
+        }
+    }
+    // solc before 0.5.9 only recorded the swarm hash, vyper never records one
+    if metadata.compiler.is_none() && metadata.source_hash.is_some() {
+        metadata.compiler = Some("solc".to_string());
+    }
+    Some((metadata, len + 2))
+}
+
+/// Selectors the dispatcher compares the calldata against: a `PUSH4`
+/// followed by `EQ` or `XOR`, optionally with a `DUPn` in between
+pub fn extract_selectors(code: &[u8]) -> Vec<u32> {
+    let mut selectors = BTreeSet::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = code[pc];
+        if op == OP_PUSH4 {
+            if let Some(bytes) = code.get(pc + 1..pc + 5) {
+                let selector = u32::from_be_bytes(bytes.try_into().unwrap());
+                let mut next = pc + 5;
+                if code
+                    .get(next)
+                    .is_some_and(|op| (OP_DUP1..=OP_DUP16).contains(op))
+                {
+                    next += 1;
+                }
+                // 0xffffffff is the mask used to cut the selector out of a word
+                if matches!(code.get(next), Some(&OP_EQ) | Some(&OP_XOR)) && selector != u32::MAX {
+                    selectors.insert(selector);
+                }
+            }
+        }
+        pc += 1;
+        if (OP_PUSH1..=OP_PUSH32).contains(&op) {
+            pc += (op - OP_PUSH1 + 1) as usize;
+        }
+    }
+    selectors.into_iter().collect()
+}
+
+/// Fetches the contract's runtime code and stores the analysis and proxy
+/// detection on its row
+pub async fn analyze_contract(
+    api: &EthersClient,
+    tables: &Tables,
+    address: H160,
+    creator: Option<H160>,
+) -> Result<ContractRow> {
+    let code = api
+        .get_contract_bytecode(address, BlockId::Number(BlockNumber::Latest))
+        .await?;
+    ensure!(!code.is_empty(), "{:?} has no code", address);
+    let analysis = analyze_bytecode(&code);
+    let metadata = analysis.metadata.unwrap_or_default();
+    let proxy = detect_proxy(api, address, &code).await?;
+    tables
+        .contracts
+        .upsert_contract(address.into(), |row| {
+            if let Some(creator) = creator {
+                row.creator = format!("{:?}", creator);
+            }
+            row.code_hash = Some(analysis.code_hash);
+            row.compiler = metadata.compiler;
+            row.compiler_version = metadata.compiler_version;
+            row.source_hash = metadata.source_hash;
+            row.selectors = analysis.selectors;
+            row.proxy_kind = proxy.map(|proxy| proxy.kind as u8);
+            row.implementation = proxy.map(|proxy| proxy.implementation.into());
+            row.beacon = proxy.and_then(|proxy| proxy.beacon).map(Into::into);
+        })
+        .await
+}
+
+#[derive(Debug)]
+enum Cbor {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    /// Negative integers and simple values, which metadata never uses
+    Other,
+}
+
+/// Just enough CBOR for compiler metadata: definite lengths only, no tags
+/// or floats
+struct CborReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl CborReader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn argument(&mut self, info: u8) -> Option<u64> {
+        Some(match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().ok()?) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().ok()?) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().ok()?),
+            _ => return None,
+        })
+    }
+
+    /// Lengths larger than the remaining input are rejected before anything
+    /// is allocated
+    fn length(&mut self, info: u8) -> Option<usize> {
+        let len = usize::try_from(self.argument(info)?).ok()?;
+        (len <= self.data.len() - self.pos).then_some(len)
+    }
+
+    fn read(&mut self, depth: usize) -> Option<Cbor> {
+        if depth > MAX_CBOR_DEPTH {
+            return None;
+        }
+        let initial = self.take(1)?[0];
+        let info = initial & 0x1f;
+        Some(match initial >> 5 {
+            0 => Cbor::Uint(self.argument(info)?),
+            1 => {
+                self.argument(info)?;
+                Cbor::Other
+            }
+            2 => {
+                let len = self.length(info)?;
+                Cbor::Bytes(self.take(len)?.to_vec())
+            }
+            3 => {
+                let len = self.length(info)?;
+                Cbor::Text(String::from_utf8(self.take(len)?.to_vec()).ok()?)
+            }
+            4 => {
+                let len = self.length(info)?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(self.read(depth + 1)?);
+                }
+                Cbor::Array(items)
+            }
+            5 => {
+                let len = self.length(info)?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    entries.push((self.read(depth + 1)?, self.read(depth + 1)?));
+                }
+                Cbor::Map(entries)
+            }
+            7 if info < 24 => Cbor::Other,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runtime code ending in `metadata` and its length
+    fn with_metadata(metadata: &[u8]) -> Vec<u8> {
+        let mut code = vec![0x60, 0x80, 0x60, 0x40, 0x52];
+        code.extend_from_slice(metadata);
+        code.extend_from_slice(&(metadata.len() as u16).to_be_bytes());
+        code
+    }
+
+    #[test]
+    fn parses_solc_metadata() {
+        let mut metadata = hex::decode("a2646970667358221220").unwrap();
+        metadata.extend_from_slice(&[0xab; 32]);
+        metadata.extend_from_slice(&hex::decode("64736f6c6343000813").unwrap());
+        let code = with_metadata(&metadata);
+
+        let (parsed, len) = parse_metadata(&code).unwrap();
+        assert_eq!(len, metadata.len() + 2);
+        assert_eq!(parsed.compiler.as_deref(), Some("solc"));
+        assert_eq!(parsed.compiler_version.as_deref(), Some("0.8.19"));
+        assert!(parsed.source_hash.unwrap().starts_with("ipfs://Qm"));
+    }
+
+    #[test]
+    fn infers_solc_from_a_lone_swarm_hash() {
+        let mut metadata = hex::decode("a165627a7a72305820").unwrap();
+        metadata.extend_from_slice(&[0x01; 32]);
+        let (parsed, _) = parse_metadata(&with_metadata(&metadata)).unwrap();
+        assert_eq!(parsed.compiler.as_deref(), Some("solc"));
+        assert_eq!(parsed.compiler_version, None);
+        assert_eq!(
+            parsed.source_hash,
+            Some(format!("bzzr0://{}", hex::encode([0x01; 32])))
+        );
+    }
+
+    #[test]
+    fn parses_vyper_metadata() {
+        let metadata = hex::decode("a16576797065728300030a").unwrap();
+        let (parsed, _) = parse_metadata(&with_metadata(&metadata)).unwrap();
+        assert_eq!(parsed.compiler.as_deref(), Some("vyper"));
+        assert_eq!(parsed.compiler_version.as_deref(), Some("0.3.10"));
+        assert_eq!(parsed.source_hash, None);
+    }
+
+    #[test]
+    fn rejects_code_without_metadata() {
+        // the length points before the start of the code
+        assert_eq!(parse_metadata(&[0x60, 0x80, 0x00, 0xff]), None);
+        // an empty map that doesn't span the whole declared length
+        assert_eq!(parse_metadata(&[0xa0, 0x00, 0x00, 0x02]), None);
+        assert_eq!(parse_metadata(&[]), None);
+    }
+
+    #[test]
+    fn rejects_oversized_cbor_lengths() {
+        // a byte string claiming 4 GiB
+        let metadata = hex::decode("a164736f6c635affffffff").unwrap();
+        assert_eq!(parse_metadata(&with_metadata(&metadata)), None);
+    }
+
+    #[test]
+    fn extracts_dispatcher_selectors() {
+        let code = hex::decode(concat!(
+            // PUSH4 0xa9059cbb EQ
+            "63a9059cbb14",
+            // PUSH4 0x70a08231 DUP2 EQ
+            "6370a082318114",
+            // PUSH4 0x095ea7b3 XOR
+            "63095ea7b318",
+            // the selector mask isn't a selector
+            "63ffffffff14",
+            // PUSH4 not compared
+            "6318160ddd16",
+        ))
+        .unwrap();
+        assert_eq!(
+            extract_selectors(&code),
+            vec![0x095ea7b3, 0x70a08231, 0xa9059cbb]
+        );
+    }
+
+    #[test]
+    fn skips_push_data() {
+        // PUSH32 whose data looks like PUSH4 .. EQ, then a real comparison
+        let mut code = vec![OP_PUSH32];
+        code.extend_from_slice(&hex::decode("63deadbeef14").unwrap());
+        code.extend_from_slice(&[0; 26]);
+        code.extend_from_slice(&hex::decode("6323b872dd14").unwrap());
+        assert_eq!(extract_selectors(&code), vec![0x23b872dd]);
+        // a truncated PUSH4 at the end of the code
+        assert_eq!(
+            extract_selectors(&[OP_PUSH4, 0x01, 0x02]),
+            Vec::<u32>::new()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use ethers::abi::{Abi, RawLog};
+use ethers::types::{Log, H160};
+use eyre::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::model::{DecodedArgument, DecodedCall, DecodedLog};
+use crate::signatures::{decode_call, text_signature, token_json, Selector};
+use crate::tables::Tables;
+
+/// Parsed form of the ABIs stored on `Contract` rows, so decoding doesn't
+/// reparse the JSON for every transaction
+#[derive(Default)]
+pub struct ContractAbis {
+    parsed: RwLock<HashMap<H160, Arc<Abi>>>,
+}
+
+impl ContractAbis {
+    pub fn get(&self, address: H160) -> Option<Arc<Abi>> {
+        self.parsed.read().unwrap().get(&address).cloned()
+    }
+
+    fn insert(&self, address: H160, abi: Arc<Abi>) {
+        self.parsed.write().unwrap().insert(address, abi);
+    }
+}
+
+impl Tables {
+    /// Stores `abi_json` on the contract's row
+    pub async fn set_contract_abi(&self, address: H160, abi_json: String) -> Result<Arc<Abi>> {
+        let abi: Abi = serde_json::from_str(&abi_json).context("invalid JSON ABI")?;
+        let abi = Arc::new(abi);
+        self.contracts
+            .upsert_contract(address.into(), |row| row.abi = Some(abi_json))
+            .await?;
+        self.contract_abis.insert(address, abi.clone());
+        Ok(abi)
+    }
+
+    /// ABIs that apply to calls to `address`: a proxy's implementation
+    /// first, then the contract's own for the proxy's admin functions
+    fn abis_for(&self, address: H160) -> Vec<Arc<Abi>> {
+        let implementation = self
+            .contracts
+            .select_contract(address.into())
+            .and_then(|row| row.implementation)
+            .map(H160::from);
+        implementation
+            .into_iter()
+            .chain([address])
+            .filter_map(|address| self.contract_abis.get(address))
+            .collect()
+    }
+
+    /// Decodes calldata with the ABIs uploaded for `to`, falling back to the
+    /// signature registry for other contracts
+    pub fn decode_call(&self, to: Option<H160>, input: &[u8]) -> Option<DecodedCall> {
+        let abis = to.map(|to| self.abis_for(to)).unwrap_or_default();
+        abis.iter()
+            .flat_map(|abi| abi.functions())
+            .find_map(|function| decode_call(function, input))
+            .or_else(|| self.signatures.decode(input))
+    }
+
+    /// Name of a selector of `address`, exact if the contract has an ABI,
+    /// otherwise every candidate from the signature registry
+    pub fn signatures_of(&self, address: H160, selector: Selector) -> Vec<String> {
+        if let Some(function) = self.abis_for(address).iter().find_map(|abi| {
+            abi.functions()
+                .find(|function| function.short_signature() == selector)
+                .cloned()
+        }) {
+            return vec![text_signature(&function)];
+        }
+        self.signatures.signatures_of(selector)
+    }
+
+    /// Decodes a log with the ABIs uploaded for its emitting contract, a
+    /// proxy emits its implementation's events
+    pub fn decode_log(&self, log: &Log) -> Option<DecodedLog> {
+        self.abis_for(log.address)
+            .iter()
+            .find_map(|abi| decode_log_with(abi, log))
+    }
+}
+
+fn decode_log_with(abi: &Abi, log: &Log) -> Option<DecodedLog> {
+    let topic = log.topics.first()?;
+    let raw = RawLog {
+        topics: log.topics.clone(),
+        data: log.data.to_vec(),
+    };
+    abi.events()
+        .filter(|event| event.anonymous || event.signature() == *topic)
+        .find_map(|event| {
+            let parsed = event.parse_log(raw.clone()).ok()?;
+            let signature = format!(
+                "{}({})",
+                event.name,
+                event
+                    .inputs
+                    .iter()
+                    .map(|input| input.kind.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            Some(DecodedLog {
+                name: event.name.clone(),
+                signature,
+                arguments: event
+                    .inputs
+                    .iter()
+                    .zip(parsed.params)
+                    .map(|(input, param)| DecodedArgument {
+                        name: Some(param.name).filter(|name| !name.is_empty()),
+                        kind: input.kind.to_string(),
+                        value: token_json(&param.value),
+                    })
+                    .collect(),
+            })
+        })
+}
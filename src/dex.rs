@@ -0,0 +1,768 @@
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use ethers::abi::{decode, encode, ParamType, Token};
+use ethers::types::{BlockId, BlockNumber, Chain, Log, H160, H256, U256};
+use ethers::utils::format_units;
+use eyre::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::api::assets::{AssetId, AssetInfoClient, AssetPriceByPeriod};
+use crate::api::{
+    EthersClient, UNISWAP_V2_SWAP_EVENT_TOPIC, UNISWAP_V2_SYNC_EVENT_TOPIC,
+    UNISWAP_V3_SWAP_EVENT_TOPIC,
+};
+use crate::model::{EnumDexProtocol, EnumTokenStandard};
+use crate::tables::{DexPoolRow, DexPoolStateRow, Tables};
+use crate::tokens::detect_token;
+
+pub const DEX_EVENT_TOPICS: [H256; 3] = [
+    UNISWAP_V2_SYNC_EVENT_TOPIC,
+    UNISWAP_V2_SWAP_EVENT_TOPIC,
+    UNISWAP_V3_SWAP_EVENT_TOPIC,
+];
+
+const TOKEN0_SELECTOR: [u8; 4] = [0x0d, 0xfe, 0x16, 0x81];
+const TOKEN1_SELECTOR: [u8; 4] = [0xd2, 0x12, 0x20, 0xa7];
+const FEE_SELECTOR: [u8; 4] = [0xdd, 0xca, 0x3f, 0x43];
+/// `getPair(address,address)` of a V2 factory
+const GET_PAIR_SELECTOR: [u8; 4] = [0xe6, 0xa4, 0x39, 0x05];
+/// `getPool(address,address,uint24)` of a V3 factory
+const GET_POOL_SELECTOR: [u8; 4] = [0x16, 0x98, 0xee, 0x82];
+/// One pool state is kept per pool and this much block time
+const POOL_STATE_INTERVAL_S: u32 = 5 * 60;
+/// Pool states are kept long enough for the 30 day period price
+const POOL_STATE_RETENTION_S: u32 = 32 * 24 * 60 * 60;
+/// Pools with less than this on their quote side, in USD and counted twice
+/// for both sides, are too easy to move to price from
+pub const DEFAULT_MIN_LIQUIDITY_USD: f64 = 50_000.0;
+
+pub fn is_dex_log(log: &Log) -> bool {
+    log.topics
+        .first()
+        .is_some_and(|topic| DEX_EVENT_TOPICS.contains(topic))
+}
+
+/// Reserves in a V3 pool's current tick range that would give its price at
+/// the same liquidity in a V2 pair: `L / sqrt(P)` and `L * sqrt(P)`
+fn virtual_reserves(sqrt_price_x96: U256, liquidity: U256) -> (U256, U256) {
+    if sqrt_price_x96.is_zero() {
+        return (U256::zero(), U256::zero());
+    }
+    let saturate = |value: ethers::types::U512| U256::try_from(value).unwrap_or(U256::MAX);
+    let reserve0 = saturate((liquidity.full_mul(U256::one() << 96)) / sqrt_price_x96);
+    let reserve1 = saturate(liquidity.full_mul(sqrt_price_x96) >> 96);
+    (reserve0, reserve1)
+}
+
+/// Pool state carried by the log, `None` for a V2 `Swap`, whose reserves
+/// come with the `Sync` every pair emits right before it
+fn log_reserves(log: &Log) -> Option<(U256, U256)> {
+    let topic = *log.topics.first()?;
+    if topic == UNISWAP_V2_SYNC_EVENT_TOPIC {
+        match decode(&[ParamType::Uint(112), ParamType::Uint(112)], &log.data).ok()?[..] {
+            [Token::Uint(reserve0), Token::Uint(reserve1)] => Some((reserve0, reserve1)),
+            _ => None,
+        }
+    } else if topic == UNISWAP_V3_SWAP_EVENT_TOPIC {
+        let params = [
+            ParamType::Int(256),
+            ParamType::Int(256),
+            ParamType::Uint(160),
+            ParamType::Uint(128),
+            ParamType::Int(24),
+        ];
+        match decode(&params, &log.data).ok()?[..] {
+            [_, _, Token::Uint(sqrt_price_x96), Token::Uint(liquidity), _] => {
+                Some(virtual_reserves(sqrt_price_x96, liquidity))
+            }
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Factories whose pools are tracked. Anyone can deploy a contract emitting
+/// the pool events, so only addresses a factory returns for their tokens
+/// count as pools
+#[derive(Debug, Clone)]
+pub struct DexFactories {
+    pub uniswap_v2: Vec<H160>,
+    pub uniswap_v3: Vec<H160>,
+}
+
+impl DexFactories {
+    /// Uniswap's factories and, where they are the main venue, forks with
+    /// the same interface
+    pub fn for_chain(chain: Chain) -> Option<Self> {
+        let (uniswap_v2, uniswap_v3): (&[&str], &[&str]) = match chain {
+            Chain::Mainnet => (
+                &[
+                    "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f",
+                    // SushiSwap
+                    "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
+                ],
+                &["0x1F98431c8aD98523631AE4a59f267346ea31F984"],
+            ),
+            Chain::Arbitrum => (
+                &["0xf1D7CC64Fb4452F05c498126312eBE29f30Fbcf9"],
+                &["0x1F98431c8aD98523631AE4a59f267346ea31F984"],
+            ),
+            Chain::Optimism => (
+                &["0x0c3c1c532F1e39EdF36BE9Fe0bE1410313E074Bf"],
+                &["0x1F98431c8aD98523631AE4a59f267346ea31F984"],
+            ),
+            Chain::Base => (
+                &["0x8909Dc15e40173Ff4699343b6eB8132c65e18eC6"],
+                &["0x33128a8fC17869897dcE68Ed026d694621f6FDfD"],
+            ),
+            Chain::BinanceSmartChain => (
+                &[
+                    "0x8909Dc15e40173Ff4699343b6eB8132c65e18eC6",
+                    // PancakeSwap
+                    "0xcA143Ce32Fe78f1f7019d7d551a6402fC5350c73",
+                ],
+                &["0xdB1d10011AD0Ff90774D0C6Bb92e5C5c8b4461F7"],
+            ),
+            _ => return None,
+        };
+        let parse = |addresses: &[&str]| {
+            addresses
+                .iter()
+                .filter_map(|address| H160::from_str(address).ok())
+                .collect()
+        };
+        Some(Self {
+            uniswap_v2: parse(uniswap_v2),
+            uniswap_v3: parse(uniswap_v3),
+        })
+    }
+}
+
+/// Records Uniswap V2 and V3 pool states from ingested blocks into the
+/// `DexPool` and `DexPoolState` tables, the latest state per pool and five
+/// minutes of block time, for a month.
+///
+/// Pools of the forks in `DexFactories` are tracked alike, their prices are
+/// only trusted above the oracle's liquidity threshold.
+pub struct DexPoolTracker {
+    api: Arc<EthersClient>,
+    tables: Arc<Tables>,
+    factories: DexFactories,
+    /// Emitters of the events that turned out not to be pools
+    non_pools: Mutex<HashSet<H160>>,
+}
+
+impl DexPoolTracker {
+    pub fn new(api: Arc<EthersClient>, tables: Arc<Tables>, factories: DexFactories) -> Self {
+        Self {
+            api,
+            tables,
+            factories,
+            non_pools: Mutex::new(HashSet::new()),
+        }
+    }
+
+    async fn call_address(&self, pool: H160, selector: [u8; 4]) -> Option<H160> {
+        let output = self
+            .api
+            .call_contract(
+                pool,
+                selector.to_vec().into(),
+                BlockId::Number(BlockNumber::Latest),
+            )
+            .await
+            .ok()?;
+        match decode(&[ParamType::Address], &output).ok()?[..] {
+            [Token::Address(address)] => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Whether one of the protocol's factories returns `address` as the
+    /// pool of its tokens
+    async fn is_factory_pool(
+        &self,
+        address: H160,
+        protocol: EnumDexProtocol,
+        (token0, token1): (H160, H160),
+        fee: u32,
+    ) -> bool {
+        let (factories, data) = match protocol {
+            EnumDexProtocol::UniswapV2 => (&self.factories.uniswap_v2, {
+                let mut data = GET_PAIR_SELECTOR.to_vec();
+                data.extend(encode(&[Token::Address(token0), Token::Address(token1)]));
+                data
+            }),
+            EnumDexProtocol::UniswapV3 => (&self.factories.uniswap_v3, {
+                let mut data = GET_POOL_SELECTOR.to_vec();
+                data.extend(encode(&[
+                    Token::Address(token0),
+                    Token::Address(token1),
+                    Token::Uint(fee.into()),
+                ]));
+                data
+            }),
+        };
+        for factory in factories {
+            let output = self
+                .api
+                .call_contract(
+                    *factory,
+                    data.clone().into(),
+                    BlockId::Number(BlockNumber::Latest),
+                )
+                .await;
+            let Result::Ok(output) = output else {
+                continue;
+            };
+            if let Result::Ok([Token::Address(pool)]) =
+                decode(&[ParamType::Address], &output).as_deref()
+            {
+                if *pool == address {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Looks up the pool's tokens on first sight, `None` if it isn't a pool
+    /// deployed by a known factory
+    async fn discover_pool(
+        &self,
+        address: H160,
+        protocol: EnumDexProtocol,
+    ) -> Result<Option<DexPoolRow>> {
+        if let Some(row) = self.tables.dex_pools.select_pool(address) {
+            return Ok(Some(row));
+        }
+        if self.non_pools.lock().unwrap().contains(&address) {
+            return Ok(None);
+        }
+        let tokens = match (
+            self.call_address(address, TOKEN0_SELECTOR).await,
+            self.call_address(address, TOKEN1_SELECTOR).await,
+        ) {
+            (Some(token0), Some(token1)) if token0 != token1 => Some((token0, token1)),
+            _ => None,
+        };
+        let Some((token0, token1)) = tokens else {
+            self.non_pools.lock().unwrap().insert(address);
+            return Ok(None);
+        };
+        let fee = match protocol {
+            EnumDexProtocol::UniswapV3 => self
+                .api
+                .call_contract(
+                    address,
+                    FEE_SELECTOR.to_vec().into(),
+                    BlockId::Number(BlockNumber::Latest),
+                )
+                .await
+                .ok()
+                .and_then(
+                    |output| match decode(&[ParamType::Uint(24)], &output).ok()?[..] {
+                        [Token::Uint(fee)] => Some(fee.low_u32()),
+                        _ => None,
+                    },
+                )
+                .unwrap_or_default(),
+            EnumDexProtocol::UniswapV2 => 0,
+        };
+        if !self
+            .is_factory_pool(address, protocol, (token0, token1), fee)
+            .await
+        {
+            self.non_pools.lock().unwrap().insert(address);
+            return Ok(None);
+        }
+        for token in [token0, token1] {
+            detect_token(
+                &self.api,
+                &self.tables,
+                token,
+                Some(EnumTokenStandard::Erc20),
+            )
+            .await?;
+        }
+        let row = DexPoolRow {
+            id: self.tables.dex_pools.get_next_pk().into(),
+            address: address.into(),
+            protocol: protocol as u8,
+            token0: token0.into(),
+            token1: token1.into(),
+            fee,
+            reserve0: U256::zero().into(),
+            reserve1: U256::zero().into(),
+            block_number: 0,
+        };
+        self.tables.dex_pools.insert(row.clone())?;
+        Ok(Some(row))
+    }
+
+    /// Records the state of every pool with an event in the block
+    pub async fn record_block(
+        &self,
+        logs: &[Log],
+        block_number: u32,
+        timestamp_s: u32,
+    ) -> Result<()> {
+        // the last event of each pool in the block, in log order
+        let mut latest: HashMap<H160, (EnumDexProtocol, Option<(U256, U256)>)> = HashMap::new();
+        for log in logs.iter().filter(|log| is_dex_log(log)) {
+            let protocol = if log.topics[0] == UNISWAP_V3_SWAP_EVENT_TOPIC {
+                EnumDexProtocol::UniswapV3
+            } else {
+                EnumDexProtocol::UniswapV2
+            };
+            let entry = latest.entry(log.address).or_insert((protocol, None));
+            if let Some(reserves) = log_reserves(log) {
+                entry.1 = Some(reserves);
+            }
+        }
+        for (address, (protocol, reserves)) in latest {
+            let Some(mut pool) = self.discover_pool(address, protocol).await? else {
+                continue;
+            };
+            let Some((reserve0, reserve1)) = reserves else {
+                continue;
+            };
+            pool.reserve0 = reserve0.into();
+            pool.reserve1 = reserve1.into();
+            pool.block_number = block_number;
+            self.tables.dex_pools.update(pool).await?;
+            let state = DexPoolStateRow {
+                id: self.tables.dex_pool_states.get_next_pk().into(),
+                pool_address: address.into(),
+                block_number,
+                timestamp_s,
+                reserve0: reserve0.into(),
+                reserve1: reserve1.into(),
+            };
+            self.tables
+                .record_dex_pool_state(state, POOL_STATE_INTERVAL_S, POOL_STATE_RETENTION_S)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// When to price at
+#[derive(Debug, Clone, Copy)]
+pub enum PricePoint {
+    Latest,
+    Block(u32),
+    Timestamp(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct DexPrice {
+    pub price_usd: f64,
+    /// Over the pools the price was taken from
+    pub liquidity_usd: f64,
+    /// The deepest of them
+    pub pool: H160,
+    /// Block of that pool's state
+    pub block_number: u32,
+}
+
+/// Tokens prices are quoted against, in USD terms
+#[derive(Debug, Clone)]
+pub struct DexQuoteTokens {
+    /// Wrapped native coin
+    pub wrapped_native: H160,
+    pub stablecoins: Vec<H160>,
+}
+
+impl DexQuoteTokens {
+    pub fn for_chain(chain: Chain) -> Option<Self> {
+        let (wrapped_native, stablecoins): (&str, &[&str]) = match chain {
+            Chain::Mainnet => (
+                "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2",
+                &[
+                    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+                    "0xdAC17F958D2ee523a2206206994597C13D831ec7",
+                    "0x6B175474E89094C44Da98b954EedeAC495271d0F",
+                ],
+            ),
+            Chain::Arbitrum => (
+                "0x82aF49447D8a07e3bd95BD0d56f35241523fBab1",
+                &[
+                    "0xaf88d065e77c8cC2239327C5EDb3A432268e5831",
+                    "0xFd086bC7CD5C481DCC9C85ebE478A1C0b69FCbb9",
+                ],
+            ),
+            Chain::Optimism => (
+                "0x4200000000000000000000000000000000000006",
+                &[
+                    "0x0b2C639c533813f4Aa9D7837CAf62653d097Ff85",
+                    "0x94b008aA00579c1307B0EF2c499aD98a8ce58e58",
+                ],
+            ),
+            Chain::Base => (
+                "0x4200000000000000000000000000000000000006",
+                &["0x833589fCD6eDb6E08f4c7C32D4f71b54bdA02913"],
+            ),
+            Chain::BinanceSmartChain => (
+                "0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c",
+                &[
+                    "0x55d398326f99059fF775485246999027B3197955",
+                    "0x8AC76a51cc950d9822D68b83fE1Ad97B32Cd580d",
+                ],
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            wrapped_native: H160::from_str(wrapped_native).ok()?,
+            stablecoins: stablecoins
+                .iter()
+                .filter_map(|address| H160::from_str(address).ok())
+                .collect(),
+        })
+    }
+}
+
+/// Prices tokens from the tracked pools' reserves, with no external API.
+///
+/// A token is priced against the wrapped native coin and stablecoins, the
+/// native coin against stablecoins taken at $1. Pools below the liquidity
+/// threshold are ignored and the rest are weighted by their liquidity.
+pub struct DexPriceOracle {
+    tables: Arc<Tables>,
+    chain: Chain,
+    quotes: DexQuoteTokens,
+    /// CMC id of the native coin, priced as its wrapped token
+    native_cmc_id: Option<u64>,
+    min_liquidity_usd: f64,
+}
+
+impl DexPriceOracle {
+    pub fn new(
+        tables: Arc<Tables>,
+        chain: Chain,
+        quotes: DexQuoteTokens,
+        native_cmc_id: Option<u64>,
+        min_liquidity_usd: f64,
+    ) -> Self {
+        Self {
+            tables,
+            chain,
+            quotes,
+            native_cmc_id,
+            min_liquidity_usd,
+        }
+    }
+
+    fn decimals(&self, token: H160) -> Option<u32> {
+        let row = self.tables.tokens.select_token(token)?;
+        (row.standard == EnumTokenStandard::Erc20 as u8).then_some(row.decimals as u32)
+    }
+
+    fn reserves_at(&self, pool: &DexPoolRow, at: PricePoint) -> Option<(U256, U256, u32)> {
+        let state = match at {
+            PricePoint::Latest => {
+                return (pool.block_number > 0).then(|| {
+                    (
+                        pool.reserve0.clone().into(),
+                        pool.reserve1.clone().into(),
+                        pool.block_number,
+                    )
+                })
+            }
+            PricePoint::Block(number) => self
+                .tables
+                .select_dex_pool_state_at_block(&pool.address, number)?,
+            PricePoint::Timestamp(timestamp_s) => self
+                .tables
+                .select_dex_pool_state_at_timestamp(&pool.address, timestamp_s)?,
+        };
+        Some((
+            state.reserve0.into(),
+            state.reserve1.into(),
+            state.block_number,
+        ))
+    }
+
+    /// Liquidity weighted price of `token` over its pools against `quotes`,
+    /// pairs of a quote token and its USD price
+    fn price_against(
+        &self,
+        token: H160,
+        quotes: &[(H160, f64)],
+        at: PricePoint,
+    ) -> Result<Option<DexPrice>> {
+        let Some(token_decimals) = self.decimals(token) else {
+            return Ok(None);
+        };
+        let mut weighted = 0.0;
+        let mut total_liquidity = 0.0;
+        let mut deepest: Option<(f64, H160, u32)> = None;
+        for pool in self.tables.dex_pools.select_token_pools(token)? {
+            let (token0, token1) = (
+                H160::from(pool.token0.clone()),
+                H160::from(pool.token1.clone()),
+            );
+            let quote = if token0 == token { token1 } else { token0 };
+            let Some(&(_, quote_usd)) = quotes.iter().find(|(address, _)| *address == quote) else {
+                continue;
+            };
+            let Some(quote_decimals) = self.decimals(quote) else {
+                continue;
+            };
+            let Some((reserve0, reserve1, block_number)) = self.reserves_at(&pool, at) else {
+                continue;
+            };
+            let (token_reserve, quote_reserve) = if token0 == token {
+                (reserve0, reserve1)
+            } else {
+                (reserve1, reserve0)
+            };
+            let token_amount: f64 = format_units(token_reserve, token_decimals)?.parse()?;
+            let quote_amount: f64 = format_units(quote_reserve, quote_decimals)?.parse()?;
+            let liquidity_usd = 2.0 * quote_amount * quote_usd;
+            if token_amount <= 0.0 || liquidity_usd < self.min_liquidity_usd {
+                continue;
+            }
+            weighted += quote_amount / token_amount * quote_usd * liquidity_usd;
+            total_liquidity += liquidity_usd;
+            if deepest.is_none_or(|(liquidity, ..)| liquidity_usd > liquidity) {
+                deepest = Some((liquidity_usd, pool.address.clone().into(), block_number));
+            }
+        }
+        Ok(deepest.map(|(_, pool, block_number)| DexPrice {
+            price_usd: weighted / total_liquidity,
+            liquidity_usd: total_liquidity,
+            pool,
+            block_number,
+        }))
+    }
+
+    fn stable_quotes(&self) -> Vec<(H160, f64)> {
+        self.quotes
+            .stablecoins
+            .iter()
+            .map(|address| (*address, 1.0))
+            .collect()
+    }
+
+    /// USD price of the token at `at`, `None` without a pool deep enough
+    pub fn price_of(&self, token: H160, at: PricePoint) -> Result<Option<DexPrice>> {
+        let native = self.price_against(self.quotes.wrapped_native, &self.stable_quotes(), at)?;
+        if token == self.quotes.wrapped_native {
+            return Ok(native);
+        }
+        let mut quotes = self.stable_quotes();
+        if let Some(native) = &native {
+            quotes.push((self.quotes.wrapped_native, native.price_usd));
+        }
+        // a stablecoin is priced like any other token, through the others
+        quotes.retain(|(address, _)| *address != token);
+        self.price_against(token, &quotes, at)
+    }
+
+    fn resolve(&self, asset: &AssetId) -> Option<H160> {
+        match asset {
+            AssetId::Contract(chain, address) if *chain == self.chain => Some(*address),
+            AssetId::Cmc(id) if Some(*id) == self.native_cmc_id => Some(self.quotes.wrapped_native),
+            AssetId::Cmc(id) => self
+                .tables
+                .tokens
+                .select_all()
+                .execute()
+                .ok()?
+                .into_iter()
+                .find(|row| row.cmc_id == Some(*id))
+                .and_then(|row| H160::from_str(&row.token_contract_hash).ok()),
+            _ => None,
+        }
+    }
+
+    fn dex_price_of_asset(&self, asset: &AssetId, at: PricePoint) -> Result<Option<DexPrice>> {
+        match self.resolve(asset) {
+            Some(token) => self.price_of(token, at),
+            None => Ok(None),
+        }
+    }
+
+    fn price_of_asset(&self, asset: &AssetId, at: PricePoint) -> Result<Option<f64>> {
+        Ok(self
+            .dex_price_of_asset(asset, at)?
+            .map(|price| price.price_usd))
+    }
+}
+
+#[async_trait]
+impl AssetInfoClient for DexPriceOracle {
+    async fn get_usd_price_latest(&self, assets: &[AssetId]) -> Result<HashMap<AssetId, f64>> {
+        let mut result = HashMap::new();
+        for asset in assets {
+            if let Some(price) = self.price_of_asset(asset, PricePoint::Latest)? {
+                result.insert(asset.clone(), price);
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_usd_price_latest_with_liquidity(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, (f64, Option<f64>)>> {
+        let mut result = HashMap::new();
+        for asset in assets {
+            if let Some(price) = self.dex_price_of_asset(asset, PricePoint::Latest)? {
+                result.insert(asset.clone(), (price.price_usd, Some(price.liquidity_usd)));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_usd_price_period(
+        &self,
+        assets: &[AssetId],
+    ) -> Result<HashMap<AssetId, AssetPriceByPeriod>> {
+        let now = Utc::now();
+        let days_ago =
+            |days: i64| PricePoint::Timestamp((now - Duration::days(days)).timestamp() as u32);
+        let mut result = HashMap::new();
+        for asset in assets {
+            let Some(price_latest) = self.price_of_asset(asset, PricePoint::Latest)? else {
+                continue;
+            };
+            result.insert(
+                asset.clone(),
+                AssetPriceByPeriod {
+                    asset: asset.clone(),
+                    price_latest,
+                    price_1d: self.price_of_asset(asset, days_ago(1))?,
+                    price_7d: self.price_of_asset(asset, days_ago(7))?,
+                    price_30d: self.price_of_asset(asset, days_ago(30))?,
+                },
+            );
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::TokenRow;
+
+    const WETH: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+    const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+
+    fn address(address: &str) -> H160 {
+        H160::from_str(address).unwrap()
+    }
+
+    fn units(amount: u64, decimals: usize) -> U256 {
+        U256::from(amount) * U256::exp10(decimals)
+    }
+
+    fn insert_token(tables: &Tables, token: H160, decimals: u8) {
+        tables
+            .insert_token(TokenRow {
+                id: tables.tokens.get_next_pk().into(),
+                token_contract_hash: format!("{:?}", token),
+                name: String::new(),
+                symbol: String::new(),
+                decimals,
+                standard: EnumTokenStandard::Erc20 as u8,
+                total_supply: U256::zero().into(),
+                max_supply: 0,
+                cmc_id: None,
+                price_usd: 0.0,
+                onchain_cap: 0,
+                circulating_cap: 0,
+                transfers: String::new(),
+            })
+            .unwrap();
+    }
+
+    fn insert_pool(
+        tables: &Tables,
+        pool: H160,
+        token0: H160,
+        token1: H160,
+        reserves: (U256, U256),
+    ) {
+        tables
+            .dex_pools
+            .insert(DexPoolRow {
+                id: tables.dex_pools.get_next_pk().into(),
+                address: pool.into(),
+                protocol: EnumDexProtocol::UniswapV2 as u8,
+                token0: token0.into(),
+                token1: token1.into(),
+                fee: 0,
+                reserve0: reserves.0.into(),
+                reserve1: reserves.1.into(),
+                block_number: 1,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn converts_sqrt_price_to_virtual_reserves() {
+        // price 4, sqrt 2
+        let liquidity = U256::exp10(18);
+        let (reserve0, reserve1) = virtual_reserves(U256::from(2) << 96, liquidity);
+        assert_eq!(reserve0, liquidity / 2);
+        assert_eq!(reserve1, liquidity * 2);
+        assert_eq!(
+            virtual_reserves(U256::zero(), liquidity),
+            (U256::zero(), U256::zero())
+        );
+    }
+
+    #[test]
+    fn reads_reserves_from_sync_logs() {
+        let log = Log {
+            topics: vec![UNISWAP_V2_SYNC_EVENT_TOPIC],
+            data: encode(&[Token::Uint(100.into()), Token::Uint(200.into())]).into(),
+            ..Default::default()
+        };
+        assert_eq!(log_reserves(&log), Some((100.into(), 200.into())));
+    }
+
+    #[test]
+    fn prices_by_reserve_ratio_adjusted_for_decimals() {
+        let tables = Arc::new(Tables::default());
+        let (weth, usdc, token) = (address(WETH), address(USDC), H160::repeat_byte(0xee));
+        insert_token(&tables, weth, 18);
+        insert_token(&tables, usdc, 6);
+        insert_token(&tables, token, 8);
+        // 10M USDC against 5000 WETH, $2000 per WETH
+        insert_pool(
+            &tables,
+            H160::repeat_byte(1),
+            usdc,
+            weth,
+            (units(10_000_000, 6), units(5_000, 18)),
+        );
+        // 1000 WETH against 1M of the token, 0.001 WETH per token
+        insert_pool(
+            &tables,
+            H160::repeat_byte(2),
+            weth,
+            token,
+            (units(1_000, 18), units(1_000_000, 8)),
+        );
+        let oracle = DexPriceOracle::new(
+            tables,
+            Chain::Mainnet,
+            DexQuoteTokens::for_chain(Chain::Mainnet).unwrap(),
+            None,
+            DEFAULT_MIN_LIQUIDITY_USD,
+        );
+
+        let native = oracle.price_of(weth, PricePoint::Latest).unwrap().unwrap();
+        assert!((native.price_usd - 2000.0).abs() < 1e-6);
+        let price = oracle.price_of(token, PricePoint::Latest).unwrap().unwrap();
+        assert!((price.price_usd - 2.0).abs() < 1e-9);
+        assert_eq!(price.pool, H160::repeat_byte(2));
+    }
+}
@@ -0,0 +1,74 @@
+use ethers::types::Transaction;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::tables::{BlockRow, TokenTransferRow, TransactionRow, WhaleAlertRow};
+
+const EVENT_BUS_CAPACITY: usize = 16_384;
+
+/// Published by the ingester once the row is committed to its table
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    Block(BlockRow),
+    Transaction(TransactionRow),
+    TokenTransfer(TokenTransferRow),
+    /// Seen in the mempool, not stored in any table
+    PendingTransaction(Box<Transaction>),
+    /// Recorded by the whale alerter after the movement was ingested
+    WhaleAlert(WhaleAlertRow),
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<ChainEvent>>,
+    /// Listeners to pending transactions, counted apart from the receivers
+    /// as internal consumers hold theirs for the whole process
+    pending_listeners: Arc<AtomicUsize>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self {
+            sender,
+            pending_listeners: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// Counts as a listener to pending transactions until dropped
+#[derive(Debug)]
+pub struct PendingListener {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for PendingListener {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: ChainEvent) {
+        // no receivers is not an error, nobody is subscribed yet
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ChainEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Held by whoever forwards pending transactions, so the mempool is
+    /// only watched while it is
+    pub fn listen_pending(&self) -> PendingListener {
+        self.pending_listeners.fetch_add(1, Ordering::SeqCst);
+        PendingListener {
+            count: self.pending_listeners.clone(),
+        }
+    }
+
+    pub fn has_pending_listeners(&self) -> bool {
+        self.pending_listeners.load(Ordering::SeqCst) > 0
+    }
+}
@@ -0,0 +1,291 @@
+use ethers::types::{Block, Transaction, U256};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+
+use crate::api::EthersClient;
+use crate::events::{ChainEvent, EventBus};
+use crate::model::{GasPriceTier, GetGasOracleResponse};
+
+/// Number of recent blocks the tip percentiles are taken over
+pub const GAS_ORACLE_BLOCKS: usize = 20;
+const SLOW_PERCENTILE: usize = 20;
+const STANDARD_PERCENTILE: usize = 50;
+const FAST_PERCENTILE: usize = 90;
+/// The mempool only says something about the next blocks while we follow
+/// the head, not while backfilling history
+const MEMPOOL_MAX_BLOCK_AGE_S: u64 = 60;
+/// EIP-1559 `ELASTICITY_MULTIPLIER` and `BASE_FEE_MAX_CHANGE_DENOMINATOR`
+const ELASTICITY_MULTIPLIER: u64 = 2;
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+#[derive(Debug, Clone)]
+struct BlockSample {
+    number: u64,
+    timestamp_s: u64,
+    base_fee: U256,
+    gas_used: U256,
+    gas_limit: U256,
+    /// Effective tips of the included transactions, ascending
+    tips: Vec<U256>,
+}
+
+/// Slow, standard and fast priority fee estimates.
+///
+/// Each tier is the tip percentile over the last [`GAS_ORACLE_BLOCKS`]
+/// ingested blocks, raised to the lowest tip that still fits into the next
+/// one, two or four blocks when the pending pool holds more than that much
+/// gas.
+pub struct GasOracle {
+    api: Arc<EthersClient>,
+    samples: Mutex<VecDeque<BlockSample>>,
+    estimate: RwLock<Option<GetGasOracleResponse>>,
+}
+
+impl GasOracle {
+    pub fn new(api: Arc<EthersClient>) -> Self {
+        Self {
+            api,
+            samples: Mutex::new(VecDeque::with_capacity(GAS_ORACLE_BLOCKS + 1)),
+            estimate: RwLock::new(None),
+        }
+    }
+
+    /// Called by the ingester for every block, cheap enough for backfills
+    pub fn record_block(&self, block: &Block<Transaction>) {
+        let Some(base_fee) = block.base_fee_per_gas else {
+            // pre-London blocks have no tips to speak of
+            return;
+        };
+        let mut tips: Vec<U256> = block
+            .transactions
+            .iter()
+            .filter_map(|tx| effective_tip(tx, base_fee))
+            .collect();
+        tips.sort();
+        let sample = BlockSample {
+            number: block.number.unwrap_or_default().as_u64(),
+            timestamp_s: block.timestamp.low_u64(),
+            base_fee,
+            gas_used: block.gas_used,
+            gas_limit: block.gas_limit,
+            tips,
+        };
+        let mut samples = self.samples.lock().unwrap();
+        if samples.back().is_some_and(|last| last.number >= sample.number) {
+            samples.clear();
+        }
+        samples.push_back(sample);
+        while samples.len() > GAS_ORACLE_BLOCKS {
+            samples.pop_front();
+        }
+    }
+
+    pub fn estimate(&self) -> Option<GetGasOracleResponse> {
+        self.estimate.read().unwrap().clone()
+    }
+
+    /// Recomputes the estimate from the recorded blocks and, near the
+    /// head, the pending pool
+    pub async fn refresh(&self) {
+        let samples: Vec<BlockSample> = self.samples.lock().unwrap().iter().cloned().collect();
+        let Some(latest) = samples.last() else {
+            return;
+        };
+        let next_base_fee = next_base_fee(latest);
+
+        let now_s = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut pending = vec![];
+        if now_s.saturating_sub(latest.timestamp_s) <= MEMPOOL_MAX_BLOCK_AGE_S {
+            match self.api.get_txpool_content().await {
+                Ok(content) => {
+                    pending = content
+                        .pending
+                        .into_values()
+                        .flat_map(|txs| txs.into_values())
+                        .filter_map(|tx| Some((effective_tip(&tx, next_base_fee)?, tx.gas)))
+                        .collect();
+                }
+                Err(e) => warn!("Failed to fetch txpool content: {:?}", e),
+            }
+        }
+        // highest paying first, the order a builder would fill the block in
+        pending.sort_by_key(|(tip, _)| std::cmp::Reverse(*tip));
+
+        let mut tips: Vec<U256> = samples
+            .iter()
+            .flat_map(|sample| sample.tips.iter().copied())
+            .collect();
+        tips.sort();
+        let tier = |percentile: usize, blocks: u64| {
+            let historical = percentile_of(&tips, percentile);
+            let clearing = clearing_tip(&pending, latest.gas_limit * blocks);
+            let priority_fee = historical.max(clearing);
+            GasPriceTier {
+                priority_fee,
+                max_fee: next_base_fee * 2 + priority_fee,
+            }
+        };
+
+        let estimate = GetGasOracleResponse {
+            block_number: latest.number,
+            base_fee: latest.base_fee,
+            next_base_fee,
+            slow: tier(SLOW_PERCENTILE, 4),
+            standard: tier(STANDARD_PERCENTILE, 2),
+            fast: tier(FAST_PERCENTILE, 1),
+            sampled_blocks: samples.len() as u32,
+            sampled_transactions: tips.len() as u32,
+            sampled_pending: pending.len() as u32,
+        };
+        *self.estimate.write().unwrap() = Some(estimate);
+    }
+
+    /// Refreshes the estimate every time the ingester commits a block
+    pub async fn refresh_on_blocks(self: Arc<Self>, events: EventBus) {
+        let mut events = events.subscribe();
+        loop {
+            match events.recv().await {
+                Ok(event) if matches!(*event, ChainEvent::Block(_)) => self.refresh().await,
+                Ok(_) => {}
+                // whatever was missed, the samples are current
+                Err(RecvError::Lagged(_)) => self.refresh().await,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// What the block producer receives per gas, `None` if the transaction
+/// can't pay `base_fee`
+fn effective_tip(tx: &Transaction, base_fee: U256) -> Option<U256> {
+    match (tx.max_fee_per_gas, tx.max_priority_fee_per_gas) {
+        (Some(max_fee), Some(max_priority_fee)) => {
+            Some(max_priority_fee.min(max_fee.checked_sub(base_fee)?))
+        }
+        _ => tx.gas_price?.checked_sub(base_fee),
+    }
+}
+
+fn percentile_of(sorted: &[U256], percentile: usize) -> U256 {
+    if sorted.is_empty() {
+        return U256::zero();
+    }
+    sorted[(sorted.len() - 1) * percentile / 100]
+}
+
+/// Lowest tip among the pending transactions that fill `capacity`, zero if
+/// everything pending fits
+fn clearing_tip(pending: &[(U256, U256)], capacity: U256) -> U256 {
+    let mut gas = U256::zero();
+    for (tip, tx_gas) in pending {
+        gas = gas.saturating_add(*tx_gas);
+        if gas > capacity {
+            return *tip;
+        }
+    }
+    U256::zero()
+}
+
+/// EIP-1559 base fee of the block following `parent`
+fn next_base_fee(parent: &BlockSample) -> U256 {
+    let target = parent.gas_limit / ELASTICITY_MULTIPLIER;
+    if target.is_zero() || parent.gas_used == target {
+        return parent.base_fee;
+    }
+    if parent.gas_used > target {
+        let delta = parent.base_fee * (parent.gas_used - target)
+            / target
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        parent.base_fee + delta.max(U256::one())
+    } else {
+        let delta = parent.base_fee * (target - parent.gas_used)
+            / target
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        parent.base_fee.saturating_sub(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn parent(base_fee: u64, gas_used: u64) -> BlockSample {
+        BlockSample {
+            number: 1,
+            timestamp_s: 0,
+            base_fee: base_fee.into(),
+            gas_used: gas_used.into(),
+            gas_limit: 30_000_000.into(),
+            tips: vec![],
+        }
+    }
+
+    #[test]
+    fn follows_eip1559_base_fee_changes() {
+        // (base fee, gas used, next base fee)
+        let cases = [
+            // full block, up 12.5%
+            (100 * GWEI, 30_000_000, 112_500_000_000),
+            // empty block, down 12.5%
+            (100 * GWEI, 0, 87_500_000_000),
+            // at target
+            (100 * GWEI, 15_000_000, 100 * GWEI),
+            // above target by a fifth of the target, up 2.5%
+            (100 * GWEI, 18_000_000, 102_500_000_000),
+            // the increase is at least 1 wei
+            (1, 30_000_000, 2),
+            (0, 0, 0),
+        ];
+        for (base_fee, gas_used, expected) in cases {
+            assert_eq!(
+                next_base_fee(&parent(base_fee, gas_used)),
+                U256::from(expected),
+                "base fee {} with {} gas used",
+                base_fee,
+                gas_used
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_base_fee_without_gas_limit() {
+        let mut block = parent(100 * GWEI, 0);
+        block.gas_limit = U256::zero();
+        assert_eq!(next_base_fee(&block), U256::from(100 * GWEI));
+    }
+
+    #[test]
+    fn rounds_percentiles_down_to_a_sample() {
+        let tips: Vec<U256> = (1..=10u64).map(U256::from).collect();
+        // (percentile, tip)
+        let cases = [(0, 1), (20, 2), (50, 5), (89, 9), (90, 9), (99, 9), (100, 10)];
+        for (percentile, expected) in cases {
+            assert_eq!(
+                percentile_of(&tips, percentile),
+                U256::from(expected),
+                "percentile {}",
+                percentile
+            );
+        }
+        assert_eq!(percentile_of(&tips[..1], 90), U256::one());
+        assert_eq!(percentile_of(&[], 50), U256::zero());
+    }
+
+    #[test]
+    fn clears_at_the_tip_overflowing_capacity() {
+        let pending: Vec<(U256, U256)> = [(30, 10), (20, 10), (10, 10)]
+            .into_iter()
+            .map(|(tip, gas)| (U256::from(tip), U256::from(gas)))
+            .collect();
+        assert_eq!(clearing_tip(&pending, 15.into()), U256::from(20));
+        assert_eq!(clearing_tip(&pending, 30.into()), U256::zero());
+    }
+}
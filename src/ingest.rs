@@ -0,0 +1,288 @@
+use ethers::prelude::*;
+use eyre::*;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+use crate::api::{
+    EthersClient, BEACON_UPGRADED_EVENT_TOPIC, TRANSFER_EVENT_TOPIC, UPGRADED_EVENT_TOPIC,
+};
+use crate::bytecode::analyze_contract;
+use crate::dex::{is_dex_log, DexFactories, DexPoolTracker, DEX_EVENT_TOPICS};
+use crate::events::{ChainEvent, EventBus};
+use crate::gas::GasOracle;
+use crate::model::EnumTokenStandard;
+use crate::proxy::record_upgrade;
+use crate::rkyv_wrappers::WrappedU256;
+use crate::stats::NetworkStatsAggregator;
+use crate::tables::{BlockRow, Tables, TokenTransferRow, TransactionLogRow, TransactionRow};
+use crate::tokens::detect_token;
+
+pub struct Ingester {
+    api: Arc<EthersClient>,
+    tables: Arc<Tables>,
+    events: EventBus,
+    stats: NetworkStatsAggregator,
+    gas_oracle: Arc<GasOracle>,
+    /// `Transfer` emitters that turned out not to be tokens, so they aren't
+    /// probed again on every transfer
+    non_tokens: Mutex<HashSet<Address>>,
+    /// Set to record DEX pool states for on-chain prices
+    dex_pools: Option<DexPoolTracker>,
+    current_tx_id: AtomicU32,
+}
+
+impl Ingester {
+    pub fn new(api: Arc<EthersClient>, tables: Arc<Tables>, events: EventBus) -> Self {
+        Self {
+            gas_oracle: Arc::new(GasOracle::new(api.clone())),
+            api,
+            stats: NetworkStatsAggregator::new(tables.clone()),
+            tables,
+            events,
+            non_tokens: Mutex::new(HashSet::new()),
+            dex_pools: None,
+            current_tx_id: AtomicU32::new(0),
+        }
+    }
+
+    /// Also records the states of the factories' Uniswap V2 and V3 pools, a
+    /// few calls per new pool
+    pub fn with_dex_pools(mut self, factories: DexFactories) -> Self {
+        self.dex_pools = Some(DexPoolTracker::new(
+            self.api.clone(),
+            self.tables.clone(),
+            factories,
+        ));
+        self
+    }
+
+    pub fn gas_oracle(&self) -> Arc<GasOracle> {
+        self.gas_oracle.clone()
+    }
+
+    /// Fetches a block and inserts its transactions and token transfers.
+    ///
+    /// Events are published after all rows of the block are committed, so a
+    /// subscriber can read any row it is notified about.
+    pub async fn ingest_block(&self, block_number: u32) -> Result<usize> {
+        let block = self
+            .api
+            .get_block_with_txs(BlockId::from(block_number as u64))
+            .await?
+            .with_context(|| format!("Block {} not found", block_number))?;
+        let mut topics = vec![
+            TRANSFER_EVENT_TOPIC,
+            UPGRADED_EVENT_TOPIC,
+            BEACON_UPGRADED_EVENT_TOPIC,
+        ];
+        if self.dex_pools.is_some() {
+            topics.extend(DEX_EVENT_TOPICS);
+        }
+        let logs = self.api.get_block_logs(block_number as u64, topics).await?;
+        let (dex_logs, logs): (Vec<_>, Vec<_>) = logs.into_iter().partition(is_dex_log);
+        let (transfer_logs, upgrade_logs): (Vec<_>, Vec<_>) = logs
+            .into_iter()
+            .partition(|log| log.topics.first() == Some(&TRANSFER_EVENT_TOPIC));
+        let timestamp_s = block.timestamp.as_u32();
+
+        let receipts = self.api.get_block_receipts(&block).await?;
+
+        let mut tx_rows = Vec::with_capacity(block.transactions.len());
+        for (tx, receipt) in block.transactions.iter().zip(&receipts) {
+            let gas_used = receipt.gas_used.unwrap_or_default();
+            let gas_price = receipt.effective_gas_price.or(tx.gas_price);
+            let row = TransactionRow {
+                id: self.current_tx_id.fetch_add(1, Ordering::SeqCst),
+                hash: tx.hash.into(),
+                status: receipt.status.map_or(1, |status| status.as_u32() as u8),
+                block_number,
+                timestamp_s,
+                from_address: tx.from.into(),
+                to_address: tx.to.map(|address| address.into()),
+                //internal_transactions: "".to_string(), //TODO: this needs to be fetched
+                value: tx.value.into(),
+                fee: gas_used.saturating_mul(gas_price.unwrap_or_default()).into(),
+                gas: gas_price.map(|gas_price| gas_price.into()),
+                nonce: tx.nonce.low_u64(),
+                gas_limit: tx.gas.low_u64(),
+                gas_used: gas_used.low_u64(),
+                input: tx.input.to_vec(),
+                value_usd_cents: None,
+                fee_usd_cents: None,
+            };
+            self.tables.insert_transaction(row.clone())?;
+            tx_rows.push(row);
+            for log in &receipt.logs {
+                self.tables.transaction_logs.insert(TransactionLogRow {
+                    id: self.tables.transaction_logs.get_next_pk().into(),
+                    tx_hash: tx.hash.into(),
+                    log_index: log.log_index.unwrap_or_default().as_u32(),
+                    block_number,
+                    address: log.address.into(),
+                    topics: log.topics.iter().map(|topic| topic.0).collect(),
+                    data: log.data.to_vec(),
+                })?;
+            }
+        }
+
+        let mut transfer_rows = Vec::with_capacity(transfer_logs.len());
+        for log in &transfer_logs {
+            let Some(row) = self.token_transfer_row(log, block_number, timestamp_s) else {
+                continue;
+            };
+            self.tables.insert_token_transfer(row.clone())?;
+            self.tables.token_balances.apply_transfer(&row).await?;
+            transfer_rows.push(row);
+        }
+
+        let block_row = BlockRow {
+            id: self.tables.blocks.get_next_pk().into(),
+            number: block_number,
+            hash: block.hash.unwrap_or_default().into(),
+            status: 1u8,
+            timestamp_s,
+            gas_used: block.gas_used.low_u64(),
+            gas_limit: block.gas_limit.low_u64(),
+            transactions: tx_rows.iter().map(|row| row.id).collect(),
+            eth_price_usd_cents: 0, //TODO: use cmc lookup here
+        };
+        self.tables.blocks.insert(block_row.clone())?;
+        self.tables.ingested.record(block_number);
+        self.stats.record_block(&block_row, &tx_rows).await?;
+        self.gas_oracle.record_block(&block);
+        for log in &upgrade_logs {
+            if let Err(e) = record_upgrade(&self.api, &self.tables, log).await {
+                warn!(
+                    "Failed to follow proxy upgrade in {:?}: {:?}",
+                    log.transaction_hash, e
+                );
+            }
+        }
+        for tx in block.transactions.iter().filter(|tx| tx.to.is_none()) {
+            if let Err(e) = self.analyze_created_contract(tx).await {
+                warn!("Failed to analyze contract created by {:?}: {:?}", tx.hash, e);
+            }
+        }
+        // after contract creations, so a token minting in its constructor is
+        // analyzed with its creator
+        for log in &transfer_logs {
+            if let Err(e) = self.detect_transfer_token(log).await {
+                warn!("Failed to detect token {:?}: {:?}", log.address, e);
+            }
+        }
+        if let Some(dex_pools) = &self.dex_pools {
+            if let Err(e) = dex_pools
+                .record_block(&dex_logs, block_number, timestamp_s)
+                .await
+            {
+                warn!(
+                    "Failed to record DEX pools of block {}: {:?}",
+                    block_number, e
+                );
+            }
+        }
+
+        let num_transactions = tx_rows.len();
+        for row in tx_rows {
+            self.events.publish(ChainEvent::Transaction(row));
+        }
+        for row in transfer_rows {
+            self.events.publish(ChainEvent::TokenTransfer(row));
+        }
+        self.events.publish(ChainEvent::Block(block_row));
+        Ok(num_transactions)
+    }
+
+    async fn analyze_created_contract(&self, tx: &Transaction) -> Result<()> {
+        let receipt = self.api.get_transaction_receipt(tx.hash).await?;
+        let Some(address) = receipt.contract_address else {
+            // the deployment reverted
+            return Ok(());
+        };
+        analyze_contract(&self.api, &self.tables, address, Some(tx.from)).await?;
+        detect_token(&self.api, &self.tables, address, None).await?;
+        Ok(())
+    }
+
+    async fn detect_transfer_token(&self, log: &Log) -> Result<()> {
+        let address = log.address;
+        if self.non_tokens.lock().unwrap().contains(&address)
+            || self.tables.tokens.select_token(address).is_some()
+        {
+            return Ok(());
+        }
+        let hint = match log.topics.len() {
+            3 => EnumTokenStandard::Erc20,
+            _ => EnumTokenStandard::Erc721,
+        };
+        if detect_token(&self.api, &self.tables, address, Some(hint))
+            .await?
+            .is_none()
+        {
+            self.non_tokens.lock().unwrap().insert(address);
+        }
+        Ok(())
+    }
+
+    /// Decodes ERC-20 `Transfer(from, to, value)` and ERC-721
+    /// `Transfer(from, to, tokenId)`, which share the topic but not the layout
+    fn token_transfer_row(
+        &self,
+        log: &Log,
+        block_number: u32,
+        timestamp_s: u32,
+    ) -> Option<TokenTransferRow> {
+        let from = Address::from(*log.topics.get(1)?);
+        let to = Address::from(*log.topics.get(2)?);
+        let (value, token_id) = match log.topics.len() {
+            3 if log.data.len() == 32 => (U256::from_big_endian(&log.data), None),
+            4 => (U256::one(), Some(U256::from_big_endian(log.topics[3].as_bytes()))),
+            _ => return None,
+        };
+        Some(TokenTransferRow {
+            id: self.tables.token_transfers.get_next_pk().into(),
+            tx_hash: log.transaction_hash?.into(),
+            log_index: log.log_index?.as_u32(),
+            block_number,
+            timestamp_s,
+            token_address: log.address.into(),
+            from_address: from.into(),
+            to_address: to.into(),
+            value: value.into(),
+            token_id: token_id.map(WrappedU256::from),
+            value_usd_cents: None,
+        })
+    }
+
+    /// Publishes transactions entering the mempool while anyone listens to
+    /// pending transactions
+    pub async fn watch_mempool(self: Arc<Self>, interval: Duration) {
+        let mut known = HashSet::new();
+        loop {
+            tokio::time::sleep(interval).await;
+            if !self.events.has_pending_listeners() {
+                known.clear();
+                continue;
+            }
+            let content = match self.api.get_txpool_content().await {
+                Result::Ok(content) => content,
+                Err(e) => {
+                    warn!("Failed to fetch txpool content: {:?}", e);
+                    continue;
+                }
+            };
+            let mut current = HashSet::with_capacity(known.len());
+            for tx in content.pending.into_values().flat_map(|txs| txs.into_values()) {
+                current.insert(tx.hash);
+                if !known.contains(&tx.hash) {
+                    self.events
+                        .publish(ChainEvent::PendingTransaction(Box::new(tx)));
+                }
+            }
+            known = current;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use ethers::types::H160;
+use eyre::*;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::model::{
+    AddressLabelView, EnumLabelCategory, ImportAddressLabelsResponse, LabelListFormat,
+};
+use crate::rkyv_wrappers::WrappedAddress;
+use crate::tables::{AddressLabelRow, AddressLabelWorkTable};
+
+/// One entry of a label list, the same shape for CSV rows and JSON objects
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabelRecord {
+    pub address: H160,
+    pub label: String,
+    pub category: EnumLabelCategory,
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl TryFrom<AddressLabelRow> for AddressLabelView {
+    type Error = Error;
+
+    fn try_from(row: AddressLabelRow) -> Result<Self> {
+        Ok(Self {
+            id: row.id,
+            label: row.label,
+            category: row.category.parse()?,
+            source: row.source,
+        })
+    }
+}
+
+impl AddressLabelWorkTable {
+    pub fn labels_of(&self, address: WrappedAddress) -> Vec<AddressLabelRow> {
+        self.select_by_address(address)
+            .map(|rows| rows.execute())
+            .unwrap_or_default()
+    }
+
+    pub fn label_views_of(&self, address: WrappedAddress) -> Vec<AddressLabelView> {
+        self.labels_of(address)
+            .into_iter()
+            .filter_map(|row| AddressLabelView::try_from(row).ok())
+            .collect()
+    }
+
+    /// Returns `None` if the address already carries the same label
+    pub fn add_label(&self, record: LabelRecord, default_source: &str) -> Result<Option<u64>> {
+        let address = WrappedAddress::from(record.address);
+        let label = record.label.trim();
+        ensure!(!label.is_empty(), "label must not be empty");
+        if self
+            .labels_of(address.clone())
+            .iter()
+            .any(|row| row.label.eq_ignore_ascii_case(label))
+        {
+            return Ok(None);
+        }
+
+        let id = self.get_next_pk().into();
+        self.insert(AddressLabelRow {
+            id,
+            address,
+            label: label.to_string(),
+            category: record.category.to_string(),
+            source: record.source.unwrap_or_else(|| default_source.to_string()),
+        })?;
+        Ok(Some(id))
+    }
+
+    pub async fn remove_label(&self, id: u64) -> Result<bool> {
+        if self.select(id.into()).is_none() {
+            return Ok(false);
+        }
+        self.delete(id.into()).await?;
+        Ok(true)
+    }
+
+    /// Every record is validated before the first is inserted, so a bad one
+    /// doesn't leave a partial import
+    pub fn import_labels(
+        &self,
+        records: Vec<LabelRecord>,
+        default_source: &str,
+    ) -> Result<ImportAddressLabelsResponse> {
+        validate_labels(&records)?;
+        let mut summary = ImportAddressLabelsResponse::default();
+        for record in records {
+            match self.add_label(record, default_source)? {
+                Some(_) => summary.added += 1,
+                None => summary.duplicates += 1,
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Checks what `add_label` would reject, naming the first bad entry
+pub fn validate_labels(records: &[LabelRecord]) -> Result<()> {
+    for (i, record) in records.iter().enumerate() {
+        ensure!(
+            !record.label.trim().is_empty(),
+            "entry {} ({:?}) has an empty label",
+            i + 1,
+            record.address
+        );
+    }
+    Ok(())
+}
+
+/// CSV with a header row of `address,label,category` and an optional `source`
+pub fn parse_csv_labels(data: &str) -> Result<Vec<LabelRecord>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    // without a header the first entry would be taken for one and dropped
+    let headers = reader.headers()?;
+    for column in ["address", "label", "category"] {
+        ensure!(
+            headers.iter().any(|header| header == column),
+            "label list header has no {} column",
+            column
+        );
+    }
+    let mut records = vec![];
+    for (i, record) in reader.deserialize().enumerate() {
+        // line 1 is the header
+        records.push(record.with_context(|| format!("invalid label on line {}", i + 2))?);
+    }
+    Ok(records)
+}
+
+/// JSON array of `{"address", "label", "category", "source"?}` objects
+pub fn parse_json_labels(data: &str) -> Result<Vec<LabelRecord>> {
+    let jd = &mut serde_json::Deserializer::from_str(data);
+    Ok(serde_path_to_error::deserialize(jd)?)
+}
+
+/// Parses and validates a whole label list
+pub fn parse_labels(format: LabelListFormat, data: &str) -> Result<Vec<LabelRecord>> {
+    let records = match format {
+        LabelListFormat::Csv => parse_csv_labels(data)?,
+        LabelListFormat::Json => parse_json_labels(data)?,
+    };
+    validate_labels(&records)?;
+    Ok(records)
+}
+
+/// Imports a label list file, the format is picked by its extension and the
+/// file name is used as source for entries without one
+pub fn import_labels_file(
+    table: &AddressLabelWorkTable,
+    path: &Path,
+) -> Result<ImportAddressLabelsResponse> {
+    let format = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => LabelListFormat::Csv,
+        Some("json") => LabelListFormat::Json,
+        _ => bail!("unsupported label list {}, expected .csv or .json", path.display()),
+    };
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let source = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("import");
+    table.import_labels(parse_labels(format, &data)?, source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const BINANCE: &str = "0x28c6c06298d514db089934071355e5743bf21d60";
+
+    #[test]
+    fn parses_csv_with_header() {
+        let data = format!(
+            "address,label,category,source\n\
+             {BINANCE}, Binance 14 ,exchange,\n\
+             {BINANCE},Binance hot wallet,exchange,etherscan\n"
+        );
+        let records = parse_labels(LabelListFormat::Csv, &data).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].address, H160::from_str(BINANCE).unwrap());
+        assert_eq!(records[0].label, "Binance 14");
+        assert_eq!(records[0].category, EnumLabelCategory::Exchange);
+        assert_eq!(records[1].source.as_deref(), Some("etherscan"));
+    }
+
+    #[test]
+    fn rejects_csv_without_header() {
+        let data = format!("{BINANCE},Binance 14,exchange\n");
+        assert!(parse_csv_labels(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_csv_rows() {
+        let header = "address,label,category\n";
+        for row in [
+            "0x1234,Short address,exchange".to_string(),
+            format!("{BINANCE},Binance 14,casino"),
+            format!("{BINANCE},Binance 14"),
+            // a header repeated inside the list
+            "address,label,category".to_string(),
+        ] {
+            let err = parse_csv_labels(&format!("{header}{row}\n")).unwrap_err();
+            assert!(err.to_string().contains("line 2"), "{row}: {err}");
+        }
+        let data = format!("{header}{BINANCE}, ,exchange\n");
+        assert!(parse_labels(LabelListFormat::Csv, &data).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for data in [
+            "{}".to_string(),
+            format!(r#"[{{"address": "{BINANCE}", "label": "Binance 14"}}]"#),
+            format!(r#"[{{"address": "{BINANCE}", "label": " ", "category": "exchange"}}]"#),
+        ] {
+            assert!(parse_labels(LabelListFormat::Json, &data).is_err(), "{data}");
+        }
+    }
+
+    #[test]
+    fn counts_duplicate_labels() {
+        let table = AddressLabelWorkTable::default();
+        let data = format!(
+            "address,label,category\n\
+             {BINANCE},Binance 14,exchange\n\
+             {BINANCE},binance 14,exchange\n\
+             {BINANCE},Binance hot wallet,exchange\n"
+        );
+        let records = parse_labels(LabelListFormat::Csv, &data).unwrap();
+        let summary = table.import_labels(records, "test").unwrap();
+        assert_eq!((summary.added, summary.duplicates), (2, 1));
+        let labels = table.labels_of(H160::from_str(BINANCE).unwrap().into());
+        assert!(labels.iter().all(|row| row.source == "test"));
+    }
+}
@@ -6,6 +6,26 @@ use tokio::sync::Mutex;
 pub mod tables;
 pub mod api;
 pub mod rkyv_wrappers;
+pub mod model;
+pub mod shared_method;
+pub mod server;
+pub mod labels;
+pub mod events;
+pub mod ingest;
+pub mod search;
+pub mod stats;
+pub mod gas;
+pub mod alerts;
+pub mod signatures;
+pub mod contract_abi;
+pub mod bytecode;
+pub mod proxy;
+pub mod tokens;
+pub mod token_prices;
+pub mod portfolio;
+pub mod price_history;
+pub mod valuation;
+pub mod dex;
 
 pub async fn check_memory_usage(sys: Arc<Mutex<System>>) {
     let mut sys = sys.lock().await;
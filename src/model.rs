@@ -0,0 +1,815 @@
+use endpoint_libs::libs::toolbox::ErrorCode;
+use ethers::types::{Bytes, H160, H256, U256};
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::tables::TransactionCursor;
+
+/// Generates a fieldless `#[repr(u32)]` enum together with its lowercase
+/// string form, `FromPrimitive` and `FromStr`, so roles and error codes can
+/// travel as plain numbers in `RequestContext` and `CustomError`.
+macro_rules! model_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $( $(#[doc = $doc:literal])* $variant:ident = $value:literal => $text:literal, )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        #[repr(u32)]
+        pub enum $name {
+            $( $(#[doc = $doc])* $variant = $value, )*
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant),*];
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $( $name::$variant => $text, )*
+                }
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = eyre::Error;
+
+            fn from_str(s: &str) -> eyre::Result<Self> {
+                match s {
+                    $( $text => Ok($name::$variant), )*
+                    _ => eyre::bail!("invalid {}: {}", stringify!($name), s),
+                }
+            }
+        }
+
+        impl FromPrimitive for $name {
+            fn from_i64(n: i64) -> Option<Self> {
+                u64::try_from(n).ok().and_then(Self::from_u64)
+            }
+
+            fn from_u64(n: u64) -> Option<Self> {
+                match n {
+                    $( $value => Some($name::$variant), )*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+model_enum! {
+    /// Roles are ordered, a caller may use every endpoint whose minimum role is
+    /// less or equal to its own.
+    pub enum EnumRole {
+        /// Anonymous caller without an API key
+        Public = 0 => "public",
+        User = 1 => "user",
+        Admin = 2 => "admin",
+        Developer = 3 => "developer",
+    }
+}
+
+model_enum! {
+    pub enum EnumErrorCode {
+        /// Request could not be parsed
+        BadRequest = 100400 => "bad_request",
+        /// API key is not known
+        Unauthorized = 100401 => "unauthorized",
+        /// Caller's role is below the endpoint's minimum role
+        InvalidRole = 100403 => "invalid_role",
+        NotFound = 100404 => "not_found",
+        UnknownMethod = 100405 => "unknown_method",
+        InternalError = 100500 => "internal_error",
+    }
+}
+
+model_enum! {
+    pub enum EnumLabelCategory {
+        Exchange = 1 => "exchange",
+        Bridge = 2 => "bridge",
+        Mixer = 3 => "mixer",
+        Protocol = 4 => "protocol",
+        Scam = 5 => "scam",
+    }
+}
+
+model_enum! {
+    /// Stats bucket size, the value is its length in seconds
+    pub enum EnumStatsPeriod {
+        Hourly = 3600 => "hourly",
+        Daily = 86400 => "daily",
+    }
+}
+
+model_enum! {
+    pub enum EnumAlertKind {
+        /// Top-level ETH value of a transaction
+        Transaction = 1 => "transaction",
+        /// Largest ETH movement inside a transaction's call trace
+        InternalTransfer = 2 => "internal_transfer",
+        TokenTransfer = 3 => "token_transfer",
+    }
+}
+
+model_enum! {
+    /// Direction of a movement relative to addresses labelled `exchange`
+    pub enum EnumExchangeFlow {
+        None = 0 => "none",
+        /// Into an exchange from elsewhere
+        Inflow = 1 => "inflow",
+        /// Out of an exchange to elsewhere
+        Outflow = 2 => "outflow",
+        /// Between two exchange addresses
+        Internal = 3 => "internal",
+    }
+}
+
+model_enum! {
+    /// How a proxy finds the contract it delegates to
+    pub enum EnumProxyKind {
+        /// Minimal proxy with the target in its bytecode
+        Eip1167 = 1 => "eip1167",
+        /// Implementation in the EIP-1967 storage slot
+        Eip1967 = 2 => "eip1967",
+        /// Beacon in the EIP-1967 beacon slot, which returns the implementation
+        Eip1967Beacon = 3 => "eip1967_beacon",
+        /// `org.zeppelinos.proxy.implementation` slot
+        OpenZeppelinLegacy = 4 => "openzeppelin_legacy",
+        /// Master copy in storage slot 0
+        GnosisSafe = 5 => "gnosis_safe",
+    }
+}
+
+model_enum! {
+    /// Interface a token contract was detected to implement
+    pub enum EnumTokenStandard {
+        Erc20 = 1 => "erc20",
+        Erc721 = 2 => "erc721",
+        Erc1155 = 3 => "erc1155",
+    }
+}
+
+model_enum! {
+    pub enum EnumDexProtocol {
+        UniswapV2 = 1 => "uniswap_v2",
+        UniswapV3 = 2 => "uniswap_v3",
+    }
+}
+
+impl From<EnumErrorCode> for ErrorCode {
+    fn from(code: EnumErrorCode) -> Self {
+        ErrorCode::new(code as u32)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionView {
+    pub id: u32,
+    pub hash: H256,
+    pub status: u8,
+    pub block_number: u32,
+    pub timestamp_s: u32,
+    pub from_address: H160,
+    pub to_address: Option<H160>,
+    pub value: U256,
+    pub fee: U256,
+    /// At the time of the block, `None` until valued
+    pub value_usd: Option<f64>,
+    pub fee_usd: Option<f64>,
+    pub gas_price: Option<U256>,
+    pub input: Bytes,
+    /// Name of the called function, if its selector is known
+    pub method: Option<String>,
+    pub decoded_input: Option<DecodedCall>,
+    pub from_labels: Vec<AddressLabelView>,
+    pub to_labels: Vec<AddressLabelView>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedCall {
+    pub name: String,
+    /// Canonical signature, e.g. `transfer(address,uint256)`
+    pub signature: String,
+    pub arguments: Vec<DecodedArgument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedLog {
+    pub name: String,
+    /// Canonical signature, e.g. `Transfer(address,address,uint256)`
+    pub signature: String,
+    /// Indexed dynamic values are only available as their hash
+    pub arguments: Vec<DecodedArgument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedArgument {
+    /// Only known from a contract ABI, text signatures carry no names
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressLabelView {
+    pub id: u64,
+    pub label: String,
+    pub category: EnumLabelCategory,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockView {
+    pub number: u32,
+    pub hash: H256,
+    pub status: u8,
+    pub timestamp_s: u32,
+    pub transactions: Vec<u32>,
+    pub eth_price_usd_cents: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlockRequest {
+    pub number: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTransactionRequest {
+    pub hash: H256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressTransactionsRequest {
+    pub address: H160,
+    #[serde(default)]
+    pub cursor: Option<TransactionCursor>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressTransactionsResponse {
+    pub transactions: Vec<TransactionView>,
+    pub next_cursor: Option<TransactionCursor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressLabelsRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressLabelsResponse {
+    pub labels: Vec<AddressLabelView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddAddressLabelRequest {
+    pub address: H160,
+    pub label: String,
+    pub category: EnumLabelCategory,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddAddressLabelResponse {
+    /// `None` when the address already carried this label
+    pub id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveAddressLabelRequest {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveAddressLabelResponse {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelListFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportAddressLabelsRequest {
+    pub format: LabelListFormat,
+    pub data: String,
+    /// Used for entries that don't name their own source
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportAddressLabelsResponse {
+    pub added: u64,
+    pub duplicates: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenTransferView {
+    pub id: u64,
+    pub tx_hash: H256,
+    pub log_index: u32,
+    pub block_number: u32,
+    pub timestamp_s: u32,
+    pub token_address: H160,
+    pub from_address: H160,
+    pub to_address: H160,
+    pub value: U256,
+    pub token_id: Option<U256>,
+    /// At the time of the block, `None` for NFTs and unlisted tokens
+    pub value_usd: Option<f64>,
+    pub from_labels: Vec<AddressLabelView>,
+    pub to_labels: Vec<AddressLabelView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingTransactionView {
+    pub hash: H256,
+    pub nonce: U256,
+    pub from_address: H160,
+    pub to_address: Option<H160>,
+    pub value: U256,
+    pub gas: U256,
+    pub gas_price: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubscriptionTopic {
+    NewBlocks,
+    /// Transactions sent or received by the address
+    AddressTransactions { address: H160 },
+    /// Transfers of the token contract
+    TokenTransfers { token: H160 },
+    /// Mempool transactions sent or received by the address
+    PendingTransactions { address: H160 },
+    /// Whale alerts worth at least `min_usd`, on top of the server thresholds
+    WhaleAlerts {
+        #[serde(default)]
+        min_usd: Option<u64>,
+        #[serde(default)]
+        exchange_flows_only: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum WsClientMessage {
+    Subscribe {
+        seq: u32,
+        topic: SubscriptionTopic,
+    },
+    Unsubscribe {
+        seq: u32,
+        subscription_id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubscriptionEvent {
+    Block(BlockView),
+    Transaction(TransactionView),
+    TokenTransfer(TokenTransferView),
+    PendingTransaction(PendingTransactionView),
+    WhaleAlert(WhaleAlertView),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsServerMessage {
+    Subscribed {
+        seq: u32,
+        subscription_id: u32,
+    },
+    Unsubscribed {
+        seq: u32,
+        subscription_id: u32,
+    },
+    Error {
+        seq: Option<u32>,
+        code: u32,
+        reason: String,
+    },
+    Event {
+        subscription_id: u32,
+        event: Box<SubscriptionEvent>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchRequest {
+    pub query: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchResult {
+    Block {
+        number: u32,
+        hash: H256,
+    },
+    Transaction {
+        hash: H256,
+        block_number: u32,
+    },
+    Address {
+        address: H160,
+        labels: Vec<AddressLabelView>,
+    },
+    Token {
+        /// `None` for coins without a contract on the indexed chain
+        address: Option<H160>,
+        symbol: String,
+        name: String,
+        cmc_id: Option<u64>,
+    },
+    Label {
+        address: H160,
+        label: AddressLabelView,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RankedSearchResult {
+    pub score: u32,
+    pub result: SearchResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub results: Vec<RankedSearchResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetNetworkStatsRequest {
+    pub period: EnumStatsPeriod,
+    /// Defaults to 30 buckets before `to_s`
+    #[serde(default)]
+    pub from_s: Option<u32>,
+    /// Defaults to the latest ingested block
+    #[serde(default)]
+    pub to_s: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkStatsPoint {
+    pub bucket_start_s: u32,
+    pub block_count: u32,
+    pub tx_count: u64,
+    pub active_senders: u32,
+    pub active_receivers: u32,
+    pub gas_used: u64,
+    pub average_fee: U256,
+    pub total_value: U256,
+    pub new_contracts: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetNetworkStatsResponse {
+    pub period: EnumStatsPeriod,
+    /// Oldest first, buckets without blocks are left out
+    pub points: Vec<NetworkStatsPoint>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GetGasOracleRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GasPriceTier {
+    /// `maxPriorityFeePerGas` in wei
+    pub priority_fee: U256,
+    /// `maxFeePerGas` in wei, twice the next base fee plus the tip
+    pub max_fee: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetGasOracleResponse {
+    /// Latest block the estimate is based on
+    pub block_number: u64,
+    pub base_fee: U256,
+    pub next_base_fee: U256,
+    pub slow: GasPriceTier,
+    pub standard: GasPriceTier,
+    pub fast: GasPriceTier,
+    pub sampled_blocks: u32,
+    pub sampled_transactions: u32,
+    pub sampled_pending: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WhaleAlertView {
+    pub id: u64,
+    pub kind: EnumAlertKind,
+    pub tx_hash: H256,
+    pub block_number: u32,
+    pub timestamp_s: u32,
+    /// `None` for ETH
+    pub token_address: Option<H160>,
+    pub symbol: String,
+    pub from_address: H160,
+    pub to_address: H160,
+    /// In the token's smallest unit
+    pub amount: U256,
+    pub usd_value: f64,
+    pub exchange_flow: EnumExchangeFlow,
+    pub from_labels: Vec<AddressLabelView>,
+    pub to_labels: Vec<AddressLabelView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetWhaleAlertsRequest {
+    /// Only alerts sent or received by this address
+    #[serde(default)]
+    pub address: Option<H160>,
+    #[serde(default)]
+    pub exchange_flows_only: bool,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetWhaleAlertsResponse {
+    /// Newest first
+    pub alerts: Vec<WhaleAlertView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetContractAbiRequest {
+    pub address: H160,
+    /// The JSON ABI array as produced by solc
+    pub abi: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetContractAbiResponse {
+    pub functions: u32,
+    pub events: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContractAbiRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContractAbiResponse {
+    pub address: H160,
+    pub abi: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTransactionLogsRequest {
+    pub hash: H256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogView {
+    pub log_index: Option<U256>,
+    pub address: H160,
+    pub topics: Vec<H256>,
+    pub data: Bytes,
+    /// Only for contracts with an uploaded ABI
+    pub decoded: Option<DecodedLog>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTransactionLogsResponse {
+    pub logs: Vec<LogView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContractMetadataRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectorView {
+    /// `0x`-prefixed 4-byte selector
+    pub selector: String,
+    /// Every known signature hashing to the selector
+    pub signatures: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContractMetadataResponse {
+    pub address: H160,
+    pub code_hash: H256,
+    pub compiler: Option<String>,
+    pub compiler_version: Option<String>,
+    pub source_hash: Option<String>,
+    pub selectors: Vec<SelectorView>,
+    pub proxy: Option<ProxyView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyView {
+    pub kind: EnumProxyKind,
+    pub implementation: H160,
+    pub beacon: Option<H160>,
+    /// Oldest first, as seen in ingested `Upgraded` events
+    pub upgrades: Vec<ProxyUpgradeView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyUpgradeView {
+    pub implementation: H160,
+    pub block_number: u32,
+    pub tx_hash: H256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTokenRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTokenResponse {
+    pub address: H160,
+    pub standard: EnumTokenStandard,
+    pub name: String,
+    pub symbol: String,
+    /// 0 for NFTs and ERC-20s without `decimals()`
+    pub decimals: u8,
+    /// In the token's smallest unit, as of detection
+    pub total_supply: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTokenHoldersRequest {
+    pub token: H160,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTokenHoldersResponse {
+    pub token: H160,
+    pub holder_count: u32,
+    /// Largest balance first
+    pub holders: Vec<TokenHolderView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenHolderView {
+    pub holder: H160,
+    pub balance: U256,
+    pub labels: Vec<AddressLabelView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressTokenBalancesRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressTokenBalancesResponse {
+    pub address: H160,
+    pub balances: Vec<TokenBalanceView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenBalanceView {
+    pub token: H160,
+    /// `None` until the token has been detected
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    pub balance: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyTokenBalanceRequest {
+    pub token: H160,
+    pub holder: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyTokenBalanceResponse {
+    /// Sum of the ingested transfers
+    pub tracked: U256,
+    /// `balanceOf` at the latest block
+    pub on_chain: U256,
+    pub matches: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPortfolioRequest {
+    pub address: H160,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPortfolioResponse {
+    pub address: H160,
+    /// Native balance first, then tokens by value
+    pub holdings: Vec<PortfolioHoldingView>,
+    pub value_usd: f64,
+    /// Current balances at the prices of the time, over the holdings priced
+    /// then and now
+    pub value_1d_usd: Option<f64>,
+    pub value_7d_usd: Option<f64>,
+    pub value_30d_usd: Option<f64>,
+    pub change_1d_pct: Option<f64>,
+    pub change_7d_pct: Option<f64>,
+    pub change_30d_pct: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioHoldingView {
+    /// `None` for the native coin
+    pub token: Option<H160>,
+    pub symbol: String,
+    pub decimals: u8,
+    /// In the smallest unit
+    pub balance: U256,
+    /// `balance` scaled by `decimals`
+    pub amount: f64,
+    /// `None` for NFTs and tokens not matched to a listing
+    pub price_usd: Option<f64>,
+    pub value_usd: Option<f64>,
+    pub change_1d_pct: Option<f64>,
+    pub change_7d_pct: Option<f64>,
+    pub change_30d_pct: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPriceAtRequest {
+    /// CoinMarketCap id
+    pub asset_id: u64,
+    pub timestamp_s: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPriceAtResponse {
+    pub asset_id: u64,
+    pub timestamp_s: u32,
+    /// Interpolated between the quotes around `timestamp_s`
+    pub price_usd: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetDexPriceRequest {
+    pub token: H160,
+    /// Latest if not given
+    pub block_number: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetDexPriceResponse {
+    pub token: H160,
+    pub price_usd: f64,
+    /// Over the pools the price was taken from
+    pub liquidity_usd: f64,
+    /// The deepest of them
+    pub pool: H160,
+    /// Block of that pool's state
+    pub block_number: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAssetPricesRequest {
+    #[serde(default)]
+    pub cmc_ids: Vec<u64>,
+    /// Token contracts on the ingested chain
+    #[serde(default)]
+    pub tokens: Vec<H160>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAssetPricesResponse {
+    /// Assets no source has a price for are left out
+    pub prices: Vec<AssetPriceView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssetPriceView {
+    /// `cmc:<id>` or `<chain>:<address>`
+    pub asset: String,
+    pub price_usd: f64,
+    pub sources: Vec<SourcePriceView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourcePriceView {
+    pub source: String,
+    pub price_usd: f64,
+    /// Seconds since the source returned the price, above 0 for a source
+    /// that failed just now
+    pub age_s: i64,
+    /// Rejected as an outlier if false
+    pub accepted: bool,
+}
@@ -0,0 +1,171 @@
+use ethers::types::{BlockId, BlockNumber, Chain, H160, U256};
+use ethers::utils::format_units;
+use eyre::*;
+
+use crate::api::assets::{AssetId, AssetInfoClient, AssetPriceByPeriod};
+use crate::api::EthersClient;
+use crate::model::{EnumTokenStandard, GetPortfolioResponse, PortfolioHoldingView};
+use crate::tables::Tables;
+
+const NATIVE_DECIMALS: u8 = 18;
+
+/// The chain's native coin as the price source knows it
+#[derive(Debug, Clone)]
+pub struct NativeCoin {
+    pub symbol: String,
+    pub asset: AssetId,
+}
+
+impl NativeCoin {
+    pub fn for_chain(chain: Chain) -> Option<Self> {
+        let (symbol, cmc_id) = match chain {
+            Chain::Mainnet | Chain::Arbitrum | Chain::Optimism | Chain::Base => ("ETH", 1027),
+            Chain::BinanceSmartChain => ("BNB", 1839),
+            _ => return None,
+        };
+        Some(Self {
+            symbol: symbol.to_string(),
+            asset: AssetId::Cmc(cmc_id),
+        })
+    }
+}
+
+struct Holding {
+    token: Option<H160>,
+    symbol: String,
+    decimals: u8,
+    balance: U256,
+    asset: Option<AssetId>,
+}
+
+/// Value now and at the start of each period, over the holdings priced at
+/// both ends so an asset listed in between doesn't count as a gain
+#[derive(Default)]
+struct PeriodValues {
+    latest: f64,
+    then: [(f64, f64); 3],
+}
+
+fn change_pct(now: f64, then: f64) -> Option<f64> {
+    (then > 0.0).then(|| (now - then) / then * 100.0)
+}
+
+/// Native balance and every token balance tracked for `address`, valued
+/// with the current balances at the latest, 1d, 7d and 30d prices.
+///
+/// Only tokens matched to a listing by contract are priced, NFTs and
+/// unlisted tokens are returned without a value.
+pub async fn value_portfolio(
+    api: &EthersClient,
+    tables: &Tables,
+    prices: &dyn AssetInfoClient,
+    native: Option<&NativeCoin>,
+    address: H160,
+) -> Result<GetPortfolioResponse> {
+    let native_balance = api
+        .get_account_balance(address, BlockId::Number(BlockNumber::Latest))
+        .await?;
+    let mut holdings = vec![Holding {
+        token: None,
+        symbol: native.map(|coin| coin.symbol.clone()).unwrap_or_default(),
+        decimals: NATIVE_DECIMALS,
+        balance: native_balance,
+        asset: native.map(|coin| coin.asset.clone()),
+    }];
+    for row in tables.token_balances.select_holder_balances(address)? {
+        let token = H160::from(row.token_address);
+        let details = tables.tokens.select_token(token);
+        let fungible = details
+            .as_ref()
+            .is_some_and(|details| details.standard == EnumTokenStandard::Erc20 as u8);
+        holdings.push(Holding {
+            token: Some(token),
+            symbol: details
+                .as_ref()
+                .map(|details| details.symbol.clone())
+                .unwrap_or_default(),
+            decimals: details.as_ref().map_or(0, |details| details.decimals),
+            balance: row.balance.into(),
+            asset: details
+                .and_then(|details| details.cmc_id)
+                .filter(|_| fungible)
+                .map(AssetId::Cmc),
+        });
+    }
+
+    let mut assets: Vec<AssetId> = holdings
+        .iter()
+        .filter(|holding| !holding.balance.is_zero())
+        .filter_map(|holding| holding.asset.clone())
+        .collect();
+    assets.sort_by_key(|asset| asset.to_string());
+    assets.dedup();
+    let periods = if assets.is_empty() {
+        Default::default()
+    } else {
+        prices.get_usd_price_period(&assets).await?
+    };
+
+    let mut total = PeriodValues::default();
+    let mut views = Vec::with_capacity(holdings.len());
+    for holding in holdings {
+        let amount: f64 = format_units(holding.balance, holding.decimals as u32)?.parse()?;
+        let period: Option<&AssetPriceByPeriod> =
+            holding.asset.as_ref().and_then(|asset| periods.get(asset));
+        let value = period.map(|period| amount * period.price_latest);
+        let mut changes = [None; 3];
+        if let Some(period) = period {
+            total.latest += amount * period.price_latest;
+            for (i, then) in [period.price_1d, period.price_7d, period.price_30d]
+                .into_iter()
+                .enumerate()
+            {
+                if let Some(then) = then {
+                    total.then[i].0 += amount * period.price_latest;
+                    total.then[i].1 += amount * then;
+                    changes[i] = change_pct(period.price_latest, then);
+                }
+            }
+        }
+        if holding.token.is_some() && holding.balance.is_zero() {
+            continue;
+        }
+        views.push(PortfolioHoldingView {
+            token: holding.token,
+            symbol: holding.symbol,
+            decimals: holding.decimals,
+            balance: holding.balance,
+            amount,
+            price_usd: period.map(|period| period.price_latest),
+            value_usd: value,
+            change_1d_pct: changes[0],
+            change_7d_pct: changes[1],
+            change_30d_pct: changes[2],
+        });
+    }
+    // the native coin stays first, tokens by value with unpriced ones last
+    views[1..].sort_by(|a, b| {
+        let value = |view: &PortfolioHoldingView| view.value_usd.unwrap_or(-1.0);
+        value(b).total_cmp(&value(a))
+    });
+
+    let period_value = |i: usize| {
+        let (_, then) = total.then[i];
+        (then > 0.0).then_some(then)
+    };
+    let period_change = |i: usize| {
+        let (now, then) = total.then[i];
+        change_pct(now, then)
+    };
+    Ok(GetPortfolioResponse {
+        address,
+        holdings: views,
+        value_usd: total.latest,
+        value_1d_usd: period_value(0),
+        value_7d_usd: period_value(1),
+        value_30d_usd: period_value(2),
+        change_1d_pct: period_change(0),
+        change_7d_pct: period_change(1),
+        change_30d_pct: period_change(2),
+    })
+}
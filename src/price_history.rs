@@ -0,0 +1,358 @@
+use chrono::{DateTime, Utc};
+use eyre::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::api::cmc::CoinMarketCap;
+use crate::tables::Tables;
+
+const DAY_S: i64 = 24 * 60 * 60;
+/// Quotes requested per call when backfilling, well under the endpoint's
+/// limit of 10000
+const BACKFILL_QUOTES_PER_CALL: i64 = 2000;
+/// Longest gap between two quotes a price is interpolated across, beyond
+/// it the asset is treated as unpriced
+const MAX_BRIDGED_GAP_S: i64 = DAY_S;
+
+/// Resolution of the quotes fetched from the historical endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteInterval {
+    FiveMinutes,
+    Hourly,
+}
+
+impl QuoteInterval {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuoteInterval::FiveMinutes => "5m",
+            QuoteInterval::Hourly => "1h",
+        }
+    }
+
+    pub fn seconds(self) -> i64 {
+        match self {
+            QuoteInterval::FiveMinutes => 5 * 60,
+            QuoteInterval::Hourly => 60 * 60,
+        }
+    }
+}
+
+/// One line of the quote log
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct StoredQuote {
+    id: u64,
+    timestamp_s: u32,
+    price_usd: f64,
+    /// Lines written before quotes were marked are taken as still open
+    #[serde(default)]
+    closed: bool,
+}
+
+/// Append log of the fetched quotes, so a restart doesn't fetch the history
+/// again. It is rewritten on open with the first closed quote of each
+/// point, or its last open one
+#[derive(Debug)]
+struct QuoteLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl QuoteLog {
+    fn open(path: &Path) -> Result<(Self, Vec<StoredQuote>)> {
+        let mut quotes = BTreeMap::new();
+        match File::open(path) {
+            Result::Ok(file) => {
+                let mut skipped = 0;
+                for line in BufReader::new(file).lines() {
+                    // a write cut short by a crash leaves a partial last line
+                    match line.map(|line| serde_json::from_str::<StoredQuote>(&line)) {
+                        Result::Ok(Result::Ok(quote)) => {
+                            let key = (quote.id, quote.timestamp_s);
+                            if !quotes
+                                .get(&key)
+                                .is_some_and(|kept: &StoredQuote| kept.closed)
+                            {
+                                quotes.insert(key, quote);
+                            }
+                        }
+                        _ => skipped += 1,
+                    }
+                }
+                if skipped > 0 {
+                    warn!("Skipped {} unreadable lines of the quote log", skipped);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+        let quotes: Vec<StoredQuote> = quotes.into_values().collect();
+
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?,
+        );
+        for quote in &quotes {
+            serde_json::to_writer(&mut writer, quote)?;
+            writer.write_all(b"\n")?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to replace {}", path.display()))?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                file: Mutex::new(file),
+            },
+            quotes,
+        ))
+    }
+
+    fn write(&self, quotes: &[StoredQuote]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for quote in quotes {
+            writeln!(file, "{}", serde_json::to_string(quote)?)?;
+        }
+        Ok(())
+    }
+
+    /// A failed write is only logged, the table works without the log
+    fn append(&self, quotes: &[StoredQuote]) {
+        if let Err(e) = self.write(quotes) {
+            warn!(
+                "Failed to append to quote log {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// USD price time series by CMC id, stored in the `PriceHistory` table and
+/// filled from the historical quotes endpoint.
+///
+/// Lookups fetch the UTC day around the timestamp when the table doesn't
+/// cover it yet. Prices between two quotes are interpolated linearly, across
+/// gaps of up to a day. Past the last quote, as for blocks younger than the
+/// interval, the last one is used if it is at most two intervals old.
+pub struct PriceHistory {
+    cmc: Arc<CoinMarketCap>,
+    tables: Arc<Tables>,
+    interval: QuoteInterval,
+    /// The table's points by asset, loaded on first use for range lookups
+    points: RwLock<HashMap<u64, BTreeMap<i64, f64>>>,
+    /// When each (id, day) was last fetched and whether the day was over
+    /// by then. Days still open or whose fetch failed are fetched again
+    /// after an interval
+    fetched: Mutex<HashMap<(u64, i64), (Instant, bool)>>,
+    log: Option<QuoteLog>,
+}
+
+impl PriceHistory {
+    pub fn new(cmc: Arc<CoinMarketCap>, tables: Arc<Tables>, interval: QuoteInterval) -> Self {
+        Self {
+            cmc,
+            tables,
+            interval,
+            points: RwLock::new(HashMap::new()),
+            fetched: Mutex::new(HashMap::new()),
+            log: None,
+        }
+    }
+
+    /// Loads the quotes logged at `path` into the table and logs the ones
+    /// fetched from now on, creating the log if missing
+    pub async fn with_quote_log(mut self, path: &Path) -> Result<Self> {
+        let (log, quotes) = QuoteLog::open(path)?;
+        for quote in &quotes {
+            self.tables
+                .price_history
+                .record(quote.id, quote.timestamp_s, quote.price_usd, quote.closed)
+                .await?;
+        }
+        info!("Loaded {} quotes from {}", quotes.len(), path.display());
+        self.log = Some(log);
+        Ok(self)
+    }
+
+    pub fn interval(&self) -> QuoteInterval {
+        self.interval
+    }
+
+    fn load(&self, id: u64) -> Result<()> {
+        if self.points.read().unwrap().contains_key(&id) {
+            return Ok(());
+        }
+        let points: BTreeMap<i64, f64> = self
+            .tables
+            .price_history
+            .select_asset_points(id)?
+            .into_iter()
+            .map(|row| (row.timestamp_s as i64, row.price_usd))
+            .collect();
+        self.points.write().unwrap().entry(id).or_insert(points);
+        Ok(())
+    }
+
+    /// Days entirely inside `from_s` to `to_s`
+    fn days_within(from_s: i64, to_s: i64) -> std::ops::Range<i64> {
+        (from_s + DAY_S - 1).div_euclid(DAY_S)..(to_s + 1).div_euclid(DAY_S)
+    }
+
+    /// Records the days of the range as fetched. A failed fetch counts like
+    /// a day still open, so it is retried after an interval rather than on
+    /// every lookup
+    fn mark_fetched(&self, id: u64, from_s: i64, to_s: i64, succeeded: bool) {
+        let now = Utc::now().timestamp();
+        let mut fetched = self.fetched.lock().unwrap();
+        for day in Self::days_within(from_s, to_s) {
+            let complete = succeeded && (to_s < now || (day + 1) * DAY_S <= now);
+            fetched.insert((id, day), (Instant::now(), complete));
+        }
+    }
+
+    fn recently_fetched(&self, id: u64, day: i64) -> bool {
+        let refetch_after = Duration::from_secs(self.interval.seconds() as u64);
+        match self.fetched.lock().unwrap().get(&(id, day)) {
+            Some((_, true)) => true,
+            Some((at, false)) => at.elapsed() < refetch_after,
+            None => false,
+        }
+    }
+
+    /// Fetches and records the quotes from `from_s` to `to_s`, returns how
+    /// many were recorded
+    async fn fetch_range(&self, id: u64, from_s: i64, to_s: i64) -> Result<usize> {
+        let result = self.fetch_quotes(id, from_s, to_s).await;
+        self.mark_fetched(id, from_s, to_s, result.is_ok());
+        result
+    }
+
+    async fn fetch_quotes(&self, id: u64, from_s: i64, to_s: i64) -> Result<usize> {
+        let from = DateTime::from_timestamp(from_s, 0).context("invalid start")?;
+        let to = DateTime::from_timestamp(to_s, 0).context("invalid end")?;
+        let quotes = self
+            .cmc
+            .get_usd_quotes_by_id(id, from, to, self.interval.as_str())
+            .await?;
+        // a quote whose interval is still running may be revised
+        let open_from = Utc::now().timestamp() - self.interval.seconds();
+        let stored: Vec<StoredQuote> = quotes
+            .iter()
+            .map(|(at, price)| StoredQuote {
+                id,
+                timestamp_s: at.timestamp() as u32,
+                price_usd: *price,
+                closed: at.timestamp() <= open_from,
+            })
+            .collect();
+        for quote in &stored {
+            self.tables
+                .price_history
+                .record(id, quote.timestamp_s, quote.price_usd, quote.closed)
+                .await?;
+        }
+        if let Some(log) = &self.log {
+            log.append(&stored);
+        }
+        self.load(id)?;
+        self.points
+            .write()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .extend(quotes.iter().map(|(at, price)| (at.timestamp(), *price)));
+        Ok(quotes.len())
+    }
+
+    /// Price at `timestamp_s` from the points around it, `None` unless they
+    /// are close enough to stand for it
+    fn interpolate(&self, id: u64, timestamp_s: i64, max_gap: i64) -> Option<f64> {
+        let points = self.points.read().unwrap();
+        let points = points.get(&id)?;
+        let before = points.range(..=timestamp_s).next_back();
+        let after = points.range(timestamp_s..).next();
+        match (before, after) {
+            (Some((&t0, &p0)), Some((&t1, &p1))) if t1 > t0 && t1 - t0 <= max_gap => {
+                let weight = (timestamp_s - t0) as f64 / (t1 - t0) as f64;
+                Some(p0 + (p1 - p0) * weight)
+            }
+            (Some((&t0, &price)), Some(_)) if t0 == timestamp_s => Some(price),
+            (Some((&t0, &price)), None) if timestamp_s - t0 <= 2 * self.interval.seconds() => {
+                Some(price)
+            }
+            _ => None,
+        }
+    }
+
+    /// USD price of the listing at `timestamp_s`, `None` if CMC has no
+    /// quotes around it
+    pub async fn price_at(&self, id: u64, timestamp_s: i64) -> Result<Option<f64>> {
+        self.load(id)?;
+        let max_gap = 2 * self.interval.seconds();
+        if let Some(price) = self.interpolate(id, timestamp_s, max_gap) {
+            return Ok(Some(price));
+        }
+        let day = timestamp_s.div_euclid(DAY_S);
+        if !self.recently_fetched(id, day) {
+            // one quote either side so the day's ends can be interpolated
+            let interval = self.interval.seconds();
+            self.fetch_range(id, day * DAY_S - interval, (day + 1) * DAY_S + interval)
+                .await?;
+        }
+        // a gap in the quotes is bridged rather than left unpriced, as long
+        // as the asset was still quoted that day
+        Ok(self.interpolate(id, timestamp_s, MAX_BRIDGED_GAP_S))
+    }
+
+    /// Like `price_at` from the quotes already stored, without fetching
+    pub fn stored_price_at(&self, id: u64, timestamp_s: i64) -> Result<Option<f64>> {
+        self.load(id)?;
+        Ok(self.interpolate(id, timestamp_s, MAX_BRIDGED_GAP_S))
+    }
+
+    /// Fills the table for `from_s` to `to_s`, a few thousand quotes per
+    /// call. Windows the table already covers are skipped. Returns how many
+    /// quotes were recorded
+    pub async fn backfill(&self, id: u64, from_s: i64, to_s: i64) -> Result<usize> {
+        self.load(id)?;
+        let interval = self.interval.seconds();
+        let window = interval * BACKFILL_QUOTES_PER_CALL;
+        let mut recorded = 0;
+        let mut start = from_s - interval;
+        while start <= to_s {
+            let end = (start + window).min(to_s + interval);
+            let known = self
+                .points
+                .read()
+                .unwrap()
+                .get(&id)
+                .map_or(0, |points| points.range(start..=end).count() as i64);
+            // gaps CMC has no quotes for are only asked about again as often
+            // as a lookup would
+            let fetched = Self::days_within(start, end).all(|day| self.recently_fetched(id, day));
+            if known < (end - start) / interval && !fetched {
+                recorded += self.fetch_range(id, start, end).await?;
+            }
+            start = end + 1;
+        }
+        if recorded > 0 {
+            debug!("Backfilled {} quotes of CMC id {}", recorded, id);
+        }
+        Ok(recorded)
+    }
+}
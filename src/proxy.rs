@@ -0,0 +1,204 @@
+use ethers::types::{BlockId, BlockNumber, Bytes, Log, H160, H256};
+use eyre::*;
+
+use crate::api::{EthersClient, BEACON_UPGRADED_EVENT_TOPIC, UPGRADED_EVENT_TOPIC};
+use crate::bytecode::analyze_contract;
+use crate::model::EnumProxyKind;
+use crate::rkyv_wrappers::WrappedAddress;
+use crate::tables::{ProxyUpgradeRow, Tables};
+
+/// keccak256("eip1967.proxy.implementation") - 1
+const EIP1967_IMPLEMENTATION_SLOT: H256 = H256([
+    0x36, 0x08, 0x94, 0xa1, 0x3b, 0xa1, 0xa3, 0x21, 0x06, 0x67, 0xc8, 0x28, 0x49, 0x2d, 0xb9, 0x8d,
+    0xca, 0x3e, 0x20, 0x76, 0xcc, 0x37, 0x35, 0xa9, 0x20, 0xa3, 0xca, 0x50, 0x5d, 0x38, 0x2b, 0xbc,
+]);
+/// keccak256("eip1967.proxy.beacon") - 1
+const EIP1967_BEACON_SLOT: H256 = H256([
+    0xa3, 0xf0, 0xad, 0x74, 0xe5, 0x42, 0x3a, 0xeb, 0xfd, 0x80, 0xd3, 0xef, 0x43, 0x46, 0x57, 0x83,
+    0x35, 0xa9, 0xa7, 0x2a, 0xea, 0xee, 0x59, 0xff, 0x6c, 0xb3, 0x58, 0x2b, 0x35, 0x13, 0x3d, 0x50,
+]);
+/// keccak256("org.zeppelinos.proxy.implementation")
+const OPENZEPPELIN_IMPLEMENTATION_SLOT: H256 = H256([
+    0x70, 0x50, 0xc9, 0xe0, 0xf4, 0xca, 0x76, 0x9c, 0x69, 0xbd, 0x3a, 0x8e, 0xf7, 0x40, 0xbc, 0x37,
+    0x93, 0x4f, 0x8e, 0x2c, 0x03, 0x6e, 0x5a, 0x72, 0x3f, 0xd8, 0xee, 0x04, 0x8e, 0xd3, 0xf8, 0xc3,
+]);
+/// `implementation()`, asked of a beacon
+const IMPLEMENTATION_SELECTOR: [u8; 4] = [0x5c, 0x60, 0xda, 0x1b];
+/// `masterCopy()`, answered by the Gnosis Safe proxy itself
+const MASTER_COPY_SELECTOR: [u8; 4] = [0xa6, 0x19, 0x48, 0x6e];
+/// The Safe proxy is a couple hundred bytes, anything larger containing the
+/// selector is a contract with its own `masterCopy()`
+const MAX_SAFE_PROXY_SIZE: usize = 1024;
+/// EIP-1167 runtime code around the 20-byte target
+const EIP1167_PREFIX: [u8; 10] = [0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x3d, 0x36, 0x3d, 0x73];
+const EIP1167_SUFFIX: [u8; 15] = [
+    0x5a, 0xf4, 0x3d, 0x82, 0x80, 0x3e, 0x90, 0x3d, 0x91, 0x60, 0x2b, 0x57, 0xfd, 0x5b, 0xf3,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyInfo {
+    pub kind: EnumProxyKind,
+    pub implementation: H160,
+    pub beacon: Option<H160>,
+}
+
+/// Target of an EIP-1167 minimal proxy
+pub fn minimal_proxy_target(code: &[u8]) -> Option<H160> {
+    let target = code
+        .strip_prefix(&EIP1167_PREFIX)?
+        .strip_suffix(&EIP1167_SUFFIX)?;
+    (target.len() == 20).then(|| H160::from_slice(target))
+}
+
+/// An address stored in a full word, `None` for empty slots and anything
+/// with the upper 12 bytes set
+fn word_address(word: &[u8]) -> Option<H160> {
+    let word: &[u8; 32] = word.get(..32)?.try_into().ok()?;
+    if word[..12] != [0; 12] || word[12..] == [0; 20] {
+        return None;
+    }
+    Some(H160::from_slice(&word[12..]))
+}
+
+async fn storage_address(api: &EthersClient, address: H160, slot: H256) -> Result<Option<H160>> {
+    let value = api
+        .get_storage_at(address, slot, BlockId::Number(BlockNumber::Latest))
+        .await?;
+    Ok(word_address(value.as_bytes()))
+}
+
+async fn beacon_implementation(
+    api: &EthersClient,
+    beacon: H160,
+    block: BlockNumber,
+) -> Result<H160> {
+    let output = api
+        .call_contract(
+            beacon,
+            Bytes::from(IMPLEMENTATION_SELECTOR.to_vec()),
+            BlockId::Number(block),
+        )
+        .await?;
+    word_address(&output).with_context(|| format!("beacon {:?} returned no implementation", beacon))
+}
+
+/// Checks the bytecode patterns first and then the well-known storage
+/// slots, one `eth_getStorageAt` each until one holds an address
+pub async fn detect_proxy(
+    api: &EthersClient,
+    address: H160,
+    code: &[u8],
+) -> Result<Option<ProxyInfo>> {
+    let proxy = |kind, implementation, beacon| {
+        Ok(Some(ProxyInfo {
+            kind,
+            implementation,
+            beacon,
+        }))
+    };
+    if let Some(implementation) = minimal_proxy_target(code) {
+        return proxy(EnumProxyKind::Eip1167, implementation, None);
+    }
+    if let Some(implementation) = storage_address(api, address, EIP1967_IMPLEMENTATION_SLOT).await?
+    {
+        return proxy(EnumProxyKind::Eip1967, implementation, None);
+    }
+    if let Some(beacon) = storage_address(api, address, EIP1967_BEACON_SLOT).await? {
+        let implementation = beacon_implementation(api, beacon, BlockNumber::Latest).await?;
+        return proxy(EnumProxyKind::Eip1967Beacon, implementation, Some(beacon));
+    }
+    if let Some(implementation) =
+        storage_address(api, address, OPENZEPPELIN_IMPLEMENTATION_SLOT).await?
+    {
+        return proxy(EnumProxyKind::OpenZeppelinLegacy, implementation, None);
+    }
+    if code.len() <= MAX_SAFE_PROXY_SIZE
+        && code.windows(4).any(|window| window == MASTER_COPY_SELECTOR)
+    {
+        if let Some(implementation) = storage_address(api, address, H256::zero()).await? {
+            return proxy(EnumProxyKind::GnosisSafe, implementation, None);
+        }
+    }
+    Ok(None)
+}
+
+/// Follows an `Upgraded` or `BeaconUpgraded` log.
+///
+/// `UpgradeableBeacon` emits the same `Upgraded` event as a proxy, so the
+/// proxies known to use the emitting address as beacon are moved along with
+/// it. Contracts seen for the first time are analyzed instead, which also
+/// tells proxies from beacons nobody points at yet. The recorded upgrade
+/// holds the implementation as of the log's block, not the current one.
+pub async fn record_upgrade(api: &EthersClient, tables: &Tables, log: &Log) -> Result<()> {
+    let topic = log.topics.first().context("log without topics")?;
+    let target = H160::from(*log.topics.get(1).context("upgrade log without target")?);
+    let block_number = log.block_number.context("pending log")?;
+    let tx_hash = log.transaction_hash.context("pending log")?;
+    let emitter = WrappedAddress::from(log.address);
+
+    let mut upgraded = vec![];
+    let implementation = if *topic == UPGRADED_EVENT_TOPIC {
+        for follower in tables.contracts.select_beacon_followers(emitter.clone())? {
+            let proxy = follower.address.clone();
+            tables
+                .contracts
+                .upsert_contract(proxy.clone(), |row| {
+                    row.implementation = Some(target.into())
+                })
+                .await?;
+            upgraded.push(proxy);
+        }
+        // a row without a code hash, such as one only holding an ABI, was
+        // never analyzed
+        let analyzed = tables
+            .contracts
+            .select_contract(emitter.clone())
+            .filter(|row| row.code_hash.is_some());
+        match analyzed {
+            Some(row) if row.proxy_kind.is_some() => {
+                tables
+                    .contracts
+                    .upsert_contract(emitter.clone(), |row| {
+                        row.implementation = Some(target.into())
+                    })
+                    .await?;
+                upgraded.push(emitter);
+            }
+            Some(_) => {}
+            None if upgraded.is_empty() => {
+                let row = analyze_contract(api, tables, log.address, None).await?;
+                if row.proxy_kind.is_some() {
+                    upgraded.push(emitter);
+                }
+            }
+            None => {}
+        }
+        target
+    } else if *topic == BEACON_UPGRADED_EVENT_TOPIC {
+        let implementation =
+            beacon_implementation(api, target, BlockNumber::Number(block_number)).await?;
+        tables
+            .contracts
+            .upsert_contract(emitter.clone(), |row| {
+                row.proxy_kind = Some(EnumProxyKind::Eip1967Beacon as u8);
+                row.implementation = Some(implementation.into());
+                row.beacon = Some(target.into());
+            })
+            .await?;
+        upgraded.push(emitter);
+        implementation
+    } else {
+        return Ok(());
+    };
+
+    for proxy in upgraded {
+        tables.proxy_upgrades.insert(ProxyUpgradeRow {
+            id: tables.proxy_upgrades.get_next_pk().into(),
+            proxy_address: proxy,
+            implementation: implementation.into(),
+            block_number: block_number.as_u32(),
+            tx_hash: tx_hash.0,
+        })?;
+    }
+    Ok(())
+}
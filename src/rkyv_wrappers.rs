@@ -25,12 +25,18 @@ impl From<AddressDef> for Address {
     }
 }
 
-#[derive(Archive, Serialize, Deserialize, Debug, PartialEq, Clone, PartialOrd)]
+#[derive(Archive, Serialize, Deserialize, Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
 pub struct WrappedAddress {
     #[rkyv(with = AddressDef)]
     address: Address,
 }
 
+impl WrappedAddress {
+    pub fn address(&self) -> Address {
+        self.address
+    }
+}
+
 impl From<H160> for WrappedAddress {
     fn from(value: H160) -> Self {
         Self {
@@ -39,6 +45,18 @@ impl From<H160> for WrappedAddress {
     }
 }
 
+impl From<Address> for WrappedAddress {
+    fn from(address: Address) -> Self {
+        Self { address }
+    }
+}
+
+impl From<WrappedAddress> for H160 {
+    fn from(value: WrappedAddress) -> Self {
+        H160::from_slice(value.address.as_slice())
+    }
+}
+
 impl Debug for ArchivedWrappedAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ArchivedWrappedAddress")
@@ -81,6 +99,24 @@ pub struct WrappedU256 {
     value: U256,
 }
 
+impl WrappedU256 {
+    pub fn value(&self) -> U256 {
+        self.value
+    }
+}
+
+impl From<U256> for WrappedU256 {
+    fn from(value: U256) -> Self {
+        Self { value }
+    }
+}
+
+impl From<WrappedU256> for ethers::types::U256 {
+    fn from(value: WrappedU256) -> Self {
+        ethers::types::U256::from_big_endian(&value.value.to_be_bytes::<32>())
+    }
+}
+
 impl From<ethers::types::U256> for WrappedU256 {
     fn from(value: ethers::types::U256) -> Self {
         let mut be_bytes = [0u8; 32];
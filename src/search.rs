@@ -0,0 +1,370 @@
+use ethers::types::{Chain, H160, H256};
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::*;
+
+use crate::api::models::{platform_chain, MapCoinInfo};
+use crate::model::{AddressLabelView, RankedSearchResult, SearchResult};
+use crate::tables::Tables;
+
+const MAX_RESULTS: usize = 20;
+
+/// Scores of the different kinds of matches, higher ranks first
+mod score {
+    pub const EXACT_ID: u32 = 1000;
+    pub const EXACT_TOKEN_SYMBOL: u32 = 900;
+    pub const EXACT_LABEL: u32 = 850;
+    pub const EXACT_TOKEN_NAME: u32 = 800;
+    pub const PREFIX_TOKEN_SYMBOL: u32 = 600;
+    pub const PREFIX_LABEL: u32 = 550;
+    pub const PREFIX_TOKEN_NAME: u32 = 500;
+    /// Listed on CMC but never seen on chain
+    pub const CMC_ONLY_PENALTY: u32 = 100;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameKind {
+    TokenSymbol,
+    TokenName,
+    Label,
+}
+
+#[derive(Debug, Clone)]
+struct IndexedName {
+    kind: NameKind,
+    /// Breaks ties between equal matches, lower is better
+    tie_break: u64,
+    penalty: u32,
+    result: SearchResult,
+}
+
+/// Lowercased token symbols, token names and address labels for prefix
+/// lookups.
+///
+/// The index is rebuilt from the tables by [`SearchIndex::refresh`] rather than
+/// kept in sync on every insert, so new tokens and labels become searchable
+/// after the next refresh.
+pub struct SearchIndex {
+    /// CMC coins are given the address of their contract on this chain
+    chain: Chain,
+    names: RwLock<BTreeMap<String, Vec<IndexedName>>>,
+    cmc_coins: RwLock<Vec<MapCoinInfo>>,
+}
+
+impl SearchIndex {
+    pub fn new(chain: Chain) -> Self {
+        Self {
+            chain,
+            names: RwLock::default(),
+            cmc_coins: RwLock::default(),
+        }
+    }
+
+    pub fn set_cmc_coins(&self, coins: Vec<MapCoinInfo>) {
+        *self.cmc_coins.write().unwrap() = coins;
+    }
+
+    pub fn refresh(&self, tables: &Tables) {
+        let mut names: BTreeMap<String, Vec<IndexedName>> = BTreeMap::new();
+        let mut add = |name: &str, entry: IndexedName| {
+            let key = name.trim().to_lowercase();
+            if !key.is_empty() {
+                names.entry(key).or_default().push(entry);
+            }
+        };
+
+        let tokens = tables.tokens.select_all().execute().unwrap_or_default();
+        let mut onchain_tokens = HashSet::with_capacity(tokens.len());
+        for token in tokens {
+            let address = H160::from_str(&token.token_contract_hash).ok();
+            onchain_tokens.extend(address);
+            let result = SearchResult::Token {
+                address,
+                symbol: token.symbol.clone(),
+                name: token.name.clone(),
+                cmc_id: token.cmc_id,
+            };
+            for (kind, name) in [
+                (NameKind::TokenSymbol, &token.symbol),
+                (NameKind::TokenName, &token.name),
+            ] {
+                add(
+                    name,
+                    IndexedName {
+                        kind,
+                        tie_break: token.id,
+                        penalty: 0,
+                        result: result.clone(),
+                    },
+                );
+            }
+        }
+
+        for coin in self.cmc_coins.read().unwrap().iter() {
+            let address = coin
+                .platform
+                .as_ref()
+                .filter(|platform| platform_chain(&platform.name) == Some(self.chain))
+                .and_then(|platform| H160::from_str(&platform.token_address).ok());
+            let penalty = match address {
+                Some(address) if onchain_tokens.contains(&address) => continue,
+                _ => score::CMC_ONLY_PENALTY,
+            };
+            let result = SearchResult::Token {
+                address,
+                symbol: coin.symbol.clone(),
+                name: coin.name.clone(),
+                cmc_id: Some(coin.id as u64),
+            };
+            for (kind, name) in [
+                (NameKind::TokenSymbol, &coin.symbol),
+                (NameKind::TokenName, &coin.name),
+            ] {
+                add(
+                    name,
+                    IndexedName {
+                        kind,
+                        tie_break: coin.rank.max(0) as u64,
+                        penalty,
+                        result: result.clone(),
+                    },
+                );
+            }
+        }
+
+        for row in tables.address_labels.select_all().execute().unwrap_or_default() {
+            let label = row.label.clone();
+            let id = row.id;
+            let address = H160::from(row.address.clone());
+            let Ok(label_view) = AddressLabelView::try_from(row) else {
+                continue;
+            };
+            add(
+                &label,
+                IndexedName {
+                    kind: NameKind::Label,
+                    tie_break: id,
+                    penalty: 0,
+                    result: SearchResult::Label {
+                        address,
+                        label: label_view,
+                    },
+                },
+            );
+        }
+
+        *self.names.write().unwrap() = names;
+    }
+
+    /// Refreshes the index every `interval`
+    pub async fn refresh_periodically(self: Arc<Self>, tables: Arc<Tables>, interval: Duration) {
+        loop {
+            self.refresh(&tables);
+            trace!("Search index refreshed");
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Classifies the input and returns the best matches first
+    pub fn search(&self, tables: &Tables, query: &str) -> Vec<RankedSearchResult> {
+        let query = query.trim();
+        if query.is_empty() {
+            return vec![];
+        }
+        let mut results = self.search_ids(tables, query);
+        results.extend(self.search_names(query));
+        results.sort_by_key(|result| std::cmp::Reverse(result.score));
+        results.truncate(MAX_RESULTS);
+        results
+    }
+
+    /// Block numbers, block and transaction hashes and addresses. A number
+    /// may also be a token symbol, [`SearchIndex::search`] looks names up
+    /// for every query
+    fn search_ids(&self, tables: &Tables, query: &str) -> Vec<RankedSearchResult> {
+        let ranked = |result| RankedSearchResult {
+            score: score::EXACT_ID,
+            result,
+        };
+        if let Ok(number) = query.parse::<u32>() {
+            let block = tables.blocks.select_block(number).map(|block| {
+                ranked(SearchResult::Block {
+                    number: block.number,
+                    hash: H256::from(block.hash),
+                })
+            });
+            return block.into_iter().collect();
+        }
+        let Some(hex) = query.strip_prefix("0x").or(query.strip_prefix("0X")) else {
+            return vec![];
+        };
+        match hex.len() {
+            64 => {
+                let Ok(hash) = H256::from_str(hex) else {
+                    return vec![];
+                };
+                let mut results = vec![];
+                if let Some(tx) = tables
+                    .transactions
+                    .select_by_hash(hash.0)
+                    .ok()
+                    .and_then(|rows| rows.execute().into_iter().next())
+                {
+                    results.push(ranked(SearchResult::Transaction {
+                        hash,
+                        block_number: tx.block_number,
+                    }));
+                }
+                if let Some(block) = tables.blocks.select_block_by_hash(hash.0) {
+                    results.push(ranked(SearchResult::Block {
+                        number: block.number,
+                        hash,
+                    }));
+                }
+                results
+            }
+            40 => {
+                let Ok(address) = H160::from_str(hex) else {
+                    return vec![];
+                };
+                vec![ranked(SearchResult::Address {
+                    address,
+                    labels: tables.address_labels.label_views_of(address.into()),
+                })]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Exact and prefix matches on token symbols, token names and labels
+    fn search_names(&self, query: &str) -> Vec<RankedSearchResult> {
+        let key = query.to_lowercase();
+        let names = self.names.read().unwrap();
+        let mut matches: Vec<(u32, u64, &IndexedName)> = names
+            .range(key.clone()..)
+            .take_while(|(name, _)| name.starts_with(&key))
+            .flat_map(|(name, entries)| {
+                let exact = *name == key;
+                // shorter completions are closer to what was typed
+                let distance = (name.len() - key.len()) as u32;
+                entries.iter().map(move |entry| {
+                    let base = match (entry.kind, exact) {
+                        (NameKind::TokenSymbol, true) => score::EXACT_TOKEN_SYMBOL,
+                        (NameKind::Label, true) => score::EXACT_LABEL,
+                        (NameKind::TokenName, true) => score::EXACT_TOKEN_NAME,
+                        (NameKind::TokenSymbol, false) => score::PREFIX_TOKEN_SYMBOL,
+                        (NameKind::Label, false) => score::PREFIX_LABEL,
+                        (NameKind::TokenName, false) => score::PREFIX_TOKEN_NAME,
+                    };
+                    let score = base
+                        .saturating_sub(entry.penalty)
+                        .saturating_sub(distance.min(99));
+                    (score, entry.tie_break, entry)
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        // a token matching by symbol and name is reported once
+        let mut seen = vec![];
+        let mut results = vec![];
+        for (score, _, entry) in matches {
+            if seen.contains(&entry.result) {
+                continue;
+            }
+            seen.push(entry.result.clone());
+            results.push(RankedSearchResult {
+                score,
+                result: entry.result.clone(),
+            });
+            if results.len() == MAX_RESULTS {
+                break;
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::models::MapCoinPlatform;
+    use ethers::types::U256;
+    use crate::tables::TokenRow;
+
+    const BSC_PLATFORM: &str = "BNB Smart Chain (BEP20)";
+
+    fn coin(id: i32, symbol: &str, platform: Option<(&str, H160)>) -> MapCoinInfo {
+        MapCoinInfo {
+            id,
+            rank: id,
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            slug: symbol.to_lowercase(),
+            is_active: 1,
+            first_historical_data: String::new(),
+            last_historical_data: String::new(),
+            platform: platform.map(|(name, address)| MapCoinPlatform {
+                id: 0,
+                name: name.to_string(),
+                symbol: String::new(),
+                slug: String::new(),
+                token_address: format!("{:?}", address),
+            }),
+        }
+    }
+
+    fn token_addresses(results: &[RankedSearchResult]) -> Vec<Option<H160>> {
+        results
+            .iter()
+            .filter_map(|ranked| match &ranked.result {
+                SearchResult::Token { address, .. } => Some(*address),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_numeric_token_symbols() {
+        let tables = Tables::default();
+        tables
+            .insert_token(TokenRow {
+                id: 0,
+                token_contract_hash: format!("{:?}", H160::repeat_byte(1)),
+                name: "Four Oh Four".to_string(),
+                symbol: "404".to_string(),
+                decimals: 18,
+                standard: 0,
+                total_supply: U256::zero().into(),
+                max_supply: 0,
+                cmc_id: None,
+                price_usd: 0.0,
+                onchain_cap: 0,
+                circulating_cap: 0,
+                transfers: String::new(),
+            })
+            .unwrap();
+        let index = SearchIndex::new(Chain::Mainnet);
+        index.refresh(&tables);
+        let results = index.search(&tables, "404");
+        assert_eq!(token_addresses(&results), vec![Some(H160::repeat_byte(1))]);
+    }
+
+    #[test]
+    fn takes_cmc_contracts_on_the_indexed_chain_only() {
+        let tables = Tables::default();
+        let index = SearchIndex::new(Chain::BinanceSmartChain);
+        index.set_cmc_coins(vec![
+            coin(1, "CAKE", Some((BSC_PLATFORM, H160::repeat_byte(2)))),
+            coin(2, "CAKEETH", Some(("Ethereum", H160::repeat_byte(3)))),
+        ]);
+        index.refresh(&tables);
+        let results = index.search(&tables, "cake");
+        assert_eq!(
+            token_addresses(&results),
+            vec![Some(H160::repeat_byte(2)), None]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use endpoint_libs::libs::toolbox::{CustomError, RequestContext};
+use eyre::*;
+use num_traits::FromPrimitive;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::*;
+
+use crate::api::EthersClient;
+use crate::events::EventBus;
+use crate::model::{EnumErrorCode, EnumRole};
+use crate::shared_method::ensure_user_role;
+use crate::tables::Tables;
+use etherscan::Etherscan;
+use ws::Subscriptions;
+
+pub mod etherscan;
+pub mod method;
+pub mod ws;
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// A single API method, served as `POST /api/<NAME>` with a JSON body.
+///
+/// The caller's role is checked against `MIN_ROLE` before the request is even
+/// parsed, so endpoints never see requests from callers below it.
+#[async_trait]
+pub trait Endpoint: Send + Sync + 'static {
+    type Request: DeserializeOwned + Send;
+    type Response: Serialize;
+
+    const NAME: &'static str;
+    const METHOD_ID: u32;
+    const MIN_ROLE: EnumRole;
+
+    async fn handle(&self, ctx: RequestContext, req: Self::Request) -> Result<Self::Response>;
+}
+
+#[async_trait]
+trait ErasedEndpoint: Send + Sync {
+    fn method_id(&self) -> u32;
+    async fn call(&self, ctx: RequestContext, req: Value) -> Result<Value>;
+}
+
+#[async_trait]
+impl<T: Endpoint> ErasedEndpoint for T {
+    fn method_id(&self) -> u32 {
+        T::METHOD_ID
+    }
+    async fn call(&self, ctx: RequestContext, req: Value) -> Result<Value> {
+        ensure_user_role(ctx, T::MIN_ROLE)?;
+        let req = serde_json::from_value(req)
+            .map_err(|err| CustomError::new(EnumErrorCode::BadRequest, err.to_string()))?;
+        let resp = self.handle(ctx, req).await?;
+        Ok(serde_json::to_value(resp)?)
+    }
+}
+
+pub struct ApiServer {
+    endpoints: HashMap<&'static str, Arc<dyn ErasedEndpoint>>,
+    api_keys: HashMap<String, EnumRole>,
+    subscriptions: Option<Subscriptions>,
+    etherscan: Option<Etherscan>,
+}
+
+impl ApiServer {
+    pub fn new(api_keys: HashMap<String, EnumRole>) -> Self {
+        Self {
+            endpoints: HashMap::new(),
+            api_keys,
+            subscriptions: None,
+            etherscan: None,
+        }
+    }
+
+    /// Serves `/ws`, pushing the ingester's events to subscribed clients
+    pub fn enable_subscriptions(&mut self, events: EventBus, tables: Arc<Tables>) -> &mut Self {
+        self.subscriptions = Some(Subscriptions { events, tables });
+        self
+    }
+
+    pub fn add_endpoint<T: Endpoint>(&mut self, endpoint: T) -> &mut Self {
+        let previous = self.endpoints.insert(T::NAME, Arc::new(endpoint));
+        assert!(previous.is_none(), "endpoint {} registered twice", T::NAME);
+        self
+    }
+
+    /// Serves the Etherscan compatible `GET /etherscan/api`
+    pub fn enable_etherscan(&mut self, tables: Arc<Tables>, api: Arc<EthersClient>) -> &mut Self {
+        self.etherscan = Some(Etherscan { tables, api });
+        self
+    }
+
+    /// Role of the caller, anonymous callers are `Public`
+    pub fn resolve_role(&self, headers: &HeaderMap) -> Result<EnumRole> {
+        let Some(key) = headers.get(API_KEY_HEADER) else {
+            return Ok(EnumRole::Public);
+        };
+        let role = key
+            .to_str()
+            .ok()
+            .and_then(|key| self.api_keys.get(key))
+            .copied()
+            .ok_or_else(|| CustomError::new(EnumErrorCode::Unauthorized, "Unknown API key"))?;
+        Ok(role)
+    }
+
+    pub fn into_router(self) -> Router {
+        let mut router = Router::new().route("/api/:method", post(handle_request));
+        if self.subscriptions.is_some() {
+            router = router.route("/ws", get(ws::handle_upgrade));
+        }
+        if self.etherscan.is_some() {
+            router = router.route("/etherscan/api", get(etherscan::handle_request));
+        }
+        router.with_state(Arc::new(self))
+    }
+}
+
+/// Parses `<key>=<role>`, as passed on the command line
+pub fn parse_api_key(s: &str) -> Result<(String, EnumRole)> {
+    let (key, role) = s
+        .split_once('=')
+        .with_context(|| format!("API key must be <key>=<role>: {}", s))?;
+    ensure!(!key.is_empty(), "API key must not be empty");
+    Ok((key.to_string(), role.parse()?))
+}
+
+pub async fn serve(router: Router, addr: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("API listening on {}", addr);
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn handle_request(
+    State(server): State<Arc<ApiServer>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(method): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<Value>,
+) -> Response {
+    let result = async {
+        let endpoint = server.endpoints.get(method.as_str()).ok_or_else(|| {
+            CustomError::new(EnumErrorCode::UnknownMethod, format!("Unknown method {}", method))
+        })?;
+        let role = server.resolve_role(&headers)?;
+        let ctx = RequestContext {
+            connection_id: 0,
+            user_id: 0,
+            seq: 0,
+            method: endpoint.method_id(),
+            log_id: 0,
+            role: role as u32,
+            ip_addr: addr.ip(),
+        };
+        endpoint.call(ctx, req).await
+    }
+    .await;
+
+    match result {
+        Result::Ok(data) => Json(data).into_response(),
+        Err(err) => error_response(&method, err),
+    }
+}
+
+/// Error code and reason sent to clients, internal errors are not exposed
+pub fn error_code_and_reason(err: &Error) -> (u32, Value) {
+    match err.downcast_ref::<CustomError>() {
+        Some(custom) => (custom.code.to_u32(), custom.params.clone()),
+        None => (
+            EnumErrorCode::InternalError as u32,
+            Value::from("Internal error"),
+        ),
+    }
+}
+
+pub fn error_response(method: &str, err: Error) -> Response {
+    let (code, reason) = error_code_and_reason(&err);
+    let status = match EnumErrorCode::from_u32(code) {
+        Some(EnumErrorCode::BadRequest) => StatusCode::BAD_REQUEST,
+        Some(EnumErrorCode::Unauthorized) => StatusCode::UNAUTHORIZED,
+        Some(EnumErrorCode::InvalidRole) => StatusCode::FORBIDDEN,
+        Some(EnumErrorCode::NotFound) | Some(EnumErrorCode::UnknownMethod) => {
+            StatusCode::NOT_FOUND
+        }
+        Some(EnumErrorCode::InternalError) | None => {
+            error!("{} failed: {:?}", method, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (
+        status,
+        Json(json!({
+            "code": code,
+            "reason": reason,
+        })),
+    )
+        .into_response()
+}
@@ -0,0 +1,521 @@
+//! Subset of the Etherscan API served from the in-memory tables, so scripts
+//! written against Etherscan only need their base URL changed.
+//!
+//! Like Etherscan, every answer is HTTP 200 and failures are reported with
+//! `"status": "0"` in the body.
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
+use endpoint_libs::libs::toolbox::RequestContext;
+use ethers::types::{BlockId, BlockNumber, H160, H256};
+use ethers::utils::hex;
+use eyre::{bail, ContextCompat};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::method::ON_DEMAND_ANALYSIS_ROLE;
+use super::ApiServer;
+use crate::api::EthersClient;
+use crate::model::EnumRole;
+use crate::rkyv_wrappers::WrappedAddress;
+use crate::shared_method::ensure_user_role;
+use crate::tables::{AddressActivity, BlockRow, Tables, TokenTransferRow, TransactionRow};
+
+pub const ETHERSCAN_METHOD_ID: u32 = 22000;
+pub const ETHERSCAN_MIN_ROLE: EnumRole = EnumRole::Public;
+/// Etherscan refuses `page * offset` above this
+const MAX_RESULT_WINDOW: usize = 10_000;
+
+pub struct Etherscan {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EtherscanQuery {
+    pub module: Option<String>,
+    pub action: Option<String>,
+    pub apikey: Option<String>,
+    pub address: Option<String>,
+    pub contractaddress: Option<String>,
+    pub startblock: Option<String>,
+    pub endblock: Option<String>,
+    pub page: Option<String>,
+    pub offset: Option<String>,
+    pub sort: Option<String>,
+    pub tag: Option<String>,
+    pub timestamp: Option<String>,
+    pub closest: Option<String>,
+    pub txhash: Option<String>,
+    pub boolean: Option<String>,
+    pub to: Option<String>,
+    pub data: Option<String>,
+    pub id: Option<Value>,
+}
+
+/// `status: 0` answer, `result` carries the reason as Etherscan does
+struct EtherscanError {
+    message: &'static str,
+    result: Value,
+}
+
+impl EtherscanError {
+    fn notok(reason: impl Into<String>) -> Self {
+        Self {
+            message: "NOTOK",
+            result: Value::String(reason.into()),
+        }
+    }
+    fn empty(message: &'static str) -> Self {
+        Self {
+            message,
+            result: Value::Array(vec![]),
+        }
+    }
+}
+
+type EtherscanResult = std::result::Result<Value, EtherscanError>;
+
+fn ok(result: Value) -> Json<Value> {
+    Json(json!({
+        "status": "1",
+        "message": "OK",
+        "result": result,
+    }))
+}
+
+pub(super) async fn handle_request(
+    State(server): State<Arc<ApiServer>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<EtherscanQuery>,
+) -> Json<Value> {
+    let etherscan = match server.etherscan.as_ref() {
+        Some(etherscan) => etherscan,
+        None => return error(EtherscanError::notok("Etherscan API is not enabled")),
+    };
+    // Etherscan clients always send their own key, one that isn't ours is
+    // served as a public caller rather than rejected
+    let role = match query.apikey.as_ref().and_then(|key| server.api_keys.get(key)) {
+        Some(role) => *role,
+        None => server.resolve_role(&headers).unwrap_or(EnumRole::Public),
+    };
+    let ctx = RequestContext {
+        connection_id: 0,
+        user_id: 0,
+        seq: 0,
+        method: ETHERSCAN_METHOD_ID,
+        log_id: 0,
+        role: role as u32,
+        ip_addr: addr.ip(),
+    };
+    if ensure_user_role(ctx, ETHERSCAN_MIN_ROLE).is_err() {
+        return error(EtherscanError::notok("Insufficient role"));
+    }
+
+    let module = query.module.clone().unwrap_or_default();
+    let action = query.action.clone().unwrap_or_default();
+    // proxied calls and balances are asked of the node, public callers are
+    // only served what is ingested
+    let asks_node = module == "proxy" || (module == "account" && action == "balance");
+    if asks_node && role < ON_DEMAND_ANALYSIS_ROLE {
+        return error(EtherscanError::notok("Insufficient role"));
+    }
+    if module == "proxy" {
+        return Json(etherscan.proxy(&action, &query).await);
+    }
+    let result = match (module.as_str(), action.as_str()) {
+        ("account", "txlist") => etherscan.txlist(&query),
+        ("account", "tokentx") => etherscan.tokentx(&query),
+        ("account", "balance") => etherscan.balance(&query).await,
+        ("block", "getblocknobytime") => etherscan.getblocknobytime(&query),
+        _ => Err(EtherscanError::notok(format!(
+            "Error! Missing Or invalid Module name / Action name ({}/{})",
+            module, action
+        ))),
+    };
+    match result {
+        Ok(result) => ok(result),
+        Err(err) => error(err),
+    }
+}
+
+fn error(err: EtherscanError) -> Json<Value> {
+    Json(json!({
+        "status": "0",
+        "message": err.message,
+        "result": err.result,
+    }))
+}
+
+fn parse_address(value: Option<&String>, name: &str) -> std::result::Result<H160, EtherscanError> {
+    let value = value.ok_or_else(|| EtherscanError::notok(format!("Error! Missing {}", name)))?;
+    H160::from_str(value.trim())
+        .map_err(|_| EtherscanError::notok(format!("Error! Invalid {} format", name)))
+}
+
+/// `None` if the parameter is missing or empty
+fn parse_optional_number<T: FromStr>(
+    value: Option<&String>,
+    name: &str,
+) -> std::result::Result<Option<T>, EtherscanError> {
+    match value.map(|value| value.trim()) {
+        None | Some("") => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| EtherscanError::notok(format!("Error! Invalid {}", name))),
+    }
+}
+
+fn parse_number<T: FromStr>(
+    value: Option<&String>,
+    name: &str,
+    default: T,
+) -> std::result::Result<T, EtherscanError> {
+    Ok(parse_optional_number(value, name)?.unwrap_or(default))
+}
+
+/// `startblock`, `endblock`, `sort`, `page` and `offset` as Etherscan applies
+/// them to account lists
+struct ListWindow {
+    start_block: u32,
+    end_block: u32,
+    descending: bool,
+    page: usize,
+    offset: usize,
+}
+
+impl ListWindow {
+    fn from_query(query: &EtherscanQuery) -> std::result::Result<Self, EtherscanError> {
+        let end_block = match query.endblock.as_deref() {
+            Some("latest") => u32::MAX,
+            _ => parse_number(query.endblock.as_ref(), "endblock", u32::MAX)?,
+        };
+        let window = Self {
+            start_block: parse_number(query.startblock.as_ref(), "startblock", 0)?,
+            end_block,
+            descending: query.sort.as_deref() == Some("desc"),
+            page: parse_number(query.page.as_ref(), "page", 1)?.max(1),
+            offset: parse_number(query.offset.as_ref(), "offset", MAX_RESULT_WINDOW)?,
+        };
+        if window.offset == 0 || window.page.saturating_mul(window.offset) > MAX_RESULT_WINDOW {
+            return Err(EtherscanError::notok(
+                "Result window is too large, PageNo x Offset size must be less than or equal to 10000",
+            ));
+        }
+        Ok(window)
+    }
+
+    /// Cuts the page out of the rows of `address` ordered by
+    /// `(block_number, id)`. `select` looks up each row and may filter it
+    /// out, `ids` is the full range of the row ids
+    fn page<Id: Copy + Ord, T>(
+        &self,
+        activity: &AddressActivity<Id>,
+        address: &WrappedAddress,
+        ids: (Id, Id),
+        select: impl FnMut(Id) -> Option<T>,
+    ) -> Vec<T> {
+        activity.page(
+            address,
+            (self.start_block, ids.0)..=(self.end_block, ids.1),
+            self.descending,
+            (self.page - 1) * self.offset,
+            self.offset,
+            select,
+        )
+    }
+}
+
+impl Etherscan {
+    fn confirmations(&self, block_number: u32) -> String {
+        self.tables
+            .ingested
+            .bounds()
+            .map(|(_, last)| (last.saturating_sub(block_number) + 1).to_string())
+            .unwrap_or_default()
+    }
+
+    fn txlist(&self, query: &EtherscanQuery) -> EtherscanResult {
+        let address = parse_address(query.address.as_ref(), "address")?;
+        let window = ListWindow::from_query(query)?;
+        let rows = window.page(
+            &self.tables.address_transactions,
+            &address.into(),
+            (0, u32::MAX),
+            |id| self.tables.transactions.select(id),
+        );
+        if rows.is_empty() {
+            return Err(EtherscanError::empty("No transactions found"));
+        }
+        Ok(Value::Array(
+            rows.into_iter().map(|row| self.tx_json(row)).collect(),
+        ))
+    }
+
+    fn tx_json(&self, row: TransactionRow) -> Value {
+        let gas_price = row
+            .gas
+            .map(|gas_price| ethers::types::U256::from(gas_price).to_string())
+            .unwrap_or_default();
+        let method_id = row
+            .input
+            .get(..4)
+            .map(|selector| format!("0x{}", hex::encode(selector)))
+            .unwrap_or_default();
+        let function_name = self
+            .tables
+            .decode_call(row.to_address.clone().map(Into::into), &row.input)
+            .map(|call| call.signature)
+            .unwrap_or_default();
+        let is_creation = row.to_address.is_none();
+        let mut json = json!({
+            "blockNumber": row.block_number.to_string(),
+            "timeStamp": row.timestamp_s.to_string(),
+            "hash": format!("{:?}", H256::from(row.hash)),
+            "nonce": row.nonce.to_string(),
+            "from": format!("{:?}", H160::from(row.from_address)),
+            "to": row.to_address.map(|to| format!("{:?}", H160::from(to))).unwrap_or_default(),
+            "value": ethers::types::U256::from(row.value).to_string(),
+            "gas": row.gas_limit.to_string(),
+            "gasPrice": gas_price,
+            "isError": if row.status == 1 { "0" } else { "1" },
+            "txreceipt_status": row.status.to_string(),
+            "input": format!("0x{}", hex::encode(&row.input)),
+            "gasUsed": row.gas_used.to_string(),
+            "confirmations": self.confirmations(row.block_number),
+            "methodId": method_id,
+            "functionName": function_name,
+        });
+        // the created contract's address isn't kept with the transaction
+        if !is_creation {
+            json["contractAddress"] = Value::String(String::new());
+        }
+        self.add_block_hash(&mut json, row.block_number);
+        json
+    }
+
+    /// Left out when the block isn't in the tables, like any field we have
+    /// no value for, rather than sent empty
+    fn add_block_hash(&self, json: &mut Value, block_number: u32) {
+        if let Some(block) = self.tables.blocks.select_block(block_number) {
+            json["blockHash"] = Value::String(format!("{:?}", H256::from(block.hash)));
+        }
+    }
+
+    fn tokentx(&self, query: &EtherscanQuery) -> EtherscanResult {
+        let address = query
+            .address
+            .as_ref()
+            .map(|address| parse_address(Some(address), "address"))
+            .transpose()?;
+        let contract = query
+            .contractaddress
+            .as_ref()
+            .map(|contract| parse_address(Some(contract), "contractaddress"))
+            .transpose()?;
+        let window = ListWindow::from_query(query)?;
+
+        let transfers = &self.tables.token_transfers;
+        let rows = match (address, contract) {
+            (Some(address), contract) => window.page(
+                &self.tables.address_transfers,
+                &address.into(),
+                (0, u64::MAX),
+                |id| {
+                    transfers.select(id).filter(|row| {
+                        contract.is_none_or(|contract| {
+                            row.token_address == WrappedAddress::from(contract)
+                        })
+                    })
+                },
+            ),
+            (None, Some(contract)) => window.page(
+                &self.tables.token_transfers_by_token,
+                &contract.into(),
+                (0, u64::MAX),
+                |id| transfers.select(id),
+            ),
+            (None, None) => return Err(EtherscanError::notok("Error! Missing address")),
+        };
+        if rows.is_empty() {
+            return Err(EtherscanError::empty("No transactions found"));
+        }
+        Ok(Value::Array(
+            rows.into_iter().map(|row| self.token_transfer_json(row)).collect(),
+        ))
+    }
+
+    fn token_transfer_json(&self, row: TokenTransferRow) -> Value {
+        let mut json = json!({
+            "blockNumber": row.block_number.to_string(),
+            "timeStamp": row.timestamp_s.to_string(),
+            "hash": format!("{:?}", H256::from(row.tx_hash)),
+            "from": format!("{:?}", H160::from(row.from_address)),
+            "contractAddress": format!("{:?}", H160::from(row.token_address.clone())),
+            "to": format!("{:?}", H160::from(row.to_address)),
+            "value": ethers::types::U256::from(row.value).to_string(),
+            "logIndex": row.log_index.to_string(),
+            "input": "deprecated",
+            "confirmations": self.confirmations(row.block_number),
+        });
+        if let Some(token) = self.tables.tokens.select_token(row.token_address.into()) {
+            json["tokenName"] = Value::String(token.name);
+            json["tokenSymbol"] = Value::String(token.symbol);
+            json["tokenDecimal"] = Value::String(token.decimals.to_string());
+        }
+        let tx = self
+            .tables
+            .transactions
+            .select_by_hash(row.tx_hash)
+            .ok()
+            .and_then(|rows| rows.execute().into_iter().next());
+        if let Some(tx) = tx {
+            json["nonce"] = Value::String(tx.nonce.to_string());
+            json["gas"] = Value::String(tx.gas_limit.to_string());
+            json["gasUsed"] = Value::String(tx.gas_used.to_string());
+            if let Some(gas_price) = tx.gas {
+                json["gasPrice"] = Value::String(ethers::types::U256::from(gas_price).to_string());
+            }
+        }
+        self.add_block_hash(&mut json, row.block_number);
+        json
+    }
+
+    async fn balance(&self, query: &EtherscanQuery) -> EtherscanResult {
+        let address = parse_address(query.address.as_ref(), "address")?;
+        let tag = parse_block_tag(query.tag.as_deref())?;
+        let balance = self
+            .api
+            .get_account_balance(address, BlockId::Number(tag))
+            .await
+            .map_err(|err| EtherscanError::notok(format!("Error! {}", err)))?;
+        Ok(Value::String(balance.to_string()))
+    }
+
+    /// Binary search over ingested blocks, timestamps grow with block numbers
+    fn getblocknobytime(&self, query: &EtherscanQuery) -> EtherscanResult {
+        let timestamp: u32 = parse_optional_number(query.timestamp.as_ref(), "timestamp")?
+            .ok_or_else(|| EtherscanError::notok("Error! Missing timestamp"))?;
+        let before = match query.closest.as_deref() {
+            Some("before") | None => true,
+            Some("after") => false,
+            Some(_) => return Err(EtherscanError::notok("Error! Invalid closest value")),
+        };
+        let (mut lo, mut hi) = self
+            .tables
+            .ingested
+            .bounds()
+            .ok_or_else(|| EtherscanError::notok("Error! No closest block found"))?;
+
+        // last block at or before the timestamp, or the first one at or after it
+        let mut found: Option<BlockRow> = None;
+        while lo <= hi {
+            let mid = lo + (hi - lo) / 2;
+            let Some(block) = self.nearest_block_at_or_below(mid, lo) else {
+                lo = mid + 1;
+                continue;
+            };
+            let go_up = if before {
+                block.timestamp_s <= timestamp
+            } else {
+                block.timestamp_s < timestamp
+            };
+            let number = block.number;
+            if go_up == before {
+                found = Some(block);
+            }
+            if go_up {
+                lo = mid + 1;
+            } else {
+                match number.checked_sub(1) {
+                    Some(prev) => hi = prev,
+                    None => break,
+                }
+            }
+        }
+        found
+            .map(|block| Value::String(block.number.to_string()))
+            .ok_or_else(|| EtherscanError::notok("Error! No closest block found"))
+    }
+
+    /// Skips over blocks that failed to ingest
+    fn nearest_block_at_or_below(&self, number: u32, floor: u32) -> Option<BlockRow> {
+        (floor..=number)
+            .rev()
+            .find_map(|number| self.tables.blocks.select_block(number))
+    }
+
+    /// `module=proxy` forwards the call to the node and answers JSON-RPC style,
+    /// for callers from `ON_DEMAND_ANALYSIS_ROLE` only
+    async fn proxy(&self, action: &str, query: &EtherscanQuery) -> Value {
+        let id = query.id.clone().unwrap_or(json!(1));
+        let result = async {
+            let tag = || {
+                query
+                    .tag
+                    .clone()
+                    .map(Value::String)
+                    .unwrap_or(json!("latest"))
+            };
+            let params = match action {
+                "eth_blockNumber" | "eth_gasPrice" => json!([]),
+                "eth_getBlockByNumber" => json!([tag(), query.boolean.as_deref() == Some("true")]),
+                "eth_getBlockTransactionCountByNumber" => json!([tag()]),
+                "eth_getTransactionByHash" | "eth_getTransactionReceipt" => {
+                    json!([query.txhash.clone().context("Missing txhash")?])
+                }
+                "eth_getTransactionCount" | "eth_getCode" => {
+                    json!([query.address.clone().context("Missing address")?, tag()])
+                }
+                "eth_call" => json!([
+                    {
+                        "to": query.to.clone().context("Missing to")?,
+                        "data": query.data.clone().unwrap_or_default(),
+                    },
+                    tag()
+                ]),
+                _ => bail!("Unsupported proxy action {}", action),
+            };
+            let result: Value = self.api.request(action, params).await?;
+            Ok::<_, eyre::Error>(result)
+        }
+        .await;
+        match result {
+            Ok(result) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result,
+            }),
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": -32000,
+                    "message": err.to_string(),
+                },
+            }),
+        }
+    }
+}
+
+fn parse_block_tag(tag: Option<&str>) -> std::result::Result<BlockNumber, EtherscanError> {
+    match tag {
+        None | Some("latest") => Ok(BlockNumber::Latest),
+        Some("pending") => Ok(BlockNumber::Pending),
+        Some("earliest") => Ok(BlockNumber::Earliest),
+        Some(tag) => {
+            let number = match tag.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => tag.parse().ok(),
+            };
+            number
+                .map(|number| BlockNumber::Number(number.into()))
+                .ok_or_else(|| EtherscanError::notok("Error! Invalid tag"))
+        }
+    }
+}
@@ -0,0 +1,953 @@
+use async_trait::async_trait;
+use endpoint_libs::libs::toolbox::{CustomError, RequestContext};
+use ethers::types::{Chain, Log, Transaction, H256};
+use eyre::*;
+use num_traits::FromPrimitive;
+use std::sync::Arc;
+
+use super::{ApiServer, Endpoint};
+use crate::api::aggregate::AggregatePriceClient;
+use crate::api::assets::{AssetId, AssetInfoClient};
+use crate::api::EthersClient;
+use crate::bytecode::analyze_contract;
+use crate::dex::{DexPriceOracle, PricePoint};
+use crate::gas::GasOracle;
+use crate::labels::{parse_labels, LabelRecord};
+use crate::model::*;
+use crate::portfolio::{value_portfolio, NativeCoin};
+use crate::price_history::PriceHistory;
+use crate::search::SearchIndex;
+use crate::shared_method::ensure_user_role;
+use crate::stats::{latest_block_timestamp, network_stats_series};
+use crate::tables::{BlockRow, Tables, TokenTransferRow, TransactionRow, WhaleAlertRow};
+use crate::tokens::{detect_token, fetch_balance_of};
+
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const MAX_PAGE_SIZE: u32 = 100;
+const DEFAULT_STATS_BUCKETS: u32 = 30;
+/// Assets priced per `get_asset_prices` request, each is a query to every
+/// price source
+const MAX_PRICED_ASSETS: usize = 100;
+/// Callers from this role may have an address nobody looked at analyzed on
+/// the spot. Public callers are only served stored rows, so they can't make
+/// the node do calls, spend price source credits or make the tables grow
+pub(super) const ON_DEMAND_ANALYSIS_ROLE: EnumRole = EnumRole::User;
+
+fn cents_to_usd(cents: u64) -> f64 {
+    cents as f64 / 100.0
+}
+
+/// Attaches the labels of both parties, every address in a response is labelled
+pub fn transaction_view(tables: &Tables, row: TransactionRow) -> TransactionView {
+    let from_labels = tables
+        .address_labels
+        .label_views_of(row.from_address.clone());
+    let to_labels = row
+        .to_address
+        .clone()
+        .map(|address| tables.address_labels.label_views_of(address))
+        .unwrap_or_default();
+    let decoded_input = tables.decode_call(row.to_address.clone().map(Into::into), &row.input);
+    TransactionView {
+        id: row.id,
+        hash: H256::from(row.hash),
+        status: row.status,
+        block_number: row.block_number,
+        timestamp_s: row.timestamp_s,
+        from_address: row.from_address.into(),
+        to_address: row.to_address.map(Into::into),
+        value: row.value.into(),
+        fee: row.fee.into(),
+        value_usd: row.value_usd_cents.map(cents_to_usd),
+        fee_usd: row.fee_usd_cents.map(cents_to_usd),
+        gas_price: row.gas.map(Into::into),
+        method: decoded_input.as_ref().map(|call| call.name.clone()),
+        decoded_input,
+        input: row.input.into(),
+        from_labels,
+        to_labels,
+    }
+}
+
+pub fn token_transfer_view(tables: &Tables, row: TokenTransferRow) -> TokenTransferView {
+    TokenTransferView {
+        id: row.id,
+        tx_hash: H256::from(row.tx_hash),
+        log_index: row.log_index,
+        block_number: row.block_number,
+        timestamp_s: row.timestamp_s,
+        token_address: row.token_address.into(),
+        from_labels: tables
+            .address_labels
+            .label_views_of(row.from_address.clone()),
+        to_labels: tables.address_labels.label_views_of(row.to_address.clone()),
+        from_address: row.from_address.into(),
+        to_address: row.to_address.into(),
+        value: row.value.into(),
+        token_id: row.token_id.map(Into::into),
+        value_usd: row.value_usd_cents.map(cents_to_usd),
+    }
+}
+
+pub fn whale_alert_view(tables: &Tables, row: WhaleAlertRow) -> WhaleAlertView {
+    WhaleAlertView {
+        id: row.id,
+        kind: EnumAlertKind::from_u8(row.kind).unwrap_or(EnumAlertKind::Transaction),
+        tx_hash: H256::from(row.tx_hash),
+        block_number: row.block_number,
+        timestamp_s: row.timestamp_s,
+        token_address: row.token_address.map(Into::into),
+        symbol: row.symbol,
+        from_labels: tables
+            .address_labels
+            .label_views_of(row.from_address.clone()),
+        to_labels: tables.address_labels.label_views_of(row.to_address.clone()),
+        from_address: row.from_address.into(),
+        to_address: row.to_address.into(),
+        amount: row.amount.into(),
+        usd_value: cents_to_usd(row.usd_value_cents),
+        exchange_flow: EnumExchangeFlow::from_u8(row.exchange_flow).unwrap_or(EnumExchangeFlow::None),
+    }
+}
+
+pub fn pending_transaction_view(tx: &Transaction) -> PendingTransactionView {
+    PendingTransactionView {
+        hash: tx.hash,
+        nonce: tx.nonce,
+        from_address: tx.from,
+        to_address: tx.to,
+        value: tx.value,
+        gas: tx.gas,
+        gas_price: tx.gas_price,
+        max_fee_per_gas: tx.max_fee_per_gas,
+        max_priority_fee_per_gas: tx.max_priority_fee_per_gas,
+    }
+}
+
+impl From<BlockRow> for BlockView {
+    fn from(row: BlockRow) -> Self {
+        Self {
+            number: row.number,
+            hash: H256::from(row.hash),
+            status: row.status,
+            timestamp_s: row.timestamp_s,
+            transactions: row.transactions,
+            eth_price_usd_cents: row.eth_price_usd_cents,
+        }
+    }
+}
+
+pub fn not_found(what: impl std::fmt::Display) -> Error {
+    CustomError::new(EnumErrorCode::NotFound, format!("{} not found", what)).into()
+}
+
+pub struct MethodGetBlock {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetBlock {
+    type Request = GetBlockRequest;
+    type Response = BlockView;
+
+    const NAME: &'static str = "get_block";
+    const METHOD_ID: u32 = 20000;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let block = self
+            .tables
+            .blocks
+            .select_block(req.number)
+            .ok_or_else(|| not_found(format!("Block {}", req.number)))?;
+        Ok(block.into())
+    }
+}
+
+pub struct MethodGetTransaction {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetTransaction {
+    type Request = GetTransactionRequest;
+    type Response = TransactionView;
+
+    const NAME: &'static str = "get_transaction";
+    const METHOD_ID: u32 = 20010;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let tx = self
+            .tables
+            .transactions
+            .select_by_hash(req.hash.0)
+            .ok()
+            .and_then(|rows| rows.execute().into_iter().next())
+            .ok_or_else(|| not_found(format!("Transaction {:?}", req.hash)))?;
+        Ok(transaction_view(&self.tables, tx))
+    }
+}
+
+pub struct MethodGetAddressTransactions {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetAddressTransactions {
+    type Request = GetAddressTransactionsRequest;
+    type Response = GetAddressTransactionsResponse;
+
+    const NAME: &'static str = "get_address_transactions";
+    const METHOD_ID: u32 = 20020;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let limit = req.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let page = self
+            .tables
+            .select_address_history(req.address.into(), req.cursor, limit as usize);
+        Ok(GetAddressTransactionsResponse {
+            transactions: page
+                .transactions
+                .into_iter()
+                .map(|row| transaction_view(&self.tables, row))
+                .collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+}
+
+pub struct MethodGetAddressLabels {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetAddressLabels {
+    type Request = GetAddressLabelsRequest;
+    type Response = GetAddressLabelsResponse;
+
+    const NAME: &'static str = "get_address_labels";
+    const METHOD_ID: u32 = 20030;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        Ok(GetAddressLabelsResponse {
+            labels: self.tables.address_labels.label_views_of(req.address.into()),
+        })
+    }
+}
+
+pub struct MethodAddAddressLabel {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodAddAddressLabel {
+    type Request = AddAddressLabelRequest;
+    type Response = AddAddressLabelResponse;
+
+    const NAME: &'static str = "add_address_label";
+    const METHOD_ID: u32 = 20031;
+    const MIN_ROLE: EnumRole = EnumRole::Admin;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let record = LabelRecord {
+            address: req.address,
+            label: req.label,
+            category: req.category,
+            source: None,
+        };
+        let id = self
+            .tables
+            .address_labels
+            .add_label(record, &req.source)
+            .map_err(|err| CustomError::new(EnumErrorCode::BadRequest, err.to_string()))?;
+        Ok(AddAddressLabelResponse { id })
+    }
+}
+
+pub struct MethodRemoveAddressLabel {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodRemoveAddressLabel {
+    type Request = RemoveAddressLabelRequest;
+    type Response = RemoveAddressLabelResponse;
+
+    const NAME: &'static str = "remove_address_label";
+    const METHOD_ID: u32 = 20032;
+    const MIN_ROLE: EnumRole = EnumRole::Admin;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        if !self.tables.address_labels.remove_label(req.id).await? {
+            return Err(not_found(format!("Label {}", req.id)));
+        }
+        Ok(RemoveAddressLabelResponse {})
+    }
+}
+
+pub struct MethodImportAddressLabels {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodImportAddressLabels {
+    type Request = ImportAddressLabelsRequest;
+    type Response = ImportAddressLabelsResponse;
+
+    const NAME: &'static str = "import_address_labels";
+    const METHOD_ID: u32 = 20033;
+    const MIN_ROLE: EnumRole = EnumRole::Admin;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        // parse and validate the whole list first so a bad line is the
+        // caller's error and doesn't leave a partial import
+        let records = parse_labels(req.format, &req.data)
+            .map_err(|err| CustomError::new(EnumErrorCode::BadRequest, format!("{:#}", err)))?;
+        self.tables
+            .address_labels
+            .import_labels(records, &req.source)
+    }
+}
+
+pub struct MethodSearch {
+    pub tables: Arc<Tables>,
+    pub index: Arc<SearchIndex>,
+}
+
+#[async_trait]
+impl Endpoint for MethodSearch {
+    type Request = SearchRequest;
+    type Response = SearchResponse;
+
+    const NAME: &'static str = "search";
+    const METHOD_ID: u32 = 20040;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        Ok(SearchResponse {
+            results: self.index.search(&self.tables, &req.query),
+        })
+    }
+}
+
+pub struct MethodGetNetworkStats {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetNetworkStats {
+    type Request = GetNetworkStatsRequest;
+    type Response = GetNetworkStatsResponse;
+
+    const NAME: &'static str = "get_network_stats";
+    const METHOD_ID: u32 = 20050;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let period_s = req.period as u32;
+        let Some(to_s) = req.to_s.or_else(|| latest_block_timestamp(&self.tables)) else {
+            return Ok(GetNetworkStatsResponse {
+                period: req.period,
+                points: vec![],
+            });
+        };
+        let from_s = req
+            .from_s
+            .unwrap_or_else(|| to_s.saturating_sub((DEFAULT_STATS_BUCKETS - 1) * period_s));
+        ensure!(
+            from_s <= to_s,
+            CustomError::new(EnumErrorCode::BadRequest, "from_s is after to_s")
+        );
+        Ok(GetNetworkStatsResponse {
+            period: req.period,
+            points: network_stats_series(&self.tables, req.period, from_s, to_s),
+        })
+    }
+}
+
+pub struct MethodGetGasOracle {
+    pub oracle: Arc<GasOracle>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetGasOracle {
+    type Request = GetGasOracleRequest;
+    type Response = GetGasOracleResponse;
+
+    const NAME: &'static str = "get_gas_oracle";
+    const METHOD_ID: u32 = 20060;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, _req: Self::Request) -> Result<Self::Response> {
+        // only post-London blocks are sampled
+        self.oracle
+            .estimate()
+            .ok_or_else(|| not_found("Gas estimate"))
+    }
+}
+
+pub struct MethodGetWhaleAlerts {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetWhaleAlerts {
+    type Request = GetWhaleAlertsRequest;
+    type Response = GetWhaleAlertsResponse;
+
+    const NAME: &'static str = "get_whale_alerts";
+    const METHOD_ID: u32 = 20070;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let limit = req.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+        let rows = match req.address {
+            Some(address) => self
+                .tables
+                .whale_alerts
+                .select_address_alerts(address.into())?,
+            None => {
+                let mut rows = self.tables.whale_alerts.select_all().execute()?;
+                rows.sort_by_key(|row| std::cmp::Reverse(row.id));
+                rows
+            }
+        };
+        let alerts = rows
+            .into_iter()
+            .filter(|row| {
+                !req.exchange_flows_only || row.exchange_flow != EnumExchangeFlow::None as u8
+            })
+            .take(limit)
+            .map(|row| whale_alert_view(&self.tables, row))
+            .collect();
+        Ok(GetWhaleAlertsResponse { alerts })
+    }
+}
+
+pub struct MethodSetContractAbi {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodSetContractAbi {
+    type Request = SetContractAbiRequest;
+    type Response = SetContractAbiResponse;
+
+    const NAME: &'static str = "set_contract_abi";
+    const METHOD_ID: u32 = 20080;
+    const MIN_ROLE: EnumRole = EnumRole::Admin;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let abi = self
+            .tables
+            .set_contract_abi(req.address, req.abi.to_string())
+            .await
+            .map_err(|err| CustomError::new(EnumErrorCode::BadRequest, format!("{:#}", err)))?;
+        Ok(SetContractAbiResponse {
+            functions: abi.functions().count() as u32,
+            events: abi.events().count() as u32,
+        })
+    }
+}
+
+pub struct MethodGetContractAbi {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetContractAbi {
+    type Request = GetContractAbiRequest;
+    type Response = GetContractAbiResponse;
+
+    const NAME: &'static str = "get_contract_abi";
+    const METHOD_ID: u32 = 20081;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let abi = self
+            .tables
+            .contracts
+            .select_contract(req.address.into())
+            .and_then(|row| row.abi)
+            .ok_or_else(|| not_found(format!("ABI of {:?}", req.address)))?;
+        Ok(GetContractAbiResponse {
+            address: req.address,
+            abi: serde_json::from_str(&abi)?,
+        })
+    }
+}
+
+/// Logs of ingested transactions are stored, those of other transactions
+/// are read from the node's receipt. Both are decoded against the uploaded
+/// ABIs
+pub struct MethodGetTransactionLogs {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetTransactionLogs {
+    type Request = GetTransactionLogsRequest;
+    type Response = GetTransactionLogsResponse;
+
+    const NAME: &'static str = "get_transaction_logs";
+    const METHOD_ID: u32 = 20090;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let ingested = self
+            .tables
+            .transactions
+            .select_by_hash(req.hash.0)
+            .is_ok_and(|rows| !rows.execute().is_empty());
+        let logs = if ingested {
+            self.tables
+                .transaction_logs
+                .select_tx_logs(req.hash.0)?
+                .into_iter()
+                .map(|row| Log {
+                    address: row.address.into(),
+                    topics: row.topics.into_iter().map(H256).collect(),
+                    data: row.data.into(),
+                    block_number: Some(row.block_number.into()),
+                    transaction_hash: Some(req.hash),
+                    log_index: Some(row.log_index.into()),
+                    ..Default::default()
+                })
+                .collect()
+        } else if ensure_user_role(ctx, ON_DEMAND_ANALYSIS_ROLE).is_ok() {
+            self.api.get_transaction_receipt(req.hash).await?.logs
+        } else {
+            return Err(not_found(format!("Transaction {:?}", req.hash)));
+        };
+        let logs = logs
+            .into_iter()
+            .map(|log| LogView {
+                decoded: self.tables.decode_log(&log),
+                log_index: log.log_index,
+                address: log.address,
+                topics: log.topics,
+                data: log.data,
+            })
+            .collect();
+        Ok(GetTransactionLogsResponse { logs })
+    }
+}
+
+/// Analyses the contract's bytecode on first request, code doesn't change
+/// so later requests are answered from the `Contract` row
+pub struct MethodGetContractMetadata {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetContractMetadata {
+    type Request = GetContractMetadataRequest;
+    type Response = GetContractMetadataResponse;
+
+    const NAME: &'static str = "get_contract_metadata";
+    const METHOD_ID: u32 = 20082;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let row = match self
+            .tables
+            .contracts
+            .select_contract(req.address.into())
+            .filter(|row| row.code_hash.is_some())
+        {
+            Some(row) => row,
+            None if ensure_user_role(ctx, ON_DEMAND_ANALYSIS_ROLE).is_err() => {
+                return Err(not_found(format!("Contract {:?}", req.address)));
+            }
+            None => analyze_contract(&self.api, &self.tables, req.address, None)
+                .await
+                .map_err(|err| CustomError::new(EnumErrorCode::NotFound, format!("{:#}", err)))?,
+        };
+        let selectors = row
+            .selectors
+            .iter()
+            .map(|selector| {
+                let selector = selector.to_be_bytes();
+                SelectorView {
+                    selector: format!("0x{}", ethers::utils::hex::encode(selector)),
+                    signatures: self.tables.signatures_of(req.address, selector),
+                }
+            })
+            .collect();
+        let proxy = match (
+            row.proxy_kind.and_then(EnumProxyKind::from_u8),
+            row.implementation,
+        ) {
+            (Some(kind), Some(implementation)) => Some(ProxyView {
+                kind,
+                implementation: implementation.into(),
+                beacon: row.beacon.map(Into::into),
+                upgrades: self
+                    .tables
+                    .proxy_upgrades
+                    .select_proxy_upgrades(req.address.into())?
+                    .into_iter()
+                    .map(|upgrade| ProxyUpgradeView {
+                        implementation: upgrade.implementation.into(),
+                        block_number: upgrade.block_number,
+                        tx_hash: H256::from(upgrade.tx_hash),
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        };
+        Ok(GetContractMetadataResponse {
+            address: req.address,
+            code_hash: H256::from(row.code_hash.unwrap_or_default()),
+            compiler: row.compiler,
+            compiler_version: row.compiler_version,
+            source_hash: row.source_hash,
+            selectors,
+            proxy,
+        })
+    }
+}
+
+/// Detects tokens not seen in a `Transfer` log yet on request
+pub struct MethodGetToken {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetToken {
+    type Request = GetTokenRequest;
+    type Response = GetTokenResponse;
+
+    const NAME: &'static str = "get_token";
+    const METHOD_ID: u32 = 20100;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let row = match self.tables.tokens.select_token(req.address) {
+            Some(row) => row,
+            None if ensure_user_role(ctx, ON_DEMAND_ANALYSIS_ROLE).is_err() => {
+                return Err(not_found(format!("Token {:?}", req.address)));
+            }
+            None => detect_token(&self.api, &self.tables, req.address, None)
+                .await
+                .map_err(|err| CustomError::new(EnumErrorCode::NotFound, format!("{:#}", err)))?
+                .ok_or_else(|| not_found(format!("Token {:?}", req.address)))?,
+        };
+        Ok(GetTokenResponse {
+            address: req.address,
+            standard: EnumTokenStandard::from_u8(row.standard)
+                .with_context(|| format!("invalid token standard {}", row.standard))?,
+            name: row.name,
+            symbol: row.symbol,
+            decimals: row.decimals,
+            total_supply: row.total_supply.into(),
+        })
+    }
+}
+
+pub struct MethodGetTokenHolders {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetTokenHolders {
+    type Request = GetTokenHoldersRequest;
+    type Response = GetTokenHoldersResponse;
+
+    const NAME: &'static str = "get_token_holders";
+    const METHOD_ID: u32 = 20101;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let limit = req.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+        let balances = &self.tables.token_balances;
+        let holders = balances
+            .top_holders(req.token, limit)?
+            .into_iter()
+            .map(|row| TokenHolderView {
+                labels: self
+                    .tables
+                    .address_labels
+                    .label_views_of(row.holder.clone()),
+                holder: row.holder.into(),
+                balance: row.balance.into(),
+            })
+            .collect();
+        Ok(GetTokenHoldersResponse {
+            token: req.token,
+            holder_count: balances.holder_count(req.token)? as u32,
+            holders,
+        })
+    }
+}
+
+pub struct MethodGetAddressTokenBalances {
+    pub tables: Arc<Tables>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetAddressTokenBalances {
+    type Request = GetAddressTokenBalancesRequest;
+    type Response = GetAddressTokenBalancesResponse;
+
+    const NAME: &'static str = "get_address_token_balances";
+    const METHOD_ID: u32 = 20102;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let balances = self
+            .tables
+            .token_balances
+            .select_holder_balances(req.address)?
+            .into_iter()
+            .map(|row| {
+                let token = row.token_address.into();
+                let details = self.tables.tokens.select_token(token);
+                TokenBalanceView {
+                    token,
+                    symbol: details.as_ref().map(|details| details.symbol.clone()),
+                    decimals: details.map(|details| details.decimals),
+                    balance: row.balance.into(),
+                }
+            })
+            .collect();
+        Ok(GetAddressTokenBalancesResponse {
+            address: req.address,
+            balances,
+        })
+    }
+}
+
+/// Compares the balance tracked from transfers with the token's own
+/// `balanceOf`, they differ if transfers before the ingested range moved it.
+/// Every request is a call to the node
+pub struct MethodVerifyTokenBalance {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+}
+
+#[async_trait]
+impl Endpoint for MethodVerifyTokenBalance {
+    type Request = VerifyTokenBalanceRequest;
+    type Response = VerifyTokenBalanceResponse;
+
+    const NAME: &'static str = "verify_token_balance";
+    const METHOD_ID: u32 = 20103;
+    const MIN_ROLE: EnumRole = ON_DEMAND_ANALYSIS_ROLE;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let tracked = self.tables.token_balances.balance_of(req.token, req.holder);
+        let on_chain = fetch_balance_of(&self.api, req.token, req.holder).await?;
+        Ok(VerifyTokenBalanceResponse {
+            tracked,
+            on_chain,
+            matches: tracked == on_chain,
+        })
+    }
+}
+
+/// Reads balances from the node and prices them from the price sources on
+/// each request
+pub struct MethodGetPortfolio {
+    pub tables: Arc<Tables>,
+    pub api: Arc<EthersClient>,
+    pub prices: Arc<dyn AssetInfoClient>,
+    pub native: Option<NativeCoin>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetPortfolio {
+    type Request = GetPortfolioRequest;
+    type Response = GetPortfolioResponse;
+
+    const NAME: &'static str = "get_portfolio";
+    const METHOD_ID: u32 = 20110;
+    const MIN_ROLE: EnumRole = ON_DEMAND_ANALYSIS_ROLE;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        value_portfolio(
+            &self.api,
+            &self.tables,
+            self.prices.as_ref(),
+            self.native.as_ref(),
+            req.address,
+        )
+        .await
+    }
+}
+
+pub struct MethodGetPriceAt {
+    pub history: Arc<PriceHistory>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetPriceAt {
+    type Request = GetPriceAtRequest;
+    type Response = GetPriceAtResponse;
+
+    const NAME: &'static str = "get_price_at";
+    const METHOD_ID: u32 = 20111;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let timestamp_s = req.timestamp_s as i64;
+        // public callers are priced from the stored quotes, fetching them
+        // spends CMC credits
+        let price_usd = if ensure_user_role(ctx, ON_DEMAND_ANALYSIS_ROLE).is_ok() {
+            self.history.price_at(req.asset_id, timestamp_s).await?
+        } else {
+            self.history.stored_price_at(req.asset_id, timestamp_s)?
+        };
+        let price_usd = price_usd.ok_or_else(|| {
+            not_found(format!(
+                "Price of CMC id {} at {}",
+                req.asset_id, req.timestamp_s
+            ))
+        })?;
+        Ok(GetPriceAtResponse {
+            asset_id: req.asset_id,
+            timestamp_s: req.timestamp_s,
+            price_usd,
+        })
+    }
+}
+
+pub struct MethodGetDexPrice {
+    pub oracle: Arc<DexPriceOracle>,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetDexPrice {
+    type Request = GetDexPriceRequest;
+    type Response = GetDexPriceResponse;
+
+    const NAME: &'static str = "get_dex_price";
+    const METHOD_ID: u32 = 20112;
+    const MIN_ROLE: EnumRole = EnumRole::Public;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        let at = match req.block_number {
+            Some(number) => PricePoint::Block(number),
+            None => PricePoint::Latest,
+        };
+        let price = self
+            .oracle
+            .price_of(req.token, at)?
+            .ok_or_else(|| not_found(format!("Liquid pool of {:?}", req.token)))?;
+        Ok(GetDexPriceResponse {
+            token: req.token,
+            price_usd: price.price_usd,
+            liquidity_usd: price.liquidity_usd,
+            pool: price.pool,
+            block_number: price.block_number,
+        })
+    }
+}
+
+/// Queries every price source on each request, so it spends their credits
+pub struct MethodGetAssetPrices {
+    pub prices: Arc<AggregatePriceClient>,
+    pub chain: Chain,
+}
+
+#[async_trait]
+impl Endpoint for MethodGetAssetPrices {
+    type Request = GetAssetPricesRequest;
+    type Response = GetAssetPricesResponse;
+
+    const NAME: &'static str = "get_asset_prices";
+    const METHOD_ID: u32 = 20113;
+    const MIN_ROLE: EnumRole = ON_DEMAND_ANALYSIS_ROLE;
+
+    async fn handle(&self, _ctx: RequestContext, req: Self::Request) -> Result<Self::Response> {
+        ensure!(
+            req.cmc_ids.len() + req.tokens.len() <= MAX_PRICED_ASSETS,
+            CustomError::new(
+                EnumErrorCode::BadRequest,
+                format!("At most {} assets per request", MAX_PRICED_ASSETS)
+            )
+        );
+        let assets: Vec<AssetId> = req
+            .cmc_ids
+            .into_iter()
+            .map(AssetId::Cmc)
+            .chain(
+                req.tokens
+                    .into_iter()
+                    .map(|token| AssetId::Contract(self.chain, token)),
+            )
+            .collect();
+        let mut prices = self.prices.get_usd_prices_with_provenance(&assets).await?;
+        let prices = assets
+            .iter()
+            .filter_map(|asset| prices.remove(asset))
+            .map(|price| AssetPriceView {
+                asset: price.asset.to_string(),
+                price_usd: price.price_usd,
+                sources: price
+                    .sources
+                    .iter()
+                    .map(|source| SourcePriceView {
+                        source: source.source.clone(),
+                        price_usd: source.price_usd,
+                        age_s: source.age().num_seconds(),
+                        accepted: source.accepted,
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(GetAssetPricesResponse { prices })
+    }
+}
+
+/// Registers every endpoint backed by the chain tables
+pub fn add_table_endpoints(server: &mut ApiServer, tables: Arc<Tables>, index: Arc<SearchIndex>) {
+    server
+        .add_endpoint(MethodSearch {
+            tables: tables.clone(),
+            index,
+        })
+        .add_endpoint(MethodGetBlock {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetTransaction {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetAddressTransactions {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetAddressLabels {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodAddAddressLabel {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodRemoveAddressLabel {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodImportAddressLabels {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetNetworkStats {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetWhaleAlerts {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodSetContractAbi {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetContractAbi {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetTokenHolders {
+            tables: tables.clone(),
+        })
+        .add_endpoint(MethodGetAddressTokenBalances { tables });
+}
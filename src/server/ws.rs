@@ -0,0 +1,274 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use endpoint_libs::libs::toolbox::{CustomError, RequestContext};
+use eyre::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+
+use super::method::{
+    pending_transaction_view, token_transfer_view, transaction_view, whale_alert_view,
+};
+use super::{error_code_and_reason, error_response, ApiServer};
+use crate::events::{ChainEvent, EventBus, PendingListener};
+use crate::model::*;
+use crate::rkyv_wrappers::WrappedAddress;
+use crate::shared_method::ensure_user_role;
+use crate::tables::Tables;
+
+pub const SUBSCRIBE_METHOD_ID: u32 = 21000;
+const MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 64;
+
+/// What the `/ws` endpoint pushes and where it reads rows from
+pub struct Subscriptions {
+    pub events: EventBus,
+    pub tables: Arc<Tables>,
+}
+
+impl SubscriptionTopic {
+    pub fn min_role(&self) -> EnumRole {
+        EnumRole::Public
+    }
+
+    pub fn matches(&self, event: &ChainEvent) -> bool {
+        match (self, event) {
+            (SubscriptionTopic::NewBlocks, ChainEvent::Block(_)) => true,
+            (SubscriptionTopic::AddressTransactions { address }, ChainEvent::Transaction(row)) => {
+                let address = WrappedAddress::from(*address);
+                row.from_address == address || row.to_address.as_ref() == Some(&address)
+            }
+            (SubscriptionTopic::TokenTransfers { token }, ChainEvent::TokenTransfer(row)) => {
+                row.token_address == WrappedAddress::from(*token)
+            }
+            (SubscriptionTopic::PendingTransactions { address }, ChainEvent::PendingTransaction(tx)) => {
+                tx.from == *address || tx.to == Some(*address)
+            }
+            (
+                SubscriptionTopic::WhaleAlerts {
+                    min_usd,
+                    exchange_flows_only,
+                },
+                ChainEvent::WhaleAlert(row),
+            ) => {
+                row.usd_value_cents >= min_usd.unwrap_or(0).saturating_mul(100)
+                    && (!exchange_flows_only || row.exchange_flow != EnumExchangeFlow::None as u8)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn subscription_event(tables: &Tables, event: &ChainEvent) -> SubscriptionEvent {
+    match event {
+        ChainEvent::Block(row) => SubscriptionEvent::Block(row.clone().into()),
+        ChainEvent::Transaction(row) => {
+            SubscriptionEvent::Transaction(transaction_view(tables, row.clone()))
+        }
+        ChainEvent::TokenTransfer(row) => {
+            SubscriptionEvent::TokenTransfer(token_transfer_view(tables, row.clone()))
+        }
+        ChainEvent::PendingTransaction(tx) => {
+            SubscriptionEvent::PendingTransaction(pending_transaction_view(tx))
+        }
+        ChainEvent::WhaleAlert(row) => {
+            SubscriptionEvent::WhaleAlert(whale_alert_view(tables, row.clone()))
+        }
+    }
+}
+
+pub(super) async fn handle_upgrade(
+    State(server): State<Arc<ApiServer>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let role = match server.resolve_role(&headers) {
+        Result::Ok(role) => role,
+        Err(err) => return error_response("ws", err),
+    };
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = handle_socket(server, socket, role, addr).await {
+            debug!("WebSocket connection from {} closed: {:?}", addr, e);
+        }
+    })
+}
+
+struct Connection {
+    role: EnumRole,
+    addr: SocketAddr,
+    events: EventBus,
+    topics: HashMap<u32, SubscriptionTopic>,
+    next_subscription_id: u32,
+    /// Held while one of the topics is pending transactions
+    pending_listener: Option<PendingListener>,
+}
+
+async fn handle_socket(
+    server: Arc<ApiServer>,
+    mut socket: WebSocket,
+    role: EnumRole,
+    addr: SocketAddr,
+) -> Result<()> {
+    let subscriptions = server
+        .subscriptions
+        .as_ref()
+        .context("subscriptions are not enabled")?;
+    let mut events = subscriptions.events.subscribe();
+    let mut conn = Connection {
+        role,
+        addr,
+        events: subscriptions.events.clone(),
+        topics: HashMap::new(),
+        next_subscription_id: 1,
+        pending_listener: None,
+    };
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+                let text = match msg? {
+                    Message::Text(text) => text,
+                    Message::Close(_) => return Ok(()),
+                    _ => continue,
+                };
+                let reply = conn.handle_message(&text);
+                send(&mut socket, &reply).await?;
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Result::Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("WebSocket client {} lagged behind by {} events", addr, missed);
+                        let reply = WsServerMessage::Error {
+                            seq: None,
+                            code: EnumErrorCode::InternalError as u32,
+                            reason: format!("Connection too slow, {} events were dropped", missed),
+                        };
+                        send(&mut socket, &reply).await?;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                // the view is only built when somebody on this connection wants it
+                let mut view = None;
+                for (&subscription_id, topic) in &conn.topics {
+                    if !topic.matches(&event) {
+                        continue;
+                    }
+                    let event = Box::new(
+                        view.get_or_insert_with(|| subscription_event(&subscriptions.tables, &event))
+                            .clone(),
+                    );
+                    send(&mut socket, &WsServerMessage::Event { subscription_id, event }).await?;
+                }
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn handle_message(&mut self, text: &str) -> WsServerMessage {
+        let reply = self.reply(text);
+        let listening = self.topics.values().any(|topic| {
+            matches!(topic, SubscriptionTopic::PendingTransactions { .. })
+        });
+        if listening != self.pending_listener.is_some() {
+            self.pending_listener = listening.then(|| self.events.listen_pending());
+        }
+        reply
+    }
+
+    fn reply(&mut self, text: &str) -> WsServerMessage {
+        let msg: WsClientMessage = match serde_json::from_str(text) {
+            Result::Ok(msg) => msg,
+            Err(err) => {
+                return WsServerMessage::Error {
+                    seq: None,
+                    code: EnumErrorCode::BadRequest as u32,
+                    reason: err.to_string(),
+                }
+            }
+        };
+        match msg {
+            WsClientMessage::Subscribe { seq, topic } => match self.subscribe(seq, topic) {
+                Result::Ok(subscription_id) => WsServerMessage::Subscribed {
+                    seq,
+                    subscription_id,
+                },
+                Err(err) => error_message(seq, err),
+            },
+            WsClientMessage::Unsubscribe {
+                seq,
+                subscription_id,
+            } => {
+                if self.topics.remove(&subscription_id).is_some() {
+                    WsServerMessage::Unsubscribed {
+                        seq,
+                        subscription_id,
+                    }
+                } else {
+                    error_message(
+                        seq,
+                        CustomError::new(
+                            EnumErrorCode::NotFound,
+                            format!("Subscription {} not found", subscription_id),
+                        )
+                        .into(),
+                    )
+                }
+            }
+        }
+    }
+
+    fn subscribe(&mut self, seq: u32, topic: SubscriptionTopic) -> Result<u32> {
+        let ctx = RequestContext {
+            connection_id: 0,
+            user_id: 0,
+            seq,
+            method: SUBSCRIBE_METHOD_ID,
+            log_id: 0,
+            role: self.role as u32,
+            ip_addr: self.addr.ip(),
+        };
+        ensure_user_role(ctx, topic.min_role())?;
+        ensure!(
+            self.topics.len() < MAX_SUBSCRIPTIONS_PER_CONNECTION,
+            CustomError::new(
+                EnumErrorCode::BadRequest,
+                format!(
+                    "At most {} subscriptions per connection",
+                    MAX_SUBSCRIPTIONS_PER_CONNECTION
+                )
+            )
+        );
+        let subscription_id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.topics.insert(subscription_id, topic);
+        Ok(subscription_id)
+    }
+}
+
+fn error_message(seq: u32, err: Error) -> WsServerMessage {
+    let (code, reason) = error_code_and_reason(&err);
+    WsServerMessage::Error {
+        seq: Some(seq),
+        code,
+        reason: match reason {
+            serde_json::Value::String(reason) => reason,
+            reason => reason.to_string(),
+        },
+    }
+}
+
+async fn send(socket: &mut WebSocket, msg: &WsServerMessage) -> Result<()> {
+    let text = serde_json::to_string(msg)?;
+    socket.send(Message::Text(text)).await?;
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use build::model::{EnumErrorCode, EnumRole};
+use crate::model::{EnumErrorCode, EnumRole};
 use eyre::*;
 use eyre::{ensure, ContextCompat};
 use endpoint_libs::libs::toolbox::{CustomError, RequestContext};
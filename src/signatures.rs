@@ -0,0 +1,335 @@
+use ethers::abi::param_type::Reader;
+use ethers::abi::{decode_whole, Function, Param, ParamType, StateMutability, Token};
+use ethers::types::I256;
+use ethers::utils::hex;
+use eyre::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::model::{DecodedArgument, DecodedCall};
+
+pub type Selector = [u8; 4];
+
+/// Text signatures by 4-byte selector, loaded from a local copy of a
+/// signature database such as 4byte.directory.
+///
+/// Selectors are only 32 bits, so several signatures can share one. Calldata
+/// is decoded against each candidate in the order they were added and the
+/// first one that consumes the input exactly wins.
+#[derive(Default)]
+pub struct SignatureRegistry {
+    functions: RwLock<HashMap<Selector, Vec<Function>>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignatureImportSummary {
+    pub added: usize,
+    pub duplicates: usize,
+    /// Malformed selectors, unparseable signatures and ones not hashing to
+    /// their selector
+    pub rejected: usize,
+}
+
+impl SignatureRegistry {
+    /// Adds `signature`, checked against `selector` if the source lists one.
+    /// Returns `false` if it was already known
+    pub fn add(&self, selector: Option<Selector>, signature: &str) -> Result<bool> {
+        let function = parse_signature(signature)?;
+        let actual = function.short_signature();
+        if let Some(selector) = selector {
+            ensure!(
+                selector == actual,
+                "{} hashes to 0x{}, not 0x{}",
+                signature,
+                hex::encode(actual),
+                hex::encode(selector)
+            );
+        }
+        let mut functions = self.functions.write().unwrap();
+        let candidates = functions.entry(actual).or_default();
+        if candidates.iter().any(|known| known.inputs == function.inputs && known.name == function.name) {
+            return Ok(false);
+        }
+        candidates.push(function);
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.read().unwrap().values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Known text signatures of a selector
+    pub fn signatures_of(&self, selector: Selector) -> Vec<String> {
+        self.functions
+            .read()
+            .unwrap()
+            .get(&selector)
+            .map(|functions| functions.iter().map(text_signature).collect())
+            .unwrap_or_default()
+    }
+
+    /// Decodes calldata with the first candidate signature that fits
+    pub fn decode(&self, input: &[u8]) -> Option<DecodedCall> {
+        let selector: Selector = input.get(..4)?.try_into().ok()?;
+        let functions = self.functions.read().unwrap();
+        functions
+            .get(&selector)?
+            .iter()
+            .find_map(|function| decode_call(function, input))
+    }
+
+    /// Imports a signature file. `.json` files hold an object of selector to
+    /// signature or list of signatures, anything else one
+    /// `<selector> <signature>` or bare `<signature>` per line, with `,`, `:`
+    /// or whitespace between the two and `#` starting a comment
+    pub fn import_file(&self, path: &Path) -> Result<SignatureImportSummary> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let entries = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => parse_json_signatures(&data)?,
+            _ => parse_signature_lines(&data),
+        };
+        Ok(self.import_entries(entries))
+    }
+
+    fn import_entries(&self, entries: Vec<RawEntry>) -> SignatureImportSummary {
+        let mut summary = SignatureImportSummary::default();
+        for (selector, signature) in entries {
+            // a malformed selector only rejects its own entry
+            let added = selector
+                .as_deref()
+                .map(parse_selector)
+                .transpose()
+                .and_then(|selector| self.add(selector, &signature));
+            match added {
+                Result::Ok(true) => summary.added += 1,
+                Result::Ok(false) => summary.duplicates += 1,
+                Err(_) => summary.rejected += 1,
+            }
+        }
+        summary
+    }
+}
+
+fn parse_selector(selector: &str) -> Result<Selector> {
+    let selector = selector.trim();
+    let hex_selector = selector.strip_prefix("0x").unwrap_or(selector);
+    let bytes = hex::decode(hex_selector)?;
+    bytes
+        .try_into()
+        .map_err(|_| eyre!("selector {} is not 4 bytes", selector))
+}
+
+/// Selectors are left as text, so a malformed one can be rejected on its own
+type RawEntry = (Option<String>, String);
+
+fn parse_signature_lines(data: &str) -> Vec<RawEntry> {
+    let mut entries = vec![];
+    for line in data.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry = match line.split_once(|c: char| c == ',' || c == ':' || c.is_whitespace()) {
+            // a bare signature has its first separator inside the parentheses
+            Some((selector, signature)) if !selector.contains('(') => {
+                (Some(selector.to_string()), signature.trim().to_string())
+            }
+            _ => (None, line.to_string()),
+        };
+        entries.push(entry);
+    }
+    entries
+}
+
+fn parse_json_signatures(data: &str) -> Result<Vec<RawEntry>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Signatures {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let mut de = serde_json::Deserializer::from_str(data);
+    let map: HashMap<String, Signatures> = serde_path_to_error::deserialize(&mut de)?;
+    let mut entries = vec![];
+    for (selector, signatures) in map {
+        let signatures = match signatures {
+            Signatures::One(signature) => vec![signature],
+            Signatures::Many(signatures) => signatures,
+        };
+        entries.extend(
+            signatures
+                .into_iter()
+                .map(|signature| (Some(selector.clone()), signature)),
+        );
+    }
+    Ok(entries)
+}
+
+/// `transfer(address,uint256)` into an ABI function with unnamed inputs
+pub fn parse_signature(signature: &str) -> Result<Function> {
+    let signature = signature.trim();
+    let (name, args) = signature
+        .split_once('(')
+        .with_context(|| format!("{} has no argument list", signature))?;
+    ensure!(
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$'),
+        "invalid function name in {}",
+        signature
+    );
+    let inputs = match args.strip_suffix(')') {
+        Some("") => vec![],
+        // the argument list parses like a tuple type
+        _ => match Reader::read(&format!("({}", args))? {
+            ParamType::Tuple(inputs) => inputs,
+            _ => bail!("invalid argument list in {}", signature),
+        },
+    };
+    #[allow(deprecated)]
+    Ok(Function {
+        name: name.to_string(),
+        inputs: inputs
+            .into_iter()
+            .map(|kind| Param {
+                name: String::new(),
+                kind,
+                internal_type: None,
+            })
+            .collect(),
+        outputs: vec![],
+        constant: None,
+        state_mutability: StateMutability::NonPayable,
+    })
+}
+
+/// Decodes `input` as a call to `function`, `None` unless the selector
+/// matches and the arguments consume the calldata exactly
+pub fn decode_call(function: &Function, input: &[u8]) -> Option<DecodedCall> {
+    let (selector, data) = input.split_at_checked(4)?;
+    if selector != function.short_signature() {
+        return None;
+    }
+    let kinds: Vec<ParamType> = function.inputs.iter().map(|param| param.kind.clone()).collect();
+    let tokens = decode_whole(&kinds, data).ok()?;
+    Some(DecodedCall {
+        name: function.name.clone(),
+        signature: text_signature(function),
+        arguments: function
+            .inputs
+            .iter()
+            .zip(&tokens)
+            .map(|(param, token)| DecodedArgument {
+                name: Some(param.name.clone()).filter(|name| !name.is_empty()),
+                kind: param.kind.to_string(),
+                value: token_json(token),
+            })
+            .collect(),
+    })
+}
+
+/// Canonical form without names, e.g. `transfer(address,uint256)`
+pub fn text_signature(function: &Function) -> String {
+    let kinds: Vec<String> = function.inputs.iter().map(|param| param.kind.to_string()).collect();
+    format!("{}({})", function.name, kinds.join(","))
+}
+
+/// Integers are strings so they survive JSON parsers without big numbers
+pub fn token_json(token: &Token) -> Value {
+    match token {
+        Token::Address(address) => json!(format!("{:?}", address)),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => json!(format!("0x{}", hex::encode(bytes))),
+        Token::Int(value) => json!(I256::from_raw(*value).to_string()),
+        Token::Uint(value) => json!(value.to_string()),
+        Token::Bool(value) => json!(value),
+        Token::String(value) => json!(value),
+        Token::FixedArray(tokens) | Token::Array(tokens) | Token::Tuple(tokens) => {
+            Value::Array(tokens.iter().map(token_json).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import_lines(data: &str) -> (SignatureRegistry, SignatureImportSummary) {
+        let registry = SignatureRegistry::default();
+        let summary = registry.import_entries(parse_signature_lines(data));
+        (registry, summary)
+    }
+
+    #[test]
+    fn imports_signature_lines() {
+        let (registry, summary) = import_lines(
+            "# 4byte export\n\
+             0xa9059cbb transfer(address,uint256)\n\
+             095ea7b3,approve(address,uint256)\n\
+             balanceOf(address) # bare\n",
+        );
+        assert_eq!(summary.added, 3);
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(
+            registry.signatures_of([0x70, 0xa0, 0x82, 0x31]),
+            vec!["balanceOf(address)"]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_header_rows() {
+        let (registry, summary) = import_lines(
+            "selector,signature\n\
+             0xzzzzzzzz transfer(address,uint256)\n\
+             0xa9059c transfer(address,uint256)\n\
+             0x095ea7b3 transfer(address,uint256)\n\
+             0xa9059cbb transfer(address,uint256\n\
+             0xa9059cbb transfer(address,uint256)\n",
+        );
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.rejected, 5);
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn counts_duplicate_rows() {
+        let (registry, summary) = import_lines(
+            "0xa9059cbb transfer(address,uint256)\n\
+             transfer(address,uint256)\n\
+             0xa9059cbb:transfer(address,uint256)\n",
+        );
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.duplicates, 2);
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn imports_json_signatures() {
+        let registry = SignatureRegistry::default();
+        let entries = parse_json_signatures(
+            r#"{
+                "0xa9059cbb": "transfer(address,uint256)",
+                "0x095ea7b3": ["approve(address,uint256)", "approve(address,uint256)"],
+                "0x12345678": "nope("
+            }"#,
+        )
+        .unwrap();
+        let summary = registry.import_entries(entries);
+        assert_eq!(summary.added, 2);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.rejected, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(parse_json_signatures("[\"transfer(address,uint256)\"]").is_err());
+        assert!(parse_json_signatures(r#"{"0xa9059cbb": 1}"#).is_err());
+        assert!(parse_json_signatures("{").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use ethers::types::U256;
+use eyre::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::model::{EnumStatsPeriod, NetworkStatsPoint};
+use crate::rkyv_wrappers::WrappedAddress;
+use crate::tables::{BlockRow, NetworkStatsRow, Tables, TransactionRow};
+
+/// Longest series a single query returns
+pub const MAX_SERIES_POINTS: u32 = 2000;
+
+#[derive(Default)]
+struct ActiveAddresses {
+    senders: HashSet<WrappedAddress>,
+    receivers: HashSet<WrappedAddress>,
+}
+
+/// Keeps the `NetworkStats` table up to date as blocks are ingested.
+///
+/// Unique senders and receivers need the set of addresses already counted in
+/// a bucket. Those sets are only kept for the current and previous bucket of
+/// each period, so a block ingested far out of order counts its addresses as
+/// new in its bucket.
+pub struct NetworkStatsAggregator {
+    tables: Arc<Tables>,
+    active: Mutex<HashMap<(EnumStatsPeriod, u32), ActiveAddresses>>,
+}
+
+impl NetworkStatsAggregator {
+    pub fn new(tables: Arc<Tables>) -> Self {
+        Self {
+            tables,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn record_block(&self, block: &BlockRow, txs: &[TransactionRow]) -> Result<()> {
+        let mut active = self.active.lock().await;
+
+        let mut total_fee = alloy_primitives::U256::ZERO;
+        let mut total_value = alloy_primitives::U256::ZERO;
+        let mut new_contracts = 0;
+        for tx in txs {
+            total_fee = total_fee.saturating_add(tx.fee.value());
+            total_value = total_value.saturating_add(tx.value.value());
+            if tx.to_address.is_none() {
+                new_contracts += 1;
+            }
+        }
+
+        for &period in EnumStatsPeriod::ALL {
+            let period_s = period as u32;
+            let bucket_start_s = block.timestamp_s - block.timestamp_s % period_s;
+            let addresses = active.entry((period, bucket_start_s)).or_default();
+            let mut new_senders = 0;
+            let mut new_receivers = 0;
+            for tx in txs {
+                if addresses.senders.insert(tx.from_address.clone()) {
+                    new_senders += 1;
+                }
+                if let Some(to) = &tx.to_address {
+                    if addresses.receivers.insert(to.clone()) {
+                        new_receivers += 1;
+                    }
+                }
+            }
+
+            let table = &self.tables.network_stats;
+            match table.select_bucket(period_s, bucket_start_s) {
+                Some(mut row) => {
+                    row.block_count += 1;
+                    row.tx_count += txs.len() as u64;
+                    row.active_senders += new_senders;
+                    row.active_receivers += new_receivers;
+                    row.gas_used += block.gas_used;
+                    row.total_fee = row.total_fee.value().saturating_add(total_fee).into();
+                    row.total_value = row.total_value.value().saturating_add(total_value).into();
+                    row.new_contracts += new_contracts;
+                    table.update(row).await?;
+                }
+                None => {
+                    table.insert(NetworkStatsRow {
+                        id: table.get_next_pk().into(),
+                        period_s,
+                        bucket_start_s,
+                        block_count: 1,
+                        tx_count: txs.len() as u64,
+                        active_senders: new_senders,
+                        active_receivers: new_receivers,
+                        gas_used: block.gas_used,
+                        total_fee: total_fee.into(),
+                        total_value: total_value.into(),
+                        new_contracts,
+                    })?;
+                }
+            }
+        }
+
+        active.retain(|(period, bucket_start_s), _| {
+            bucket_start_s.saturating_add(2 * *period as u32) > block.timestamp_s
+        });
+        Ok(())
+    }
+}
+
+impl From<NetworkStatsRow> for NetworkStatsPoint {
+    fn from(row: NetworkStatsRow) -> Self {
+        let total_fee = U256::from(row.total_fee);
+        let average_fee = if row.tx_count == 0 {
+            U256::zero()
+        } else {
+            total_fee / U256::from(row.tx_count)
+        };
+        Self {
+            bucket_start_s: row.bucket_start_s,
+            block_count: row.block_count,
+            tx_count: row.tx_count,
+            active_senders: row.active_senders,
+            active_receivers: row.active_receivers,
+            gas_used: row.gas_used,
+            average_fee,
+            total_value: row.total_value.into(),
+            new_contracts: row.new_contracts,
+        }
+    }
+}
+
+/// Buckets between `from_s` and `to_s`, oldest first, at most
+/// [`MAX_SERIES_POINTS`] ending at `to_s`
+pub fn network_stats_series(
+    tables: &Tables,
+    period: EnumStatsPeriod,
+    from_s: u32,
+    to_s: u32,
+) -> Vec<NetworkStatsPoint> {
+    let period_s = period as u32;
+    let last = to_s - to_s % period_s;
+    let first = (from_s - from_s % period_s)
+        .max(last.saturating_sub((MAX_SERIES_POINTS - 1) * period_s));
+    (first..=last)
+        .step_by(period_s as usize)
+        .filter_map(|bucket_start_s| tables.network_stats.select_bucket(period_s, bucket_start_s))
+        .map(NetworkStatsPoint::from)
+        .collect()
+}
+
+/// Timestamp of the newest ingested block
+pub fn latest_block_timestamp(tables: &Tables) -> Option<u32> {
+    let (_, last) = tables.ingested.bounds()?;
+    tables
+        .blocks
+        .select_block(last)
+        .map(|block| block.timestamp_s)
+}
@@ -1,26 +1,46 @@
+use ethers::types::{H160, U256};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
 use worktable::prelude::*;
 use worktable::worktable;
 
 use crate::rkyv_wrappers::WrappedAddress;
 use crate::rkyv_wrappers::WrappedU256;
+use crate::contract_abi::ContractAbis;
+use crate::signatures::SignatureRegistry;
 
 
 
 
 type TxHash = [u8; 32];
+type BlockHash = [u8; 32];
 type  TransactionId = Vec<u32>;
+type CallData = Vec<u8>;
+type CodeHash = [u8; 32];
+type SelectorList = Vec<u32>;
+type TopicList = Vec<[u8; 32]>;
+// token address followed by holder address
+type BalanceKey = [u8; 40];
+// asset id followed by timestamp, both big endian
+type PricePointKey = [u8; 12];
 worktable!(
     name: Block,
     columns: {
         id: u32 primary_key autoincrement,
         number: u32,
+        hash: BlockHash,
         status: u8,
         timestamp_s: u32,
+        gas_used: u64,
+        gas_limit: u64,
         transactions: TransactionId,
         eth_price_usd_cents: u32,
     }
     indexes: {
-        number_idx: number
+        number_idx: number,
+        hash_idx: hash,
     }
 );
 
@@ -36,13 +56,118 @@ worktable!(
         to_address: WrappedAddress optional,
         //internal_transactions: String,
         value: WrappedU256,
+        // gas used times the effective gas price, from the receipt
         fee: WrappedU256,
+        // gas price
         gas: WrappedU256 optional,
+        nonce: u64,
+        gas_limit: u64,
+        gas_used: u64,
+        input: CallData,
+        // at the block's timestamp, filled in after ingestion
+        value_usd_cents: u64 optional,
+        fee_usd_cents: u64 optional,
     }
     indexes: {
-        hash_idx: hash
+        hash_idx: hash,
     }
 );
+
+/// Position in an address history listing. Transactions are ordered newest
+/// first by `(block_number, id)`, so the cursor is the last row of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct TransactionCursor {
+    pub block_number: u32,
+    pub id: u32,
+}
+
+impl From<&TransactionRow> for TransactionCursor {
+    fn from(row: &TransactionRow) -> Self {
+        Self {
+            block_number: row.block_number,
+            id: row.id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AddressHistoryPage {
+    pub transactions: Vec<TransactionRow>,
+    /// `None` once the oldest transaction of the address has been returned
+    pub next_cursor: Option<TransactionCursor>,
+}
+
+/// Non-unique index lookups fail with `NotFound` when no row has the key
+fn not_found_as_empty<Row, Table>(
+    result: Result<SelectResult<Row, Table>, WorkTableError>,
+) -> Result<Vec<Row>, WorkTableError> {
+    match result {
+        Ok(rows) => Ok(rows.execute()),
+        Err(WorkTableError::NotFound) => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+worktable!(
+    name: TokenTransfer,
+    columns: {
+        id: u64 primary_key autoincrement,
+        tx_hash: TxHash,
+        log_index: u32,
+        block_number: u32,
+        timestamp_s: u32,
+        token_address: WrappedAddress,
+        from_address: WrappedAddress,
+        to_address: WrappedAddress,
+        // amount for ERC-20, 1 for ERC-721
+        value: WrappedU256,
+        token_id: WrappedU256 optional,
+        // at the block's timestamp, for priced ERC-20s only
+        value_usd_cents: u64 optional,
+    }
+    indexes: {
+        tx_hash_idx: tx_hash,
+    }
+);
+
+impl TokenTransferWorkTable {
+    pub fn select_tx_transfers(
+        &self,
+        tx_hash: TxHash,
+    ) -> Result<Vec<TokenTransferRow>, WorkTableError> {
+        not_found_as_empty(self.select_by_tx_hash(tx_hash))
+    }
+}
+
+// every log of the ingested transactions, from their receipts
+worktable!(
+    name: TransactionLog,
+    columns: {
+        id: u64 primary_key autoincrement,
+        tx_hash: TxHash,
+        log_index: u32,
+        block_number: u32,
+        address: WrappedAddress,
+        topics: TopicList,
+        data: CallData,
+    }
+    indexes: {
+        tx_hash_idx: tx_hash,
+    }
+);
+
+impl TransactionLogWorkTable {
+    /// Logs of the transaction in the order they were emitted
+    pub fn select_tx_logs(
+        &self,
+        tx_hash: TxHash,
+    ) -> Result<Vec<TransactionLogRow>, WorkTableError> {
+        let mut rows = not_found_as_empty(self.select_by_tx_hash(tx_hash))?;
+        rows.sort_by_key(|row| row.log_index);
+        Ok(rows)
+    }
+}
+
 worktable!(
     name: Address,
     columns: {
@@ -57,6 +182,20 @@ worktable!(
     }
 );
 
+worktable!(
+    name: AddressLabel,
+    columns: {
+        id: u64 primary_key autoincrement,
+        address: WrappedAddress,
+        label: String,
+        category: String,
+        source: String,
+    }
+    indexes: {
+        address_idx: address,
+    }
+);
+
 worktable!(
     name: Wallet,
     columns: {
@@ -74,31 +213,784 @@ worktable!(
     name: Contract,
     columns: {
         id: u64 primary_key autoincrement,
+        address: WrappedAddress,
         balance: u64,
         creator: String,
         tracker: String optional,
         code: String optional,
         transactions: String,
+        // uploaded JSON ABI
+        abi: String optional,
+        // keccak256 of the runtime bytecode, set once it was analyzed
+        code_hash: CodeHash optional,
+        compiler: String optional,
+        compiler_version: String optional,
+        // `ipfs://<cid>` or `bzzr0://`/`bzzr1://<hex>` from the CBOR metadata
+        source_hash: String optional,
+        // PUSH4 selectors of the dispatcher, ascending
+        selectors: SelectorList,
+        // `EnumProxyKind` if the contract delegates to another one
+        proxy_kind: u8 optional,
+        implementation: WrappedAddress optional,
+        beacon: WrappedAddress optional,
     }
     indexes: {
-        id_idx: id
+        id_idx: id,
+        address_idx: address,
+        beacon_idx: beacon,
+    }
+);
+
+worktable!(
+    name: ProxyUpgrade,
+    columns: {
+        id: u64 primary_key autoincrement,
+        proxy_address: WrappedAddress,
+        implementation: WrappedAddress,
+        block_number: u32,
+        tx_hash: TxHash,
+    }
+    indexes: {
+        proxy_address_idx: proxy_address,
     }
 );
 
+impl ProxyUpgradeWorkTable {
+    /// Upgrades of the proxy, oldest first
+    pub fn select_proxy_upgrades(
+        &self,
+        proxy: WrappedAddress,
+    ) -> Result<Vec<ProxyUpgradeRow>, WorkTableError> {
+        let mut rows = not_found_as_empty(self.select_by_proxy_address(proxy))?;
+        rows.sort_by_key(|row| row.id);
+        Ok(rows)
+    }
+}
+
 worktable!(
     name: Token,
     columns: {
         id: u64 primary_key autoincrement,
         token_contract_hash: String,
+        name: String,
+        symbol: String,
+        decimals: u8,
+        // EnumTokenStandard
+        standard: u8,
+        total_supply: WrappedU256,
         max_supply: u64,
+        // set once the contract is matched to a CMC listing
+        cmc_id: u64 optional,
         price_usd: f64,
         onchain_cap: u64,
         circulating_cap: u64,
         transfers: String,
-        holders: String,
     }
     indexes: {
         token_contract_hash_idx: token_contract_hash
     }
 );
 
+impl ContractWorkTable {
+    pub fn select_contract(&self, address: WrappedAddress) -> Option<ContractRow> {
+        not_found_as_empty(self.select_by_address(address))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    /// Proxies delegating through the beacon
+    pub fn select_beacon_followers(
+        &self,
+        beacon: WrappedAddress,
+    ) -> Result<Vec<ContractRow>, WorkTableError> {
+        not_found_as_empty(self.select_by_beacon(Some(beacon)))
+    }
+
+    /// Applies `change` to the contract's row, inserting an empty row first
+    /// if the contract isn't known yet
+    pub async fn upsert_contract(
+        &self,
+        address: WrappedAddress,
+        change: impl FnOnce(&mut ContractRow),
+    ) -> eyre::Result<ContractRow> {
+        match self.select_contract(address.clone()) {
+            Some(mut row) => {
+                change(&mut row);
+                self.update(row.clone()).await?;
+                Ok(row)
+            }
+            None => {
+                let mut row = ContractRow {
+                    id: self.get_next_pk().into(),
+                    address,
+                    balance: 0,
+                    creator: String::new(),
+                    tracker: None,
+                    code: None,
+                    transactions: String::new(),
+                    abi: None,
+                    code_hash: None,
+                    compiler: None,
+                    compiler_version: None,
+                    source_hash: None,
+                    selectors: vec![],
+                    proxy_kind: None,
+                    implementation: None,
+                    beacon: None,
+                };
+                change(&mut row);
+                self.insert(row.clone())?;
+                Ok(row)
+            }
+        }
+    }
+}
+
+impl TokenWorkTable {
+    /// Contract addresses are stored as lowercase `0x`-prefixed hex
+    pub fn select_token(&self, address: H160) -> Option<TokenRow> {
+        not_found_as_empty(self.select_by_token_contract_hash(format!("{:?}", address)))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+}
+
+impl BlockWorkTable {
+    pub fn select_block(&self, number: u32) -> Option<BlockRow> {
+        not_found_as_empty(self.select_by_number(number))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    pub fn select_block_by_hash(&self, hash: BlockHash) -> Option<BlockRow> {
+        not_found_as_empty(self.select_by_hash(hash))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+}
+
+// Hourly or daily aggregate, one row per `(period_s, bucket_start_s)`
+worktable!(
+    name: NetworkStats,
+    columns: {
+        id: u64 primary_key autoincrement,
+        period_s: u32,
+        bucket_start_s: u32,
+        block_count: u32,
+        tx_count: u64,
+        active_senders: u32,
+        active_receivers: u32,
+        gas_used: u64,
+        total_fee: WrappedU256,
+        total_value: WrappedU256,
+        new_contracts: u32,
+    }
+    indexes: {
+        bucket_start_s_idx: bucket_start_s,
+    }
+);
+
+impl NetworkStatsWorkTable {
+    pub fn select_bucket(&self, period_s: u32, bucket_start_s: u32) -> Option<NetworkStatsRow> {
+        not_found_as_empty(self.select_by_bucket_start_s(bucket_start_s))
+            .ok()?
+            .into_iter()
+            .find(|row| row.period_s == period_s)
+    }
+}
+
+worktable!(
+    name: WhaleAlert,
+    columns: {
+        id: u64 primary_key autoincrement,
+        tx_hash: TxHash,
+        block_number: u32,
+        timestamp_s: u32,
+        kind: u8,
+        // `None` for ETH
+        token_address: WrappedAddress optional,
+        symbol: String,
+        from_address: WrappedAddress,
+        to_address: WrappedAddress,
+        amount: WrappedU256,
+        usd_value_cents: u64,
+        exchange_flow: u8,
+    }
+    indexes: {
+        tx_hash_idx: tx_hash,
+        from_address_idx: from_address,
+        to_address_idx: to_address,
+    }
+);
+
+impl WhaleAlertWorkTable {
+    /// Alerts touching the address, newest first
+    pub fn select_address_alerts(
+        &self,
+        address: WrappedAddress,
+    ) -> Result<Vec<WhaleAlertRow>, WorkTableError> {
+        let mut rows = not_found_as_empty(self.select_by_from_address(address.clone()))?;
+        let received = not_found_as_empty(self.select_by_to_address(address.clone()))?;
+        rows.extend(received.into_iter().filter(|row| row.from_address != address));
+        rows.sort_by_key(|row| std::cmp::Reverse(row.id));
+        Ok(rows)
+    }
+}
+
+worktable!(
+    name: TokenBalance,
+    columns: {
+        id: u64 primary_key autoincrement,
+        key: BalanceKey,
+        token_address: WrappedAddress,
+        holder: WrappedAddress,
+        // token units for ERC-20, number of tokens owned for ERC-721
+        balance: WrappedU256,
+    }
+    indexes: {
+        key_idx: key,
+        token_address_idx: token_address,
+        holder_idx: holder,
+    }
+);
+
+pub fn balance_key(token: H160, holder: H160) -> BalanceKey {
+    let mut key = [0; 40];
+    key[..20].copy_from_slice(token.as_bytes());
+    key[20..].copy_from_slice(holder.as_bytes());
+    key
+}
+
+impl TokenBalanceWorkTable {
+    pub fn select_balance(&self, token: H160, holder: H160) -> Option<TokenBalanceRow> {
+        not_found_as_empty(self.select_by_key(balance_key(token, holder)))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    /// Replaces the holder's balance, rows are removed once it reaches zero
+    /// so every row is a current holder
+    pub async fn set_balance(&self, token: H160, holder: H160, balance: U256) -> eyre::Result<()> {
+        match (self.select_balance(token, holder), balance.is_zero()) {
+            (Some(row), true) => self.delete(row.id.into()).await?,
+            (Some(mut row), false) => {
+                row.balance = balance.into();
+                self.update(row).await?;
+            }
+            (None, true) => {}
+            (None, false) => {
+                self.insert(TokenBalanceRow {
+                    id: self.get_next_pk().into(),
+                    key: balance_key(token, holder),
+                    token_address: token.into(),
+                    holder: holder.into(),
+                    balance: balance.into(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a transfer to both sides, the zero address stands for mints
+    /// and burns and has no balance.
+    ///
+    /// Balances are only exact for tokens ingested since their creation, a
+    /// sender whose earlier receipts weren't seen is floored at zero.
+    pub async fn apply_transfer(&self, transfer: &TokenTransferRow) -> eyre::Result<()> {
+        let token = H160::from(transfer.token_address.clone());
+        let from = H160::from(transfer.from_address.clone());
+        let to = H160::from(transfer.to_address.clone());
+        let value = U256::from(transfer.value.clone());
+        if value.is_zero() || from == to {
+            return Ok(());
+        }
+        if !from.is_zero() {
+            let balance = self.balance_of(token, from).saturating_sub(value);
+            self.set_balance(token, from, balance).await?;
+        }
+        if !to.is_zero() {
+            let balance = self.balance_of(token, to).saturating_add(value);
+            self.set_balance(token, to, balance).await?;
+        }
+        Ok(())
+    }
+
+    pub fn balance_of(&self, token: H160, holder: H160) -> U256 {
+        self.select_balance(token, holder)
+            .map(|row| row.balance.into())
+            .unwrap_or_default()
+    }
+
+    pub fn select_token_holders(
+        &self,
+        token: H160,
+    ) -> Result<Vec<TokenBalanceRow>, WorkTableError> {
+        not_found_as_empty(self.select_by_token_address(token.into()))
+    }
+
+    /// Largest balances first
+    pub fn top_holders(
+        &self,
+        token: H160,
+        limit: usize,
+    ) -> Result<Vec<TokenBalanceRow>, WorkTableError> {
+        let mut rows = self.select_token_holders(token)?;
+        rows.sort_by_cached_key(|row| std::cmp::Reverse(U256::from(row.balance.clone())));
+        rows.truncate(limit);
+        Ok(rows)
+    }
+
+    pub fn holder_count(&self, token: H160) -> Result<usize, WorkTableError> {
+        Ok(self.select_token_holders(token)?.len())
+    }
+
+    pub fn select_holder_balances(
+        &self,
+        holder: H160,
+    ) -> Result<Vec<TokenBalanceRow>, WorkTableError> {
+        not_found_as_empty(self.select_by_holder(holder.into()))
+    }
+}
+
+worktable!(
+    name: PriceHistory,
+    columns: {
+        id: u64 primary_key autoincrement,
+        key: PricePointKey,
+        // CoinMarketCap id
+        asset_id: u64,
+        timestamp_s: u32,
+        price_usd: f64,
+        // the quote's interval was over when it was fetched
+        closed: bool,
+    }
+    indexes: {
+        key_idx: key,
+        asset_id_idx: asset_id,
+    }
+);
+
+pub fn price_point_key(asset_id: u64, timestamp_s: u32) -> PricePointKey {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&asset_id.to_be_bytes());
+    key[8..].copy_from_slice(&timestamp_s.to_be_bytes());
+    key
+}
+
+impl PriceHistoryWorkTable {
+    pub fn select_point(&self, asset_id: u64, timestamp_s: u32) -> Option<PriceHistoryRow> {
+        not_found_as_empty(self.select_by_key(price_point_key(asset_id, timestamp_s)))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    /// Quotes are final once their interval is over, a point recorded
+    /// again only replaces the price of a quote that was still open
+    pub async fn record(
+        &self,
+        asset_id: u64,
+        timestamp_s: u32,
+        price_usd: f64,
+        closed: bool,
+    ) -> eyre::Result<()> {
+        match self.select_point(asset_id, timestamp_s) {
+            Some(row) if row.closed => {}
+            Some(row) if row.price_usd == price_usd && row.closed == closed => {}
+            Some(mut row) => {
+                row.price_usd = price_usd;
+                row.closed = closed;
+                self.update(row).await?;
+            }
+            None => {
+                self.insert(PriceHistoryRow {
+                    id: self.get_next_pk().into(),
+                    key: price_point_key(asset_id, timestamp_s),
+                    asset_id,
+                    timestamp_s,
+                    price_usd,
+                    closed,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every point of the asset, oldest first
+    pub fn select_asset_points(
+        &self,
+        asset_id: u64,
+    ) -> Result<Vec<PriceHistoryRow>, WorkTableError> {
+        let mut rows = not_found_as_empty(self.select_by_asset_id(asset_id))?;
+        rows.sort_by_key(|row| row.timestamp_s);
+        Ok(rows)
+    }
+}
+
+worktable!(
+    name: DexPool,
+    columns: {
+        id: u64 primary_key autoincrement,
+        address: WrappedAddress,
+        protocol: u8,
+        token0: WrappedAddress,
+        token1: WrappedAddress,
+        // in hundredths of a basis point, V3 only
+        fee: u32,
+        // latest state, virtual reserves for V3
+        reserve0: WrappedU256,
+        reserve1: WrappedU256,
+        block_number: u32,
+    }
+    indexes: {
+        address_idx: address,
+        token0_idx: token0,
+        token1_idx: token1,
+    }
+);
+
+impl DexPoolWorkTable {
+    pub fn select_pool(&self, address: H160) -> Option<DexPoolRow> {
+        not_found_as_empty(self.select_by_address(address.into()))
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    /// Pools with the token on either side
+    pub fn select_token_pools(&self, token: H160) -> Result<Vec<DexPoolRow>, WorkTableError> {
+        let mut rows = not_found_as_empty(self.select_by_token0(token.into()))?;
+        rows.extend(not_found_as_empty(self.select_by_token1(token.into()))?);
+        Ok(rows)
+    }
+}
+
+worktable!(
+    name: DexPoolState,
+    columns: {
+        id: u64 primary_key autoincrement,
+        pool_address: WrappedAddress,
+        block_number: u32,
+        timestamp_s: u32,
+        // as of the pool's last event in the block
+        reserve0: WrappedU256,
+        reserve1: WrappedU256,
+    }
+    indexes: {
+        pool_address_idx: pool_address,
+    }
+);
+
+/// Lowest and highest block number ingested so far
+#[derive(Debug)]
+pub struct IngestedRange {
+    first: AtomicU32,
+    last: AtomicU32,
+}
+
+impl Default for IngestedRange {
+    fn default() -> Self {
+        Self {
+            first: AtomicU32::new(u32::MAX),
+            last: AtomicU32::new(0),
+        }
+    }
+}
+
+impl IngestedRange {
+    pub fn record(&self, block_number: u32) {
+        self.first.fetch_min(block_number, Ordering::SeqCst);
+        self.last.fetch_max(block_number, Ordering::SeqCst);
+    }
+
+    pub fn bounds(&self) -> Option<(u32, u32)> {
+        let first = self.first.load(Ordering::SeqCst);
+        let last = self.last.load(Ordering::SeqCst);
+        (first <= last).then_some((first, last))
+    }
+}
+
+/// Primary keys of the rows touching each address, ordered by
+/// `(block_number, id)`, so a page of an address's history is read off the
+/// ordered set instead of sorting every row of the address. Pool states are
+/// also ordered by `(timestamp_s, id)`
+#[derive(Debug)]
+pub struct AddressActivity<Id> {
+    keys: RwLock<HashMap<WrappedAddress, BTreeSet<(u32, Id)>>>,
+}
+
+impl<Id> Default for AddressActivity<Id> {
+    fn default() -> Self {
+        Self {
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<Id: Copy + Ord> AddressActivity<Id> {
+    pub fn record(&self, address: WrappedAddress, block_number: u32, id: Id) {
+        self.keys
+            .write()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .insert((block_number, id));
+    }
+
+    pub fn remove(&self, address: &WrappedAddress, block_number: u32, id: Id) {
+        let mut keys = self.keys.write().unwrap();
+        if let Some(address_keys) = keys.get_mut(address) {
+            address_keys.remove(&(block_number, id));
+            if address_keys.is_empty() {
+                keys.remove(address);
+            }
+        }
+    }
+
+    /// Rows of `address` with keys in `range`, newest first if `descending`.
+    ///
+    /// `select` looks up each row and may filter it out; `skip` and `limit`
+    /// count the rows it returns.
+    pub fn page<T>(
+        &self,
+        address: &WrappedAddress,
+        range: impl RangeBounds<(u32, Id)>,
+        descending: bool,
+        skip: usize,
+        limit: usize,
+        mut select: impl FnMut(Id) -> Option<T>,
+    ) -> Vec<T> {
+        let keys = self.keys.read().unwrap();
+        let Some(keys) = keys.get(address) else {
+            return vec![];
+        };
+        let range = keys.range(range);
+        let ids: Box<dyn Iterator<Item = &(u32, Id)>> = if descending {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        };
+        ids.filter_map(|(_, id)| select(*id))
+            .skip(skip)
+            .take(limit)
+            .collect()
+    }
+}
+
+/// All tables of one chain, shared between the ingester and the API, and the
+/// lookups used to present their rows
+#[derive(Default)]
+pub struct Tables {
+    pub ingested: IngestedRange,
+    pub blocks: BlockWorkTable,
+    pub transactions: TransactionWorkTable,
+    pub token_transfers: TokenTransferWorkTable,
+    pub transaction_logs: TransactionLogWorkTable,
+    pub network_stats: NetworkStatsWorkTable,
+    pub whale_alerts: WhaleAlertWorkTable,
+    pub addresses: AddressWorkTable,
+    pub address_labels: AddressLabelWorkTable,
+    pub wallets: WalletWorkTable,
+    pub contracts: ContractWorkTable,
+    pub proxy_upgrades: ProxyUpgradeWorkTable,
+    pub tokens: TokenWorkTable,
+    pub token_balances: TokenBalanceWorkTable,
+    pub price_history: PriceHistoryWorkTable,
+    pub dex_pools: DexPoolWorkTable,
+    pub dex_pool_states: DexPoolStateWorkTable,
+    pub signatures: SignatureRegistry,
+    pub contract_abis: ContractAbis,
+    pub address_transactions: AddressActivity<u32>,
+    pub address_transfers: AddressActivity<u64>,
+    pub token_transfers_by_token: AddressActivity<u64>,
+    pub dex_pool_states_by_block: AddressActivity<u64>,
+    pub dex_pool_states_by_time: AddressActivity<u64>,
+    /// Held from checking for a token's row to inserting it
+    token_inserts: Mutex<()>,
+}
+
+impl Tables {
+    /// Inserts the token's row unless ingestion or an API call detecting the
+    /// same contract got there first, returning the row that was kept
+    pub fn insert_token(&self, row: TokenRow) -> Result<TokenRow, WorkTableError> {
+        let _guard = self.token_inserts.lock().unwrap();
+        let hash = row.token_contract_hash.clone();
+        if let Some(existing) = not_found_as_empty(self.tokens.select_by_token_contract_hash(hash))?
+            .into_iter()
+            .next()
+        {
+            return Ok(existing);
+        }
+        self.tokens.insert(row.clone())?;
+        Ok(row)
+    }
+
+    /// The pool's state after the last block at or before `block_number`
+    /// with an event of it
+    pub fn select_dex_pool_state_at_block(
+        &self,
+        pool: &WrappedAddress,
+        block_number: u32,
+    ) -> Option<DexPoolStateRow> {
+        self.dex_pool_states_by_block
+            .page(pool, ..=(block_number, u64::MAX), true, 0, 1, |id| {
+                self.dex_pool_states.select(id)
+            })
+            .pop()
+    }
+
+    pub fn select_dex_pool_state_at_timestamp(
+        &self,
+        pool: &WrappedAddress,
+        timestamp_s: u32,
+    ) -> Option<DexPoolStateRow> {
+        self.dex_pool_states_by_time
+            .page(pool, ..=(timestamp_s, u64::MAX), true, 0, 1, |id| {
+                self.dex_pool_states.select(id)
+            })
+            .pop()
+    }
+
+    /// Records a pool state, keeping the pool's latest one per `interval_s`
+    /// window of block time. States more than `retention_s` older than it
+    /// are dropped
+    pub async fn record_dex_pool_state(
+        &self,
+        row: DexPoolStateRow,
+        interval_s: u32,
+        retention_s: u32,
+    ) -> eyre::Result<()> {
+        eyre::ensure!(interval_s > 0, "pool state interval must not be 0");
+        let pool = row.pool_address.clone();
+        let window_start = row.timestamp_s - row.timestamp_s % interval_s;
+        let window = (window_start, 0)..(window_start.saturating_add(interval_s), 0);
+        let in_window = self
+            .dex_pool_states_by_time
+            .page(&pool, window, true, 0, 1, |id| self.dex_pool_states.select(id))
+            .pop();
+        match in_window {
+            Some(existing) if existing.block_number >= row.block_number => return Ok(()),
+            Some(existing) => self.remove_dex_pool_state(existing).await?,
+            None => {}
+        }
+        let (id, block_number, timestamp_s) = (row.id, row.block_number, row.timestamp_s);
+        self.dex_pool_states.insert(row)?;
+        self.dex_pool_states_by_block.record(pool.clone(), block_number, id);
+        self.dex_pool_states_by_time.record(pool.clone(), timestamp_s, id);
+
+        let cutoff = timestamp_s.saturating_sub(retention_s);
+        let expired = self
+            .dex_pool_states_by_time
+            .page(&pool, ..(cutoff, 0), false, 0, usize::MAX, |id| {
+                self.dex_pool_states.select(id)
+            });
+        for state in expired {
+            self.remove_dex_pool_state(state).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_dex_pool_state(&self, row: DexPoolStateRow) -> eyre::Result<()> {
+        self.dex_pool_states_by_block
+            .remove(&row.pool_address, row.block_number, row.id);
+        self.dex_pool_states_by_time
+            .remove(&row.pool_address, row.timestamp_s, row.id);
+        self.dex_pool_states.delete(row.id.into()).await?;
+        Ok(())
+    }
+
+    /// Inserts a transaction and orders it into its sender's and
+    /// recipient's history
+    pub fn insert_transaction(&self, row: TransactionRow) -> Result<(), WorkTableError> {
+        let (id, block_number) = (row.id, row.block_number);
+        let from = row.from_address.clone();
+        let to = row.to_address.clone();
+        self.transactions.insert(row)?;
+        if to.as_ref() != Some(&from) {
+            if let Some(to) = to {
+                self.address_transactions.record(to, block_number, id);
+            }
+        }
+        self.address_transactions.record(from, block_number, id);
+        Ok(())
+    }
+
+    /// Inserts a token transfer and orders it into its sender's, recipient's
+    /// and token's history
+    pub fn insert_token_transfer(&self, row: TokenTransferRow) -> Result<(), WorkTableError> {
+        let (id, block_number) = (row.id, row.block_number);
+        let from = row.from_address.clone();
+        let to = row.to_address.clone();
+        let token = row.token_address.clone();
+        self.token_transfers.insert(row)?;
+        if to != from {
+            self.address_transfers.record(to, block_number, id);
+        }
+        self.address_transfers.record(from, block_number, id);
+        self.token_transfers_by_token.record(token, block_number, id);
+        Ok(())
+    }
+
+    /// Transactions sent or received by `address`, newest first.
+    ///
+    /// Pass the `next_cursor` of the previous page to continue; rows inserted
+    /// after the first page was served don't shift later pages.
+    pub fn select_address_history(
+        &self,
+        address: WrappedAddress,
+        cursor: Option<TransactionCursor>,
+        limit: usize,
+    ) -> AddressHistoryPage {
+        let end = cursor.map_or(Bound::Unbounded, |cursor| {
+            Bound::Excluded((cursor.block_number, cursor.id))
+        });
+        let mut rows = self.address_transactions.page(
+            &address,
+            (Bound::Unbounded, end),
+            true,
+            0,
+            limit + 1,
+            |id| self.transactions.select(id),
+        );
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        let next_cursor = if has_more {
+            rows.last().map(TransactionCursor::from)
+        } else {
+            None
+        };
+        AddressHistoryPage {
+            transactions: rows,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_closed_quotes() {
+        let table = PriceHistoryWorkTable::default();
+        table.record(1, 3600, 100.0, true).await.unwrap();
+        table.record(1, 3600, 120.0, true).await.unwrap();
+        assert_eq!(table.select_point(1, 3600).unwrap().price_usd, 100.0);
+    }
+
+    #[tokio::test]
+    async fn updates_open_quotes_until_closed() {
+        let table = PriceHistoryWorkTable::default();
+        table.record(1, 3600, 100.0, false).await.unwrap();
+        table.record(1, 3600, 110.0, true).await.unwrap();
+        table.record(1, 3600, 120.0, false).await.unwrap();
+        let row = table.select_point(1, 3600).unwrap();
+        assert_eq!(row.price_usd, 110.0);
+        assert!(row.closed);
+    }
+}
@@ -0,0 +1,219 @@
+use ethers::types::{Chain, H160};
+use eyre::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::api::assets::AssetId;
+use crate::api::cmc::CoinMarketCap;
+use crate::api::coingecko::CoinGecko;
+use crate::api::models::CoinMarketCapTokenInfo;
+use crate::tables::Tables;
+
+/// CMC accepts up to 100 ids per quotes or info request before credit use
+/// goes up
+const CMC_BATCH_SIZE: usize = 100;
+/// Listings change slowly, the map is a few thousand credits to page through
+const ID_SYNC_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// CMC ids by the contract address of the listed token on each chain
+#[derive(Default)]
+pub struct CmcTokenIds {
+    ids: RwLock<HashMap<(Chain, H160), u64>>,
+}
+
+impl CmcTokenIds {
+    pub fn get(&self, chain: Chain, address: H160) -> Option<u64> {
+        self.ids.read().unwrap().get(&(chain, address)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Listed contracts on the chain with their ids
+    pub fn on_chain(&self, chain: Chain) -> Vec<(H160, u64)> {
+        self.ids
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|((token_chain, _), _)| *token_chain == chain)
+            .map(|((_, address), id)| (*address, *id))
+            .collect()
+    }
+
+    fn replace(&self, ids: HashMap<(Chain, H160), u64>) {
+        *self.ids.write().unwrap() = ids;
+    }
+}
+
+/// The first listing of an address wins, so callers pass the more
+/// established coins first
+fn insert_ids(
+    ids: &mut HashMap<(Chain, H160), u64>,
+    infos: impl IntoIterator<Item = CoinMarketCapTokenInfo>,
+) {
+    for info in infos {
+        for token in info.addresses {
+            let address = H160::from(token.address.0);
+            ids.entry((token.chain, address)).or_insert(info.cmc_id);
+        }
+    }
+}
+
+/// Matches `Token` rows to CMC listings by contract address and keeps their
+/// `price_usd` current.
+///
+/// Symbols are ambiguous, dozens of contracts call themselves USDT, so a
+/// token without a listing for its exact contract stays unpriced.
+pub struct TokenPriceSync {
+    cmc: Arc<CoinMarketCap>,
+    tables: Arc<Tables>,
+    chain: Chain,
+    ids: CmcTokenIds,
+    /// Given the matches as aliases, so it prices the CMC ids by contract
+    coingecko: Option<Arc<CoinGecko>>,
+}
+
+impl TokenPriceSync {
+    pub fn new(cmc: Arc<CoinMarketCap>, tables: Arc<Tables>, chain: Chain) -> Self {
+        Self {
+            cmc,
+            tables,
+            chain,
+            ids: CmcTokenIds::default(),
+            coingecko: None,
+        }
+    }
+
+    pub fn with_coingecko(mut self, coingecko: Arc<CoinGecko>) -> Self {
+        self.coingecko = Some(coingecko);
+        self
+    }
+
+    pub fn ids(&self) -> &CmcTokenIds {
+        &self.ids
+    }
+
+    /// Rebuilds the id mapping from the map endpoint, which only lists each
+    /// coin's primary platform. Coins sharing a symbol with an on-chain
+    /// token still unmatched are looked up in the info endpoint for their
+    /// contracts on the other platforms. The mapping is only replaced once
+    /// every lookup succeeded
+    pub async fn sync_ids(&self) -> Result<()> {
+        let mut coins = self.cmc.get_coin_map().await?;
+        // unranked coins report rank 0
+        coins.sort_by_key(|coin| if coin.rank > 0 { coin.rank } else { i32::MAX });
+        let mut ids = HashMap::new();
+        insert_ids(&mut ids, coins.iter().map(|coin| coin.token_info()));
+
+        let unmatched: HashSet<String> = self
+            .tables
+            .tokens
+            .select_all()
+            .execute()?
+            .into_iter()
+            .filter(|row| row.cmc_id.is_none())
+            .filter(|row| {
+                H160::from_str(&row.token_contract_hash)
+                    .is_ok_and(|address| !ids.contains_key(&(self.chain, address)))
+            })
+            .map(|row| row.symbol.to_uppercase())
+            .collect();
+        let candidates: Vec<u64> = coins
+            .iter()
+            .filter(|coin| unmatched.contains(&coin.symbol.to_uppercase()))
+            .map(|coin| coin.id as u64)
+            .collect();
+        for chunk in candidates.chunks(CMC_BATCH_SIZE) {
+            let mut infos = self.cmc.get_token_infos_by_id(chunk).await?;
+            // keep the rank order across the batch
+            insert_ids(
+                &mut ids,
+                chunk
+                    .iter()
+                    .filter_map(|id| infos.remove(id))
+                    .map(|info| info.token_info()),
+            );
+        }
+        info!(
+            "Mapped {} token contracts to CMC ids, looked up {} candidates",
+            ids.len(),
+            candidates.len()
+        );
+        self.ids.replace(ids);
+        if let Some(coingecko) = &self.coingecko {
+            for (address, id) in self.ids.on_chain(self.chain) {
+                coingecko.alias_cmc_id(id, AssetId::Contract(self.chain, address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches tokens to their current ids, dropping delisted ones, and
+    /// prices every matched token by its id. A failed batch keeps the
+    /// previous prices of its tokens
+    pub async fn refresh_prices(&self) -> Result<()> {
+        let mut rows = self.tables.tokens.select_all().execute()?;
+        // before the first sync the stored matches are all there is
+        let synced = !self.ids.is_empty();
+        let mut matched = Vec::with_capacity(rows.len());
+        for row in &mut rows {
+            let previous = row.cmc_id;
+            if synced || row.cmc_id.is_none() {
+                row.cmc_id = H160::from_str(&row.token_contract_hash)
+                    .ok()
+                    .and_then(|address| self.ids.get(self.chain, address));
+            }
+            matched.push(row.cmc_id != previous);
+        }
+        let ids: Vec<u64> = rows
+            .iter()
+            .filter_map(|row| row.cmc_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut prices = HashMap::with_capacity(ids.len());
+        for chunk in ids.chunks(CMC_BATCH_SIZE) {
+            match self.cmc.get_usd_prices_by_id(chunk).await {
+                Result::Ok(chunk_prices) => prices.extend(chunk_prices),
+                Err(e) => warn!("Failed to fetch prices of {} CMC ids: {:?}", chunk.len(), e),
+            }
+        }
+        for (mut row, rematched) in rows.into_iter().zip(matched) {
+            match row.cmc_id {
+                Some(id) => {
+                    if let Some(price) = prices.get(&id) {
+                        row.price_usd = *price;
+                    }
+                }
+                None if rematched => row.price_usd = 0.0,
+                None => continue,
+            }
+            self.tables.tokens.update(row).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut last_id_sync: Option<Instant> = None;
+        loop {
+            if last_id_sync.is_none_or(|at| at.elapsed() >= ID_SYNC_INTERVAL) {
+                match self.sync_ids().await {
+                    Result::Ok(()) => last_id_sync = Some(Instant::now()),
+                    Err(e) => error!("Failed to sync CMC token ids: {:?}", e),
+                }
+            }
+            if let Err(e) = self.refresh_prices().await {
+                error!("Failed to refresh token prices: {:?}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
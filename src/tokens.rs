@@ -0,0 +1,245 @@
+use ethers::abi::{decode, encode, ParamType, Token};
+use ethers::types::{BlockId, BlockNumber, Bytes, H160, U256};
+use eyre::*;
+
+use crate::api::EthersClient;
+use crate::bytecode::analyze_contract;
+use crate::model::EnumTokenStandard;
+use crate::tables::{Tables, TokenRow};
+
+const SUPPORTS_INTERFACE_SELECTOR: [u8; 4] = [0x01, 0xff, 0xc9, 0xa7];
+const ERC165_INTERFACE_ID: [u8; 4] = SUPPORTS_INTERFACE_SELECTOR;
+/// ERC-165 requires this to be unsupported, contracts answering `true` to
+/// everything don't actually implement it
+const INVALID_INTERFACE_ID: [u8; 4] = [0xff; 4];
+const ERC721_INTERFACE_ID: [u8; 4] = [0x80, 0xac, 0x58, 0xcd];
+const ERC1155_INTERFACE_ID: [u8; 4] = [0xd9, 0xb6, 0x7a, 0x26];
+
+const NAME_SELECTOR: [u8; 4] = [0x06, 0xfd, 0xde, 0x03];
+const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+const TOTAL_SUPPLY_SELECTOR: [u8; 4] = [0x18, 0x16, 0x0d, 0xdd];
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+/// totalSupply, balanceOf, transfer, transferFrom, approve, allowance
+const ERC20_SELECTORS: [u32; 6] = [
+    0x18160ddd, 0x70a08231, 0xa9059cbb, 0x23b872dd, 0x095ea7b3, 0xdd62ed3e,
+];
+/// ownerOf, balanceOf, transferFrom, setApprovalForAll
+const ERC721_SELECTORS: [u32; 4] = [0x6352211e, 0x70a08231, 0x23b872dd, 0xa22cb465];
+/// balanceOf(address,uint256), balanceOfBatch, safeTransferFrom
+const ERC1155_SELECTORS: [u32; 3] = [0x00fdd58e, 0x4e1273f4, 0xf242432a];
+
+/// Output of a view call, `None` if it reverted or returned nothing. Node
+/// and transport errors are returned so nothing is stored from a call that
+/// never ran
+async fn call(api: &EthersClient, address: H160, data: Vec<u8>) -> Result<Option<Bytes>> {
+    let output = api
+        .try_call_contract(address, data.into(), BlockId::Number(BlockNumber::Latest))
+        .await?;
+    Ok(output.filter(|output| !output.is_empty()))
+}
+
+async fn supports_interface(
+    api: &EthersClient,
+    address: H160,
+    interface_id: [u8; 4],
+) -> Result<bool> {
+    let data = encode_function_call(
+        SUPPORTS_INTERFACE_SELECTOR,
+        &[Token::FixedBytes(interface_id.to_vec())],
+    );
+    let Some(output) = call(api, address, data).await? else {
+        return Ok(false);
+    };
+    Ok(matches!(
+        decode(&[ParamType::Bool], &output).as_deref(),
+        Result::Ok([Token::Bool(true)])
+    ))
+}
+
+/// Dispatcher selectors of the contract, including its implementation's if
+/// it is a proxy. Contracts not analyzed yet are analyzed first
+async fn contract_selectors(
+    api: &EthersClient,
+    tables: &Tables,
+    address: H160,
+) -> Result<Vec<u32>> {
+    let row = match tables
+        .contracts
+        .select_contract(address.into())
+        .filter(|row| row.code_hash.is_some())
+    {
+        Some(row) => row,
+        None => analyze_contract(api, tables, address, None).await?,
+    };
+    let mut selectors = row.selectors;
+    if let Some(implementation) = row.implementation.map(H160::from) {
+        let implementation = match tables
+            .contracts
+            .select_contract(implementation.into())
+            .filter(|row| row.code_hash.is_some())
+        {
+            Some(row) => row,
+            None => analyze_contract(api, tables, implementation, None).await?,
+        };
+        selectors.extend(implementation.selectors);
+    }
+    Ok(selectors)
+}
+
+/// ERC-165 first, then the functions found in the bytecode. `transfer_hint`
+/// is the standard implied by the layout of a `Transfer` log the contract
+/// emitted, used when neither is conclusive
+pub async fn detect_token_standard(
+    api: &EthersClient,
+    tables: &Tables,
+    address: H160,
+    transfer_hint: Option<EnumTokenStandard>,
+) -> Result<Option<EnumTokenStandard>> {
+    if supports_interface(api, address, ERC165_INTERFACE_ID).await?
+        && !supports_interface(api, address, INVALID_INTERFACE_ID).await?
+    {
+        if supports_interface(api, address, ERC1155_INTERFACE_ID).await? {
+            return Ok(Some(EnumTokenStandard::Erc1155));
+        }
+        if supports_interface(api, address, ERC721_INTERFACE_ID).await? {
+            return Ok(Some(EnumTokenStandard::Erc721));
+        }
+    }
+
+    let selectors = contract_selectors(api, tables, address).await?;
+    let has_all = |required: &[u32]| required.iter().all(|selector| selectors.contains(selector));
+    // ERC-721 shares balanceOf and transferFrom with ERC-20, ownerOf tells them apart
+    if has_all(&ERC1155_SELECTORS) {
+        Ok(Some(EnumTokenStandard::Erc1155))
+    } else if has_all(&ERC721_SELECTORS) {
+        Ok(Some(EnumTokenStandard::Erc721))
+    } else if has_all(&ERC20_SELECTORS) {
+        Ok(Some(EnumTokenStandard::Erc20))
+    } else if selectors.is_empty() {
+        // vyper and hand-written dispatchers don't match the selector pattern
+        Ok(transfer_hint)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads a `string` return value, or a `bytes32` one as returned by MKR,
+/// SAI and other tokens deployed before `string` returns were common
+pub fn decode_text(output: &[u8]) -> Option<String> {
+    let text = match decode(&[ParamType::String], output).as_deref() {
+        Result::Ok([Token::String(text)]) => text.clone(),
+        _ if output.len() == 32 => {
+            let len = output.iter().rposition(|byte| *byte != 0)? + 1;
+            String::from_utf8(output[..len].to_vec()).ok()?
+        }
+        _ => return None,
+    };
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn decode_uint(output: &[u8]) -> Option<U256> {
+    match decode(&[ParamType::Uint(256)], output).ok()?.as_slice() {
+        [Token::Uint(value)] => Some(*value),
+        _ => None,
+    }
+}
+
+/// Returns the contract's `Token` row, detecting its standard and fetching
+/// its metadata on first sight. `None` if it isn't a token. Concurrent
+/// detections of one contract may both call it, only one row is kept
+pub async fn detect_token(
+    api: &EthersClient,
+    tables: &Tables,
+    address: H160,
+    transfer_hint: Option<EnumTokenStandard>,
+) -> Result<Option<TokenRow>> {
+    if let Some(row) = tables.tokens.select_token(address) {
+        return Ok(Some(row));
+    }
+    let Some(standard) = detect_token_standard(api, tables, address, transfer_hint).await? else {
+        return Ok(None);
+    };
+
+    let text = |selector: [u8; 4]| async move {
+        let output = call(api, address, selector.to_vec()).await?;
+        Ok(output
+            .and_then(|output| decode_text(&output))
+            .unwrap_or_default())
+    };
+    let name = text(NAME_SELECTOR).await?;
+    let symbol = text(SYMBOL_SELECTOR).await?;
+    // decimals() is optional in ERC-20, tokens without it count in whole units
+    let decimals = match standard {
+        EnumTokenStandard::Erc20 => call(api, address, DECIMALS_SELECTOR.to_vec())
+            .await?
+            .and_then(|output| decode_uint(&output))
+            .filter(|decimals| *decimals <= U256::from(u8::MAX))
+            .map_or(0, |decimals| decimals.as_u32() as u8),
+        _ => 0,
+    };
+    let total_supply = call(api, address, TOTAL_SUPPLY_SELECTOR.to_vec())
+        .await?
+        .and_then(|output| decode_uint(&output))
+        .unwrap_or_default();
+
+    let row = TokenRow {
+        id: tables.tokens.get_next_pk().into(),
+        token_contract_hash: format!("{:?}", address),
+        name,
+        symbol,
+        decimals,
+        standard: standard as u8,
+        total_supply: total_supply.into(),
+        max_supply: 0,
+        cmc_id: None,
+        price_usd: 0.0,
+        onchain_cap: 0,
+        circulating_cap: 0,
+        transfers: String::new(),
+    };
+    Ok(Some(tables.insert_token(row)?))
+}
+
+/// `balanceOf(holder)` at the latest block
+pub async fn fetch_balance_of(api: &EthersClient, token: H160, holder: H160) -> Result<U256> {
+    let data = encode_function_call(BALANCE_OF_SELECTOR, &[Token::Address(holder)]);
+    let output = api
+        .call_contract(token, data.into(), BlockId::Number(BlockNumber::Latest))
+        .await?;
+    decode_uint(&output).with_context(|| format!("{:?} returned no balance", token))
+}
+
+fn encode_function_call(selector: [u8; 4], arguments: &[Token]) -> Vec<u8> {
+    let mut data = selector.to_vec();
+    data.extend(encode(arguments));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_string_text() {
+        let output = encode(&[Token::String("Wrapped Ether".to_string())]);
+        assert_eq!(decode_text(&output).as_deref(), Some("Wrapped Ether"));
+    }
+
+    #[test]
+    fn decodes_bytes32_text() {
+        // MKR's symbol()
+        let mut output = [0u8; 32];
+        output[..3].copy_from_slice(b"MKR");
+        assert_eq!(decode_text(&output).as_deref(), Some("MKR"));
+    }
+
+    #[test]
+    fn rejects_empty_text() {
+        assert_eq!(decode_text(&[0u8; 32]), None);
+        let output = encode(&[Token::String(String::new())]);
+        assert_eq!(decode_text(&output), None);
+    }
+}
@@ -0,0 +1,264 @@
+use ethers::types::{H160, U256};
+use ethers::utils::format_units;
+use eyre::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::*;
+
+use crate::events::ChainEvent;
+use crate::model::EnumTokenStandard;
+use crate::price_history::PriceHistory;
+use crate::tables::{BlockRow, Tables, TokenTransferRow};
+
+const NATIVE_DECIMALS: u32 = 18;
+
+fn usd_cents(amount: U256, decimals: u32, price: f64) -> Result<u64> {
+    let units: f64 = format_units(amount, decimals)?.parse()?;
+    Ok((units * price * 100.0).round() as u64)
+}
+
+/// Values every ingested block's transactions and token transfers in USD
+/// at the block's timestamp.
+///
+/// Runs behind the event bus like the whale alerter. A block is done once
+/// its `eth_price_usd_cents` is set, so blocks missed while lagging behind
+/// or whose quotes weren't available yet are picked up by `backfill`.
+/// Transfers of a token matched to a CMC listing after their block was
+/// valued are valued by `backfill` once the token has its id.
+pub struct UsdValuer {
+    tables: Arc<Tables>,
+    history: Arc<PriceHistory>,
+    /// CMC id of the chain's native coin
+    native_id: u64,
+    /// Listed tokens whose earlier transfers were already valued
+    listed_tokens: Mutex<HashSet<H160>>,
+}
+
+impl UsdValuer {
+    pub fn new(tables: Arc<Tables>, history: Arc<PriceHistory>, native_id: u64) -> Self {
+        Self {
+            tables,
+            history,
+            native_id,
+            listed_tokens: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub async fn value_block(&self, block: &BlockRow) -> Result<bool> {
+        let timestamp_s = block.timestamp_s as i64;
+        let Some(native_price) = self.history.price_at(self.native_id, timestamp_s).await? else {
+            return Ok(false);
+        };
+        let mut token_prices: HashMap<H160, Option<(f64, u32)>> = HashMap::new();
+        for id in &block.transactions {
+            let Some(mut tx) = self.tables.transactions.select(*id) else {
+                continue;
+            };
+            tx.value_usd_cents = Some(usd_cents(
+                tx.value.clone().into(),
+                NATIVE_DECIMALS,
+                native_price,
+            )?);
+            tx.fee_usd_cents = Some(usd_cents(
+                tx.fee.clone().into(),
+                NATIVE_DECIMALS,
+                native_price,
+            )?);
+            let hash = tx.hash;
+            self.tables.transactions.update(tx).await?;
+
+            for mut transfer in self.tables.token_transfers.select_tx_transfers(hash)? {
+                // NFT transfers carry an id, their value is a count
+                if transfer.token_id.is_some() {
+                    continue;
+                }
+                let token = H160::from(transfer.token_address.clone());
+                let price = match token_prices.get(&token) {
+                    Some(price) => *price,
+                    None => {
+                        let price = self.token_price(token, timestamp_s).await?;
+                        token_prices.insert(token, price);
+                        price
+                    }
+                };
+                let Some((price, decimals)) = price else {
+                    continue;
+                };
+                transfer.value_usd_cents =
+                    Some(usd_cents(transfer.value.clone().into(), decimals, price)?);
+                self.tables.token_transfers.update(transfer).await?;
+            }
+        }
+
+        let mut block = block.clone();
+        block.eth_price_usd_cents = (native_price * 100.0).round() as u32;
+        self.tables.blocks.update(block).await?;
+        Ok(true)
+    }
+
+    /// Price and decimals of an ERC-20 matched to a CMC listing by contract,
+    /// other tokens aren't valued
+    async fn token_price(&self, token: H160, timestamp_s: i64) -> Result<Option<(f64, u32)>> {
+        let Some(row) = self.tables.tokens.select_token(token) else {
+            return Ok(None);
+        };
+        match row.cmc_id {
+            Some(id) if row.standard == EnumTokenStandard::Erc20 as u8 => Ok(self
+                .history
+                .price_at(id, timestamp_s)
+                .await?
+                .map(|price| (price, row.decimals as u32))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Values the transfers left unvalued of ERC-20s that got a CMC id
+    /// since their block was valued, returns how many were valued. Each
+    /// token is looked at once, a token that fails is tried again by the
+    /// next backfill
+    async fn value_listed_tokens(&self) -> Result<usize> {
+        let tokens: Vec<(H160, u64, u32)> = self
+            .tables
+            .tokens
+            .select_all()
+            .execute()?
+            .into_iter()
+            .filter(|row| row.standard == EnumTokenStandard::Erc20 as u8)
+            .filter_map(|row| {
+                let address = H160::from_str(&row.token_contract_hash).ok()?;
+                Some((address, row.cmc_id?, row.decimals as u32))
+            })
+            .filter(|(address, ..)| !self.listed_tokens.lock().unwrap().contains(address))
+            .collect();
+        let mut valued = 0;
+        for (address, id, decimals) in tokens {
+            match self.value_token_transfers(address, id, decimals).await {
+                Result::Ok(transfers) => {
+                    valued += transfers;
+                    self.listed_tokens.lock().unwrap().insert(address);
+                }
+                Err(e) => warn!("Failed to value transfers of {:?} in USD: {:?}", address, e),
+            }
+        }
+        Ok(valued)
+    }
+
+    /// Transfers without quotes around them stay unvalued
+    async fn value_token_transfers(&self, address: H160, id: u64, decimals: u32) -> Result<usize> {
+        let transfers: Vec<TokenTransferRow> = self.tables.token_transfers_by_token.page(
+            &address.into(),
+            ..,
+            false,
+            0,
+            usize::MAX,
+            |transfer_id| {
+                self.tables
+                    .token_transfers
+                    .select(transfer_id)
+                    .filter(|row| row.token_id.is_none() && row.value_usd_cents.is_none())
+            },
+        );
+        if let (Some(first), Some(last)) = (transfers.first(), transfers.last()) {
+            self.history
+                .backfill(id, first.timestamp_s as i64, last.timestamp_s as i64)
+                .await?;
+        }
+        let mut valued = 0;
+        for mut transfer in transfers {
+            let timestamp_s = transfer.timestamp_s as i64;
+            let Some(price) = self.history.price_at(id, timestamp_s).await? else {
+                continue;
+            };
+            transfer.value_usd_cents =
+                Some(usd_cents(transfer.value.clone().into(), decimals, price)?);
+            self.tables.token_transfers.update(transfer).await?;
+            valued += 1;
+        }
+        Ok(valued)
+    }
+
+    /// Values every block not valued yet, oldest first, backfilling the
+    /// native coin's price history over their range. A block that fails is
+    /// logged and left for the next backfill
+    pub async fn backfill(&self) -> Result<usize> {
+        let mut blocks: Vec<BlockRow> = self
+            .tables
+            .blocks
+            .select_all()
+            .execute()?
+            .into_iter()
+            .filter(|block| block.eth_price_usd_cents == 0)
+            .collect();
+        blocks.sort_by_key(|block| block.number);
+        // native quotes for the whole range in a few calls rather than a
+        // call per day, blocks it fails for are still looked up one by one
+        if let (Some(first), Some(last)) = (blocks.first(), blocks.last()) {
+            if let Err(e) = self
+                .history
+                .backfill(
+                    self.native_id,
+                    first.timestamp_s as i64,
+                    last.timestamp_s as i64,
+                )
+                .await
+            {
+                warn!("Failed to backfill native price history: {:?}", e);
+            }
+        }
+        let mut valued = 0;
+        for block in blocks {
+            match self.value_block(&block).await {
+                Result::Ok(true) => valued += 1,
+                Result::Ok(false) => {}
+                Err(e) => warn!("Failed to value block {} in USD: {:?}", block.number, e),
+            }
+        }
+        match self.value_listed_tokens().await {
+            Result::Ok(0) => {}
+            Result::Ok(transfers) => {
+                debug!("Valued {} transfers of newly listed tokens", transfers)
+            }
+            Err(e) => warn!("Failed to value transfers of newly listed tokens: {:?}", e),
+        }
+        Ok(valued)
+    }
+
+    /// Takes the receiver rather than subscribing itself so nothing ingested
+    /// before the task is first polled gets missed
+    pub async fn run(
+        self: Arc<Self>,
+        mut receiver: broadcast::Receiver<Arc<ChainEvent>>,
+        backfill_interval: Duration,
+    ) {
+        let mut last_backfill = Instant::now();
+        loop {
+            let event = tokio::time::timeout(backfill_interval, receiver.recv()).await;
+            let result = match event {
+                Result::Ok(Result::Ok(event)) => match &*event {
+                    ChainEvent::Block(block) => self.value_block(block).await.map(|_| ()),
+                    _ => Ok(()),
+                },
+                Result::Ok(Err(RecvError::Lagged(missed))) => {
+                    warn!("USD valuer lagged behind by {} events, backfilling", missed);
+                    self.backfill().await.map(|_| ())
+                }
+                Result::Ok(Err(RecvError::Closed)) => return,
+                Err(_) => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to value block in USD: {:?}", e);
+            }
+            if last_backfill.elapsed() >= backfill_interval {
+                last_backfill = Instant::now();
+                match self.backfill().await {
+                    Result::Ok(0) => {}
+                    Result::Ok(valued) => debug!("Backfilled USD values of {} blocks", valued),
+                    Err(e) => warn!("Failed to backfill USD values: {:?}", e),
+                }
+            }
+        }
+    }
+}